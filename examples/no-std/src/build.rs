@@ -0,0 +1,8 @@
+use ructe::{Result, Ructe};
+
+fn main() -> Result<()> {
+    Ructe::from_env()?
+        .fmt_write(true)?
+        .check_html(true)
+        .compile_templates("templates")
+}
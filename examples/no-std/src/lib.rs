@@ -0,0 +1,43 @@
+//! An example of rendering ructe templates in a `no_std` crate.
+//!
+//! With `Ructe::fmt_write` in the build script, the templates write
+//! to a `core::fmt::Write`, and only need `core` and `alloc`.
+#![no_std]
+extern crate alloc;
+
+include!(concat!(env!("OUT_DIR"), "/templates.rs"));
+
+#[cfg(test)]
+mod test {
+    use super::templates;
+    use alloc::string::String;
+
+    #[test]
+    fn status() {
+        assert_eq!(
+            templates::status_html_to_string("<dev>", &[21.0, 22.25], false),
+            "<!doctype html>\n<html>\n  <head><title>&lt;dev&gt;</title></head>\n  \
+             <body>\n    <h1>&lt;dev&gt;</h1>\n    \n  \
+             <ul><li>21.0 °C</li><li>22.2 °C</li></ul>\n  \
+             <p >All is well.</p>\n\n  </body>\n</html>\n\n",
+        );
+    }
+
+    #[test]
+    fn write_to_fmt_writer() {
+        let mut out = String::new();
+        let mut status = templates::StatusHtml::new("x", &[]);
+        status.alarm = true;
+        status.render(&mut out).unwrap();
+        assert!(out.contains("<p class=\"alarm\">Alarm!</p>"));
+    }
+
+    #[test]
+    fn display() {
+        let status = templates::StatusHtml::new("x", &[]);
+        assert_eq!(
+            alloc::format!("{}", status.display()),
+            templates::status_html_to_string("x", &[], false),
+        );
+    }
+}
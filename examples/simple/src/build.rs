@@ -1,5 +1,7 @@
 use ructe::{Result, Ructe};
 
 fn main() -> Result<()> {
-    Ructe::from_env()?.compile_templates("templates")
+    Ructe::from_env()?
+        .check_html(true)
+        .compile_templates("templates")
 }
@@ -89,6 +89,24 @@ fn test_if_else_if() {
     assert_eq!(f(7), "There are 7\n");
 }
 
+#[test]
+fn test_match_guards() {
+    let f =
+        |count, grade| r2s(|o| templates::match_guards_html(o, count, grade));
+    assert_eq!(f(Some(17), 'A'), "<b>17!</b>\npass\n");
+    assert_eq!(f(Some(10), 'D'), "10\nfail\n");
+    assert_eq!(f(Some(0), 'B'), "none\npass\n");
+    assert_eq!(f(None, 'F'), "none\nfail\n");
+}
+
+#[test]
+fn test_if_operators() {
+    assert_eq!(
+        r2s(|o| templates::if_operators_html(o, &[3, 9, 7, 4, 8], 5)),
+        "\n3\n\n9\n\n<b>7</b>\n\n-\n\n<b>8</b>\n\n"
+    );
+}
+
 #[test]
 fn test_list() {
     assert_eq!(
@@ -105,6 +123,33 @@ fn test_list_empty() {
     );
 }
 
+#[test]
+fn test_for_sep_else() {
+    assert_eq!(
+        r2s(|o| templates::for_sep_else_html(o, &["foo", "bar", "baz"])),
+        "<p>foo, bar, baz.</p>\n<ol>\
+         \n  <li class=\"first\">0: foo</li>\
+         \n  <li>1: bar</li>\
+         \n  <li class=\"last\">2: baz</li>\n</ol>\n"
+    );
+}
+
+#[test]
+fn test_for_sep_else_empty() {
+    assert_eq!(
+        r2s(|o| templates::for_sep_else_html(o, &[])),
+        "<p>No items.</p>\n<ol>\n</ol>\n"
+    );
+}
+
+#[test]
+fn test_while_let() {
+    assert_eq!(
+        r2s(|o| templates::while_let_html(o, &["foo", "bar"])),
+        "\n<ol>\n  <li>foo,</li>\n  <li>bar</li>\n</ol>\n"
+    );
+}
+
 #[test]
 fn test_list_destructure() {
     assert_eq!(
@@ -159,6 +204,9 @@ mod models {
     use crate::templates::Html;
     use std::fmt;
 
+    /// The default size of a widget, known only to the widget template.
+    pub const DEFAULT_SIZE: u8 = 3;
+
     pub struct User<'a> {
         pub name: &'a str,
         pub email: &'a str,
@@ -241,6 +289,26 @@ fn test_for_destructure() {
     )
 }
 
+#[test]
+fn test_let_binding() {
+    let users = vec![
+        models::User {
+            name: "Tom Puss",
+            email: "tom@example.nl",
+        },
+        models::User {
+            name: "Heloise Walker",
+            email: "helwa@briarson.edu",
+        },
+    ];
+    assert_eq!(
+        r2s(|o| templates::let_binding_html(o, &users)),
+        "\n  \n  \n  <p>TOM PUSS (example.nl)</p>\n\
+         \n  \n  \n  <p>HELOISE WALKER (briarson.edu)</p>\n\
+         \n\n<p>2 users</p>\n"
+    );
+}
+
 #[test]
 fn test_explicit_formatting() {
     assert_eq!(
@@ -288,6 +356,271 @@ fn test_page_with_base() {
     );
 }
 
+#[test]
+fn test_page_named_args() {
+    assert_eq!(
+        r2s(|o| page::page_named_html(o, 2019)),
+        r2s(|o| page::page_two_html(o, 2019)),
+    );
+}
+
+#[test]
+fn test_path_call() {
+    assert_eq!(
+        r2s(|o| templates::path_call_html(o, "Path")),
+        "<!doctype html>\n<html>\
+         \n  <head><title>Path</title><meta name=\"x\"></head>\
+         \n  <body>\n    <h1>Path</h1>\n    <p>Content</p>\
+         \n  </body>\n</html>\n\n"
+    );
+}
+
+#[test]
+fn test_default_args() {
+    assert_eq!(
+        r2s(|o| templates::widget_defaults_html(o)),
+        "<div class=\"widget\" data-size=\"3\">First: one</div>\n\n\
+         <div class=\"widget big\" data-size=\"5\">Second: two</div>\n\n"
+    );
+}
+
+#[test]
+fn test_named_args_next_to_glob_use() {
+    assert_eq!(
+        r2s(|o| templates::widget_glob_html(o)),
+        "<div class=\"widget\" data-size=\"3\">Glob: x</div>\n\n"
+    );
+}
+
+#[test]
+fn test_props_struct() {
+    assert_eq!(
+        r2s(|o| templates::GreetingHtml::default().render(o)),
+        "<p>Hello, World!</p>\n"
+    );
+    assert_eq!(
+        r2s(|o| {
+            let mut widget =
+                templates::WidgetHtml::new("Props", |o| o.write_all(b"body"));
+            widget.size = 7;
+            widget.render(o)
+        }),
+        "<div class=\"widget\" data-size=\"7\">Props: body</div>\n"
+    );
+}
+
+#[test]
+fn test_template_value() {
+    let greetings = [
+        templates::GreetingHtml::default(),
+        templates::GreetingHtml {
+            greeting: "Hi",
+            name: "<you>",
+        },
+    ];
+    assert_eq!(
+        r2s(|o| templates::template_value_html(o, &greetings)),
+        "<ul><li><p>Hello, World!</p>\n</li>\
+         <li><p>Hi, &lt;you&gt;!</p>\n</li></ul>\n"
+    );
+    assert_eq!(
+        format!("{}", greetings[0].display()),
+        "<p>Hello, World!</p>\n"
+    );
+}
+
+#[test]
+fn test_size_hint() {
+    assert_eq!(templates::GREETING_HTML_SIZE_HINT, "<p>, !</p>\n".len());
+}
+
+#[test]
+fn test_generic_list() {
+    assert_eq!(
+        r2s(|o| templates::generic_list_html(o, ["a", "b"], ", ")),
+        "a, b, \n"
+    );
+    assert_eq!(
+        r2s(|o| templates::generic_list_html::<str, 1, _>(o, ["c"], ";")),
+        "c;\n"
+    );
+}
+
+#[test]
+fn test_generic_props() {
+    assert_eq!(
+        r2s(|o| templates::GenericLabelHtml::new(17).render(o)),
+        "<p>Value: 17</p>\n"
+    );
+    assert_eq!(
+        r2s(|o| {
+            let mut props = templates::GenericLabelHtml::new('a');
+            props.label = "Char";
+            props.opts = Some(vec!['a', 'b']);
+            props.render(o)
+        }),
+        "<p>Char: a (of 2)</p>\n"
+    );
+}
+
+#[test]
+fn test_helpers() {
+    assert_eq!(
+        r2s(|o| templates::helpers_html(o, &[("a", true), ("b", false)])),
+        "<tr><td>a</td><td>b*</td><td>all!</td></tr>\n"
+    );
+}
+
+#[test]
+fn test_include() {
+    assert_eq!(
+        r2s(|o| templates::include_html(o)),
+        "<p><svg viewBox=\"0 0 8 8\"><path d=\"M1 4l2 2 4-4\"/></svg>\n</p>\n\
+         <pre>if a &lt; b &amp;&amp; b &gt; c { &quot;ok&quot; }\n</pre>\n"
+    );
+}
+
+#[test]
+fn test_collapsed_whitespace() {
+    assert_eq!(
+        r2s(|o| templates::collapsed_html(o, &["a", "b"])),
+        "<ul><li>a</li><li>b</li></ul><pre>\n  keep\n    this\n</pre>"
+    );
+}
+
+#[test]
+fn test_trim_markers() {
+    assert_eq!(
+        r2s(|o| templates::trimmed_html(o, &["a", "b"])),
+        "<p>[a][b]</p>\n"
+    );
+}
+
+#[test]
+fn test_raw_block() {
+    assert_eq!(
+        r2s(|o| templates::raw_script_html(o, "x")),
+        "<script>\nif (x) { y(\"@\"); }\n</script>\n<p id=\"x\">}#</p>\n"
+    );
+}
+
+#[test]
+fn test_contextual_escaping() {
+    assert_eq!(
+        r2s(|o| templates::contextual_html(o, "a'b c", "javascript:x()")),
+        "<a href=\"#\" title=\"a&#39;b c\" data-q=\"/s?q=a&#39;b c\" \
+         onclick=\"greet('a\\u0027b c')\">a&#39;b c</a>\n\
+         <a href=\"/s?q=a%27b%20c\" style=\"color: a\\27 b\\20 c\">x</a>\n\
+         <a href=\"#\">y</a>\n\
+         <script>var name = \"a\\u0027b c\", \
+         html = \"a\\u0027b c\";</script>\n"
+    );
+}
+
+#[test]
+fn test_optional_attributes() {
+    assert_eq!(
+        r2s(|o| templates::attributes_html(o, true, Some("<b>"), 4)),
+        "<input type=\"checkbox\" checked title=\"&lt;b&gt;\">\n\
+         <p class=\"item done big\">4</p>\n"
+    );
+    assert_eq!(
+        r2s(|o| templates::attributes_html(o, false, None, 2)),
+        "<input type=\"checkbox\"  >\n<p class=\"item\">2</p>\n"
+    );
+}
+
+#[test]
+fn test_for_sep_continue() {
+    assert_eq!(
+        r2s(|o| templates::for_sep_continue_html(o, &["", "a", "", "b", ""])),
+        "<p>[a, b]</p>\n"
+    );
+}
+
+#[test]
+fn test_for_info_continue() {
+    assert_eq!(
+        r2s(|o| templates::for_info_continue_html(o, &[0, 1, 0, 2])),
+        "[0:1 first=true last=false][1:2 first=false last=true]\n"
+    );
+}
+
+#[test]
+fn test_loop_control() {
+    assert_eq!(
+        r2s(|o| templates::loop_control_html(
+            o,
+            &[&["a", "", "b"], &["c", "end", "d"], &["e"]],
+        )),
+        "<ul>\n  <li>a</li>\n  <li>b</li>\n  <li>c</li>\n</ul>\n"
+    );
+}
+
+#[test]
+fn test_labeled_loop_with_info_and_sep() {
+    assert_eq!(
+        r2s(|o| templates::labeled_sep_html(
+            o,
+            &[&["a", "b"], &["c", "end", "d"], &["e"]],
+        )),
+        "<p>0=a,b; 1=c.</p>\n"
+    );
+}
+
+#[test]
+fn test_formatted() {
+    assert_eq!(
+        r2s(|o| templates::formatted_html(o, 2.5, 7, "<b>")),
+        "<p>2.50 for 007 of    &lt;b&gt;</p>\n"
+    );
+}
+
+#[test]
+fn test_formatted_contextual() {
+    assert_eq!(
+        r2s(|o| templates::formatted_script_html(o, "\"x")),
+        "<script>var name = \"    \\u0022x\";</script>\n"
+    );
+}
+
+#[test]
+fn test_documented() {
+    assert_eq!(
+        r2s(|o| templates::DocumentedHtml::new(2.5).render(o)),
+        "<p>2.50 SEK</p>\n"
+    );
+}
+
+#[test]
+fn test_extends() {
+    assert_eq!(
+        r2s(|o| page::layout_html(o, "Plain")),
+        "<!doctype html>\n<html>\n  <head><title>Plain</title></head>\
+         \n  <body>\n    <h1>Plain</h1>\n    <p>No content.</p>\
+         \n  </body>\n</html>\n"
+    );
+    assert_eq!(
+        r2s(|o| page::article_html(o, "Hello", "Some text")),
+        "<!doctype html>\n<html>\n  <head><title>Articles: Hello</title>\
+         <meta name=\"description\" content=\"Some text\"></head>\
+         \n  <body>\n    <h1>Articles: Hello</h1>\
+         \n    \n    <nav>Articles</nav>\
+         \n    <h2>Hello</h2><p>Some text</p>\n\
+         \n  </body>\n</html>\n"
+    );
+}
+
+#[test]
+fn test_extends_other_directory() {
+    assert_eq!(
+        r2s(|o| templates::framed_note_html(o, "Hi")),
+        "<section><h1>Note</h1><p>Hi</p><h1>Hello World!</h1>\
+         \n<p>Note: Brackets and @ signs needs to be escaped: { ... }</p>\
+         \n</section>\n"
+    );
+}
+
 #[test]
 fn test_page_two() {
     assert_eq!(
@@ -0,0 +1,8 @@
+use ructe::{Result, Ructe};
+
+fn main() -> Result<()> {
+    Ructe::from_env()?
+        .check_html(true)
+        .async_templates(true)
+        .compile_templates("templates")
+}
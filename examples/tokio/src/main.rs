@@ -0,0 +1,55 @@
+//! An example of async rendering with ructe.
+//!
+//! With the `tokio1` feature, ructe also generates an async variant of
+//! each template, writing to a `tokio::io::AsyncWrite`.
+include!(concat!(env!("OUT_DIR"), "/templates.rs"));
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> std::io::Result<()> {
+    templates::hello_html_async(tokio::io::stdout(), "World", &[1, 2, 3])
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::templates;
+
+    #[tokio::test]
+    async fn hello() {
+        let out = templates::hello_html_async(Vec::new(), "World", &[3, 4])
+            .await
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "<!doctype html>\n<html>\n  \
+             <head><title>Hello World!</title></head>\n  \
+             <body>\n    <h1>Hello World!</h1>\n    \n  \
+             <ul><li>3</li><li>4</li></ul>\n  \
+             <p>Total: 0007</p>\n\n  </body>\n</html>\n\n",
+        );
+    }
+
+    #[tokio::test]
+    async fn same_as_sync() {
+        let mut sync = Vec::new();
+        templates::hello_html(&mut sync, "<you>", &[17]).unwrap();
+        let out = templates::hello_html_async(Vec::new(), "<you>", &[17])
+            .await
+            .unwrap();
+        assert_eq!(out, sync);
+    }
+
+    #[tokio::test]
+    async fn extends() {
+        let out = templates::article_html_async(Vec::new(), "Title", "Text")
+            .await
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "<!doctype html>\n<html>\n  <head><title>Title</title></head>\n  \
+             <body>\n    \n  <h1>Title</h1>\n  <p>Text</p>\n\n  \
+             </body>\n</html>\n",
+        );
+    }
+}
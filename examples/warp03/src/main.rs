@@ -45,10 +45,19 @@ fn wrap(result: Result<impl Reply, impl Reply>) -> Response {
 }
 
 /// Home page handler; just render a template with some arguments.
+///
+/// The buffer for the page is allocated with room for the text of
+/// the template, as given by its size hint.
 async fn home_page() -> Result<impl Reply> {
-    Ok(Builder::new().html(|o| {
-        templates::page_html(o, &[("first", 3), ("second", 7), ("third", 2)])
-    })?)
+    Ok(Builder::new().html_with_capacity(
+        templates::PAGE_HTML_SIZE_HINT,
+        |o| {
+            templates::page_html(
+                o,
+                &[("first", 3), ("second", 7), ("third", 2)],
+            )
+        },
+    )?)
 }
 
 /// A handler with some error handling.
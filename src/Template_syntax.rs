@@ -61,6 +61,54 @@
 //! precedes expressions and special blocks; verbtim `@` signs must be
 //! escaped as `@@`.
 //!
+//! For longer verbatim parts, such as inline scripts or styles, a raw
+//! block can be used instead.
+//! Everything between `@raw#{` and `}#` is written as is:
+//!
+//! ```html
+//! <script>@raw#{
+//!   function f(a) { return a ? { id: "@" } : {}; }
+//! }#</script>
+//! ```
+//!
+//! If the content contains `}#`, more hashes can be used, as in
+//! `@raw##{ ... }##`.
+//!
+//! The declaration can also have generic parameters (lifetimes,
+//! types and consts, with bounds) and a `where` clause, just as a
+//! rust function:
+//!
+//! ```html
+//! @use std::fmt::Display;
+//!
+//! @<'a, T: Display, const N: usize>(items: [&'a T; N], sep: &str)
+//! where T: ?Sized
+//! @for item in items {@item@sep}
+//! ```
+//!
+//! The generated function gets these parameters before the type
+//! parameter for the output it writes to.
+//!
+//! A template can start with a `@** ... **@` doc comment, which
+//! becomes the rustdoc of the generated function.
+//! The preamble can also have attributes such as `@#[cfg(...)]` or
+//! `@#[deprecated]`, and each parameter can be documented with
+//! `///` lines:
+//!
+//! ```html
+//! @** Show a price, with its currency. **@
+//! @#[cfg(feature = "shop")]
+//! @(
+//!   /// The price, shown with two decimals.
+//!   price: f64,
+//!   /// The currency code.
+//!   currency: &str)
+//! <p>@(price:.2) @currency</p>
+//! ```
+//!
+//! A `cfg` attribute also applies to the module and re-export of the
+//! template, so it is only compiled when the condition holds.
+//!
 //! [`ToHtml`]: crate::templates::ToHtml
 //! [`Display`]: std::fmt::Display
 #![allow(non_snake_case)]
@@ -140,6 +188,74 @@ pub mod a_Value_expressions {
     //! <p>name.len()</p>
     //! <p>1</p>
     //! ```
+    //!
+    //! # Format specs
+    //!
+    //! An expression in parenthesis can end with a `:` and a format
+    //! spec, as used in the [`format!`] macro.
+    //! The value is then written with that spec, escaped as html,
+    //! without first formatting it to a `String`:
+    //!
+    //! ```text
+    //! <p>The value is @(float_value:.1), item @(n:03).</p>
+    //! ```
+    //!
+    //! Note that a single `:` is only taken as the start of a format
+    //! spec inside parenthesis; `@a::b` and `@name: text` mean just
+    //! what they used to.
+    //!
+    //! The spec is checked when the templates are compiled.
+    //! A width or precision can be a number or a `name$` referring
+    //! to a variable, but not a `*`, since the value is the only
+    //! argument.
+    //!
+    //! # Optional attributes
+    //!
+    //! An attribute that should only be present for some values can
+    //! be written as `@?name=value`.
+    //! For a `bool` value, the attribute is written without a value if
+    //! the value is `true`, and omitted if it is `false`.
+    //! For an `Option` value, the attribute is written with the
+    //! escaped value if it is `Some`, and omitted if it is `None`.
+    //!
+    //! ```text
+    //! <input type="checkbox" @?checked=item.done @?title=item.note>
+    //! ```
+    //!
+    //! A `class` attribute can be given as a list of class names, each
+    //! with an optional condition.
+    //! The attribute is omitted if no class name is included.
+    //!
+    //! ```text
+    //! <li @?class=["item", "done" if item.done, item.kind()]>...</li>
+    //! ```
+    //!
+    //! The attribute value is given by any expression that is allowed
+    //! after `@`.
+    //! The [`Attr`] and [`ClassList`] types that these give can also be
+    //! used directly.
+    //! With contextual escaping (see below), the value of an url,
+    //! `style` or `on...` attribute is escaped for that attribute,
+    //! using [`EscapedAttr`].
+    //!
+    //! [`Attr`]: crate::templates::Attr
+    //! [`EscapedAttr`]: crate::templates::EscapedAttr
+    //! [`ClassList`]: crate::templates::ClassList
+    //!
+    //! # Including files
+    //!
+    //! The contents of a file can be included in a template when it is
+    //! compiled, e.g. to inline an svg icon or some critical css.
+    //! The file name is relative to the template file.
+    //!
+    //! ```text
+    //! <button>@include_raw("icons/check.svg") Ok</button>
+    //! <pre>@include_escaped("example.rs")</pre>
+    //! ```
+    //!
+    //! With `@include_raw`, the file is included as is, while
+    //! `@include_escaped` escapes it as html text.
+    //! Cargo will rebuild the templates when an included file changes.
 }
 
 pub mod b_Loops {
@@ -178,6 +294,71 @@ pub mod b_Loops {
     //!     <p>@name is @age years old.</p>
     //! }
     //! ```
+    //!
+    //! ## Separators, empty loops and loop info
+    //!
+    //! A `@for` loop can be followed by a `@sep` block, that is
+    //! rendered between each pair of items, and by an `else` block,
+    //! that is rendered if the loop has no items at all:
+    //!
+    //! ```text
+    //! <p>@for tag in tags {@tag} @sep {, } else {No tags}.</p>
+    //! ```
+    //!
+    //! Writing `with` and a name after the iterable expression makes a
+    //! [`LoopInfo`] with that name available in the loop body.
+    //! It tells the (zero-based) `index` of the current item, and if
+    //! it is the `first` and / or `last` item:
+    //!
+    //! ```text
+    //! @for item in items with info {
+    //!   <li@if info.last { class="last"}>@info.index: @item</li>
+    //! }
+    //! ```
+    //!
+    //! [`LoopInfo`]: crate::templates::LoopInfo
+    //!
+    //! ## while
+    //!
+    //! There are also `@while` loops, with either a boolean condition
+    //! or a `let` pattern, just as in rust:
+    //!
+    //! ```text
+    //! @while let Some(page) = cursor.next_page() {
+    //!     <p>Page @page.number()</p>
+    //! }
+    //! ```
+    //!
+    //! The condition is written just as the condition of an `@if`.
+    //!
+    //! ## break and continue
+    //!
+    //! Inside a loop, `@break` ends the loop and `@continue` goes on
+    //! with the next item.
+    //! A loop can be given a label, so that an inner loop can break
+    //! or continue it:
+    //!
+    //! ```text
+    //! @'rows: for row in rows {
+    //!   @for item in row {
+    //!     @if item.is_empty() {@continue}
+    //!     @if item.is_last() {@break 'rows}
+    //!     <li>@item</li>
+    //!   }
+    //! }
+    //! ```
+    //!
+    //! A `@sep` block is written just before the first output of
+    //! each item after the first one, so an item that is skipped by
+    //! `@continue` before writing anything gets no separator either.
+    //! Likewise, the `index` in the [`LoopInfo`] counts only the
+    //! items that have written output, so `first` (which is true
+    //! when `index` is zero) stays true until some item has written
+    //! output.
+    //!
+    //! Using `@break` or `@continue` outside of a loop, including in
+    //! a block given as an argument to another template, is an error
+    //! when compiling the templates.
 }
 
 pub mod c_Conditionals {
@@ -207,6 +388,8 @@ pub mod c_Conditionals {
     //!
     //! The condition or let expression should allow anything that would be
     //! allowed in the same place in plain rust.
+    //! That includes arithmetic, bitwise and comparison operators,
+    //! negation and `as` casts, e.g. `@if count % 2 == 0 {`.
     //! As with loops, the things in the curly brackets are ructe template
     //! code.
     //!
@@ -228,6 +411,18 @@ pub mod c_Conditionals {
     //! The let expression and patterns should allow anything that would be
     //! allowed in the same place in plain rust.
     //! As above, the things in the curly brackets are ructe template code.
+    //!
+    //! This includes or-patterns, range patterns, bindings and match
+    //! guards:
+    //!
+    //! ```text
+    //! @match status {
+    //!   Status::Done | Status::Closed => {<span class="done">done</span>}
+    //!   Status::Open(n) if n > 10 => {<span class="hot">@n</span>}
+    //!   Status::Open(n @ 1..=10) => {<span>@n</span>}
+    //!   _ => {}
+    //! }
+    //! ```
 }
 
 pub mod d_Calling_other_templates {
@@ -302,6 +497,106 @@ pub mod d_Calling_other_templates {
     //! })
     //! ```
     //!
+    //! ## Named arguments and trailing blocks
+    //!
+    //! Arguments can also be given by name, after any positional
+    //! arguments.
+    //! When the last argument is a block, it can be given after the
+    //! closing parenthesis instead:
+    //!
+    //! ```text
+    //! @:base_page_html(title = "Example") {
+    //!     <p>page content ...</p>
+    //! }
+    //! ```
+    //!
+    //! Named arguments are checked against the argument declaration
+    //! of the called template when the templates are compiled, and
+    //! passed in the declared order.
+    //! For that to work, the called template must be found through a
+    //! `@use` statement or in the same directory.
+    //! A glob `@use`, such as `@use super::statics::*;`, is only
+    //! used for a name if there is a template at that path.
+    //!
+    //! The called template can also be given as a path, such as
+    //! `@:layouts::base_html(...)`, if `layouts` is in scope.
+    //!
+    //! ## Default values
+    //!
+    //! An argument can have a default value in the declaration of a
+    //! template:
+    //!
+    //! ```text
+    //! @(label: &str, size: u8 = 3, class: Option<&str> = None)
+    //! ```
+    //!
+    //! The argument can then be left out when calling the template
+    //! with `@:`, e.g. `@:widget_html("Hi", class = Some("big"))`.
+    //! The default value is evaluated in the module of the called
+    //! template, so it can refer to anything that template `@use`s,
+    //! but not to its other arguments.
+    //! `Content` and `impl Trait` arguments can not have defaults.
+    //!
+    //! For a template with default values, a struct is also generated,
+    //! named after the template function in CamelCase, such as
+    //! `WidgetHtml` for `widget_html`.
+    //! It has a public field for each argument, a `new` method that
+    //! takes the required arguments, and a `render` method:
+    //!
+    //! ```ignore
+    //! let mut widget = templates::WidgetHtml::new("Hi");
+    //! widget.size = 5;
+    //! widget.render(&mut out)?;
+    //! ```
+    //!
+    //! If all arguments have default values, the struct also
+    //! implements [`Default`].
+    //! A `Content` argument is given as a closure taking a
+    //! `&mut dyn Write`.
+    //!
+    //! When its fields can be copied or cloned, the struct also
+    //! implements `ToHtml`, so a struct value can be given to another
+    //! template and written just like any other value, as
+    //! `@widget`.
+    //! That is the case when each field is a `Content`, a `String`,
+    //! a shared reference, a `bool`, a `char`, a number, or an
+    //! `Option` or tuple of those.
+    //! Fields of a type that uses a generic parameter or is an `impl`
+    //! type are supported as well, and get a `Clone` bound.
+    //! A field of any other type, such as a `Vec<T>`, an
+    //! `Option<String>` or a type of your own, means that there is no
+    //! `ToHtml` impl, and a warning about it when compiling the
+    //! templates.
+    //! Since a struct that is `ToHtml` can not also be `Display`, it
+    //! has a `display` method instead, for use with `format!`:
+    //!
+    //! ```ignore
+    //! let html = format!("{}", widget.display());
+    //! ```
+    //!
+    //! To get a struct for every template, not only those with default
+    //! values, use
+    //! [`Ructe::template_structs`](crate::Ructe::template_structs).
+    //!
+    //! ## Helper functions
+    //!
+    //! A template file can define helpers with `@fn`, at the top level
+    //! of the template body.
+    //! A helper is declared like a template, with a name, optional
+    //! generic parameters and arguments, followed by a block:
+    //!
+    //! ```text
+    //! @(checks: &[(&str, bool)])
+    //! @fn cell(label: &str, ok: bool) {<td class="@ok">@label</td>}
+    //! <tr>@for (label, ok) in checks {@:cell(label, *ok)}</tr>
+    //! ```
+    //!
+    //! Helpers are called with `@:` just like templates, but they are
+    //! private to the template file that defines them.
+    //! A helper name is found before any template with the same name.
+    //! Helpers of a template used with `@extends` are also available
+    //! in the extending template.
+    //!
     //! ## Intermediate templates with block parameters
     //!
     //! Due to a limitation in Ructe, it is currently not possible to
@@ -324,3 +619,287 @@ pub mod d_Calling_other_templates {
     //! }}
     //! ```
 }
+
+pub mod e_Local_bindings {
+    //! Intermediate values can be given a name with `@let`, and
+    //! arbitrary rust statements can be put in a code block.
+    //!
+    //! # Local bindings
+    //!
+    //! A `@let` binding works just as a rust `let` statement, and
+    //! must be terminated by a semicolon:
+    //!
+    //! ```text
+    //! @for user in users {
+    //!   @let name = user.profile().display_name();
+    //!   <p title="@name">@name</p>
+    //! }
+    //! ```
+    //!
+    //! The binding is visible in the rest of the enclosing block, in
+    //! the example above the body of the `@for` loop.
+    //! The left hand side can be a name, optionally with `mut`, or an
+    //! irrefutable pattern, and can have a type annotation:
+    //! `@let (min, max): (u32, u32) = limits();`.
+    //!
+    //! # Code blocks
+    //!
+    //! Any rust statements can be put in a code block.
+    //! Since `@{` is the escape for a verbatim `{`, a code block is
+    //! delimited by double braces:
+    //!
+    //! ```text
+    //! @{{
+    //!   let count = items.len();
+    //!   let plural = if count == 1 { "" } else { "s" };
+    //! }}
+    //! <p>@count item@plural</p>
+    //! ```
+    //!
+    //! Just as with `@let`, any bindings made in a code block are
+    //! visible in the rest of the enclosing block.
+}
+
+pub mod f_Template_inheritance {
+    //! A template can extend another template, overriding some
+    //! parts of it.
+    //!
+    //! # Blocks
+    //!
+    //! A template can mark parts of its body as named blocks, that
+    //! extending templates may override:
+    //!
+    //! ```text
+    //! @(title: &str)
+    //!
+    //! <html>
+    //!   <head><title>@title</title>@block head {}</head>
+    //!   <body>
+    //!     @block content {<p>No content.</p>}
+    //!   </body>
+    //! </html>
+    //! ```
+    //!
+    //! When the template is called directly, each block is rendered
+    //! with the default content in its curly brackets.
+    //!
+    //! # Extends
+    //!
+    //! Given the above in `layout.rs.html`, another template can
+    //! extend it:
+    //!
+    //! ```text
+    //! @(title: &str, user: &User)
+    //! @extends layout_html(&format!("{title} - Example"))
+    //!
+    //! @block content {
+    //!   <h1>@title</h1>
+    //!   <p>Hello @user.name!</p>
+    //! }
+    //! ```
+    //!
+    //! The `@extends` declaration comes right after the argument
+    //! declaration, and gives the arguments to the extended
+    //! template, just as in a call.
+    //! After it, the template may only contain `@block` definitions
+    //! (and comments).
+    //! Each of them overrides the block with the same name in the
+    //! extended template, and blocks that are not overridden keep
+    //! their default content.
+    //! It is an error to define a block that the extended template
+    //! does not have.
+    //!
+    //! The name of the extended template is a path as in a `@use`
+    //! statement, so `super::layout_html` (or just `layout_html`) is
+    //! `layout.rs.html` in the same directory, while
+    //! `super::super::layout_html` and `super::layouts::base_html`
+    //! can be used to find it in the parent directory or a
+    //! subdirectory.
+    //! No `@use` is needed for the extended template.
+    //! The `@use` statements of the extended template are added to
+    //! the extending template, with `super::` paths adjusted to the
+    //! directory of the extending template.
+    //! The same goes for `super::` paths in template calls in the
+    //! extended template.
+    //!
+    //! The extended template may in turn extend another template, and
+    //! the blocks in an overriding block can be overridden further
+    //! down the chain.
+    //!
+    //! This is all resolved when the templates are compiled, so the
+    //! generated function for the extending template contains the
+    //! body of the extended template directly.
+    //! The overriding blocks are rendered with the arguments of the
+    //! template that defines them, even where the extended template
+    //! has an argument with the same name.
+}
+
+pub mod g_Whitespace {
+    //! Text in a template is written exactly as it is, including the
+    //! indentation and line breaks around `@if`, `@for` and other
+    //! blocks.
+    //!
+    //! # Trim markers
+    //!
+    //! A `@~` marker removes all whitespace on both sides of it, so
+    //! it can be put before or after a block to remove the whitespace
+    //! there:
+    //!
+    //! ```text
+    //! <p>
+    //!   @~@for item in items {@~
+    //!     [@item]
+    //!   @~}@~
+    //! </p>
+    //! ```
+    //!
+    //! With `items` as `["a", "b"]`, this renders as:
+    //!
+    //! ```text
+    //! <p>[a][b]</p>
+    //! ```
+    //!
+    //! # Collapsing whitespace
+    //!
+    //! With a `@whitespace collapse;` line before the declaration, the
+    //! whitespace of a template is collapsed when it is compiled.
+    //! Each run of whitespace is replaced by a single space, and
+    //! whitespace that includes a line break is removed completely
+    //! between tags.
+    //! Whitespace next to an expression, such as `@name`, is kept as a
+    //! single space.
+    //! The content of `pre`, `textarea` and `script` elements, of
+    //! quoted attribute values and of `@raw` blocks is left as is.
+    //!
+    //! ```text
+    //! @whitespace collapse;
+    //! @(items: &[&str])
+    //! <ul>
+    //!   @for item in items {
+    //!     <li>@item</li>
+    //!   }
+    //! </ul>
+    //! ```
+    //!
+    //! This renders as `<ul><li>a</li><li>b</li></ul>`.
+    //!
+    //! The mode can also be set for all templates with
+    //! [`Ructe::collapse_whitespace`](crate::Ructe::collapse_whitespace),
+    //! and a single template can then opt out with
+    //! `@whitespace preserve;`.
+}
+
+pub mod h_Contextual_escaping {
+    //! By default, the value of every expression is escaped as html
+    //! text (unless it is wrapped in [`Html`]).
+    //! That is safe in element content and in quoted attribute
+    //! values, but not in e.g. a `script` element or an `href`.
+    //!
+    //! # Escaping by context
+    //!
+    //! With an `@escaping contextual;` line before the declaration, the
+    //! escaping of each expression depends on where in the html it is:
+    //!
+    //! * In a quoted string in a `script` element or an `on...`
+    //!   attribute, the value is escaped for use in a javascript
+    //!   string.
+    //! * In a `style` element or attribute, the value is escaped for
+    //!   css.
+    //! * At the start of an url attribute, such as `href` or `src`,
+    //!   the value is written as an url, but an url with an unsafe
+    //!   scheme (such as `javascript:`) is replaced by `#`.
+    //! * Later in an url attribute, the value is url encoded.
+    //! * Anywhere else, the value is escaped as html text.
+    //!
+    //! ```text
+    //! @escaping contextual;
+    //! @(name: &str)
+    //! <a href="/search?q=@name" onclick="greet('@name')">@name</a>
+    //! ```
+    //!
+    //! An expression as an unquoted attribute value, such as
+    //! `<a href=@url>`, can not be escaped safely, so with contextual
+    //! escaping it is an error when the template is compiled.
+    //! So is an expression in javascript outside of a quoted string,
+    //! such as `<script>var x = @x;</script>` (write `"@x"` instead),
+    //! an expression in a javascript template literal, and an
+    //! optional `on...` attribute.
+    //!
+    //! The values are escaped by the [`JsString`], [`CssEscaped`],
+    //! [`SafeUrl`] and [`UrlEncoded`] wrappers, that can also be used
+    //! explicitly.
+    //! They escape the plain text of any value, even a value wrapped in
+    //! [`Html`].
+    //!
+    //! Each branch of an `@if` or `@match` starts in the context before
+    //! it, and a `@block` that overrides a block of the template it
+    //! extends starts in the context of that block.
+    //!
+    //! Contextual escaping can also be turned on for all templates
+    //! with
+    //! [`Ructe::contextual_escaping`](crate::Ructe::contextual_escaping),
+    //! and a single template can then opt out with `@escaping html;`.
+    //!
+    //! [`Html`]: crate::templates::Html
+    //! [`JsString`]: crate::templates::JsString
+    //! [`CssEscaped`]: crate::templates::CssEscaped
+    //! [`SafeUrl`]: crate::templates::SafeUrl
+    //! [`UrlEncoded`]: crate::templates::UrlEncoded
+}
+
+pub mod i_Async_rendering {
+    //! With the `tokio1` feature of ructe, and `async_templates(true)`
+    //! when compiling the templates, each template also gets an async
+    //! variant, with an `_async` suffix, that writes to a [tokio]
+    //! `AsyncWrite`.
+    //!
+    //! ```toml
+    //! [build-dependencies]
+    //! ructe = { version = "0.18", features = ["tokio1"] }
+    //!
+    //! [dependencies]
+    //! tokio = { version = "1", features = ["io-util"] }
+    //! ```
+    //!
+    //! ```ignore
+    //! Ructe::from_env()?
+    //!     .async_templates(true)
+    //!     .compile_templates("templates")
+    //! ```
+    //!
+    //! The async variant takes the output by value, and gives it back
+    //! when the template is written:
+    //!
+    //! ```text
+    //! let out = templates::hello_html_async(socket, "World").await?;
+    //! ```
+    //!
+    //! The output must be `AsyncWrite + Unpin + Send`.
+    //! The value of each expression is written to a buffer before it
+    //! is written to the output, since [`ToHtml`] only writes
+    //! synchronously.
+    //!
+    //! # Content and calls
+    //!
+    //! In the async variant, `@:` calls to other templates and helpers
+    //! call their async variants, and content blocks become closures
+    //! that take the output and return a boxed future that gives it
+    //! back.
+    //! Calls with `@:` to anything that is not a template or a
+    //! `Content` argument, such as a rust function taking a `Write`,
+    //! do not work in the async variant, so templates that make such
+    //! calls must be compiled (in a separate `compile_templates` call)
+    //! without `async_templates`.
+    //!
+    //! A `@fn` helper gets an async variant too, but as an
+    //! `async fn` can not call itself, a helper that calls itself
+    //! (directly or through another helper) does not compile with
+    //! `async_templates`.
+    //!
+    //! Since the arguments are captured by the future of an async
+    //! function, an argument type that has a lifetime must write it
+    //! out, as in `&User<'_>` rather than `&User`.
+    //!
+    //! [tokio]: https://crates.rs/crates/tokio
+    //! [`ToHtml`]: crate::templates::ToHtml
+}
@@ -0,0 +1,485 @@
+//! Map compiler diagnostics for generated code to template sources.
+use crate::parseresult::{END, MARKER};
+use nom::branch::alt;
+use nom::bytes::complete::take_while1;
+use nom::character::complete::{anychar, char, multispace0, none_of};
+use nom::combinator::{map, recognize, value};
+use nom::multi::{many0, separated_list0};
+use nom::sequence::{delimited, preceded, separated_pair};
+use nom::{IResult, Parser as _};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::fs::read_to_string;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+
+/// Rewrite json diagnostics from rustc or cargo to point at the
+/// template sources of generated code.
+///
+/// The generated code for each template expression, template
+/// declaration and argument is preceded by a comment marking its
+/// position in the template file, such as
+/// `// ructe: templates/page.rs.html:12:5`, and each generated
+/// function is followed by a `// ructe: end` comment.
+/// Each line of `input` is a json message, such as those written by
+/// `cargo build --message-format=json`.
+/// In each span of a diagnostic that is in a generated template
+/// module, the file name, line and column are replaced by those of
+/// the nearest marker above it, and so are the locations in the
+/// rendered message.
+/// A span after an end marker, such as in a generated props struct,
+/// is left as it is.
+/// Other lines are written to `output` unchanged.
+///
+/// The template paths are as given to
+/// [`Ructe::compile_templates`](crate::Ructe::compile_templates),
+/// so normally relative to the directory of the crate.
+///
+/// # Examples
+///
+/// A small program, separate from the crate using the templates,
+/// can filter the output of cargo:
+///
+/// ```no_run
+/// fn main() -> std::io::Result<()> {
+///     ructe::map_diagnostics(std::io::stdin().lock(), std::io::stdout())
+/// }
+/// ```
+///
+/// # Errors
+///
+/// Returns an error if reading `input` or writing `output` fails.
+/// A generated file that can't be read is left unmapped.
+pub fn map_diagnostics(
+    input: impl BufRead,
+    mut output: impl Write,
+) -> io::Result<()> {
+    let mut markers = Markers::default();
+    for line in input.lines() {
+        writeln!(output, "{}", markers.map_line(&line?))?;
+    }
+    Ok(())
+}
+
+/// The markers of generated files, by file name as given in the
+/// diagnostics.
+#[derive(Default)]
+struct Markers(HashMap<String, Vec<Marker>>);
+
+/// A marker on `line` of generated code, for a position in a
+/// template, or `None` for the end of code generated from a template.
+#[derive(Debug, PartialEq, Eq)]
+struct Marker {
+    line: usize,
+    location: Option<Location>,
+}
+
+/// A position in a template, with the path escaped for json.
+#[derive(Debug, PartialEq, Eq)]
+struct Location {
+    path: String,
+    line: usize,
+    col: usize,
+}
+
+impl Markers {
+    /// Map the spans of a json message `line`.
+    ///
+    /// A line that is not json, or has no spans in generated
+    /// template code, is returned unchanged.
+    fn map_line(&mut self, line: &str) -> String {
+        let Ok(("", mut message)) = json(line) else {
+            return line.to_string();
+        };
+        let mut locations = Vec::new();
+        self.map_spans(&mut message, &mut locations);
+        if locations.is_empty() {
+            return line.to_string();
+        }
+        replace_rendered(&mut message, &locations);
+        message.to_string()
+    }
+
+    /// Map all spans in `value`, including nested ones.
+    ///
+    /// The generated and template location of each mapped span is
+    /// added to `locations`.
+    fn map_spans(
+        &mut self,
+        value: &mut Json,
+        locations: &mut Vec<(String, String)>,
+    ) {
+        match value {
+            Json::Object(fields) => {
+                for (_, value) in fields.iter_mut() {
+                    self.map_spans(value, locations);
+                }
+                locations.extend(self.map_span(fields));
+            }
+            Json::Array(items) => {
+                for item in items {
+                    self.map_spans(item, locations);
+                }
+            }
+            Json::String(_) | Json::Scalar(_) => {}
+        }
+    }
+
+    /// Map `span` if it is in a generated template module.
+    ///
+    /// Returns the generated and template locations of the span.
+    fn map_span(
+        &mut self,
+        span: &mut [(&str, Json)],
+    ) -> Option<(String, String)> {
+        let Some(Json::String(file)) = field(span, "file_name") else {
+            return None;
+        };
+        let file = file.to_string();
+        let line_start = number(span, "line_start")?;
+        let marker = self
+            .markers(&file)
+            .iter()
+            .rev()
+            .find(|m| m.line < line_start)?;
+        let Location { path, line, col } = marker.location.as_ref()?;
+        let (path, line, col) = (path.clone(), *line, *col);
+        let col_start = number(span, "column_start").unwrap_or(1);
+        let col_end = number(span, "column_end").unwrap_or(col_start);
+        let width = if number(span, "line_end") == Some(line_start) {
+            col_end.saturating_sub(col_start).max(1)
+        } else {
+            1
+        };
+        set_field(span, "file_name", Json::String(path.clone().into()));
+        set_number(span, "line_start", line);
+        set_number(span, "line_end", line);
+        set_number(span, "column_start", col);
+        set_number(span, "column_end", col + width);
+        Some((
+            format!("{file}:{line_start}:{col_start}"),
+            format!("{path}:{line}:{col}"),
+        ))
+    }
+
+    /// The markers in the generated `file`, if it is a template module.
+    fn markers(&mut self, file: &str) -> &[Marker] {
+        self.0.entry(file.to_string()).or_insert_with(|| {
+            let path = unescape(file);
+            let file = Path::new(&path);
+            let is_template = file.extension().map_or(false, |e| e == "rs")
+                && file
+                    .file_stem()
+                    .and_then(|name| name.to_str())
+                    .map_or(false, |name| name.starts_with("template_"));
+            if is_template {
+                read_to_string(path)
+                    .map(|code| markers(&code))
+                    .unwrap_or_default()
+            } else {
+                Vec::new()
+            }
+        })
+    }
+}
+
+/// The markers in generated `code`.
+fn markers(code: &str) -> Vec<Marker> {
+    code.lines()
+        .enumerate()
+        .filter_map(|(i, line)| {
+            let location = line.strip_prefix(MARKER)?;
+            if location == END {
+                return Some(Marker {
+                    line: i + 1,
+                    location: None,
+                });
+            }
+            let mut parts = location.rsplitn(3, ':');
+            let col = parts.next()?.parse().ok()?;
+            let template_line = parts.next()?.parse().ok()?;
+            let path =
+                parts.next()?.replace('\\', "\\\\").replace('"', "\\\"");
+            Some(Marker {
+                line: i + 1,
+                location: Some(Location {
+                    path,
+                    line: template_line,
+                    col,
+                }),
+            })
+        })
+        .collect()
+}
+
+/// Replace the generated `locations` in all rendered messages in
+/// `value`.
+fn replace_rendered(value: &mut Json, locations: &[(String, String)]) {
+    match value {
+        Json::Object(fields) => {
+            for (key, value) in fields.iter_mut() {
+                match value {
+                    Json::String(text) if *key == "rendered" => {
+                        for (generated, template) in locations {
+                            *text =
+                                replace_location(text, generated, template)
+                                    .into();
+                        }
+                    }
+                    value => replace_rendered(value, locations),
+                }
+            }
+        }
+        Json::Array(items) => {
+            for item in items {
+                replace_rendered(item, locations);
+            }
+        }
+        Json::String(_) | Json::Scalar(_) => {}
+    }
+}
+
+/// Replace each `generated` location in `text` with `template`.
+///
+/// A location followed by a digit is part of another location, such
+/// as `x.rs:3:12` for `x.rs:3:1`, and is not replaced.
+fn replace_location(text: &str, generated: &str, template: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(i) = rest.find(generated) {
+        result.push_str(&rest[..i]);
+        rest = &rest[i + generated.len()..];
+        if rest.starts_with(|c: char| c.is_ascii_digit()) {
+            result.push_str(generated);
+        } else {
+            result.push_str(template);
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+/// A json value, with strings (still escaped) and other scalars kept
+/// as written.
+#[derive(Debug)]
+enum Json<'a> {
+    Object(Vec<(&'a str, Json<'a>)>),
+    Array(Vec<Json<'a>>),
+    String(Cow<'a, str>),
+    Scalar(Cow<'a, str>),
+}
+
+impl Display for Json<'_> {
+    fn fmt(&self, out: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Json::Object(fields) => {
+                out.write_str("{")?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    let sep = if i > 0 { "," } else { "" };
+                    write!(out, "{sep}\"{key}\":{value}")?;
+                }
+                out.write_str("}")
+            }
+            Json::Array(items) => {
+                out.write_str("[")?;
+                for (i, item) in items.iter().enumerate() {
+                    let sep = if i > 0 { "," } else { "" };
+                    write!(out, "{sep}{item}")?;
+                }
+                out.write_str("]")
+            }
+            Json::String(s) => write!(out, "\"{s}\""),
+            Json::Scalar(s) => out.write_str(s),
+        }
+    }
+}
+
+/// Parse a json value.
+fn json(input: &str) -> IResult<&str, Json<'_>> {
+    delimited(
+        multispace0,
+        alt((
+            map(
+                delimited(
+                    char('{'),
+                    separated_list0(
+                        char(','),
+                        separated_pair(
+                            preceded(multispace0, string),
+                            preceded(multispace0, char(':')),
+                            json,
+                        ),
+                    ),
+                    preceded(multispace0, char('}')),
+                ),
+                Json::Object,
+            ),
+            map(
+                delimited(
+                    char('['),
+                    separated_list0(char(','), json),
+                    preceded(multispace0, char(']')),
+                ),
+                Json::Array,
+            ),
+            map(string, |s| Json::String(s.into())),
+            map(
+                take_while1(|c: char| {
+                    c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.')
+                }),
+                |s: &str| Json::Scalar(s.into()),
+            ),
+        )),
+        multispace0,
+    )
+    .parse(input)
+}
+
+/// A json string, without the quotes but still escaped.
+fn string(input: &str) -> IResult<&str, &str> {
+    delimited(
+        char('"'),
+        recognize(many0(alt((
+            value((), preceded(char('\\'), anychar)),
+            value((), none_of("\\\"")),
+        )))),
+        char('"'),
+    )
+    .parse(input)
+}
+
+/// The value of field `key` in a json object.
+fn field<'j, 'a>(
+    fields: &'j [(&str, Json<'a>)],
+    key: &str,
+) -> Option<&'j Json<'a>> {
+    fields.iter().find(|(k, _)| *k == key).map(|(_, v)| v)
+}
+
+/// Set the value of field `key`, if present, in a json object.
+fn set_field<'a>(
+    fields: &mut [(&str, Json<'a>)],
+    key: &str,
+    value: Json<'a>,
+) {
+    if let Some((_, v)) = fields.iter_mut().find(|(k, _)| *k == key) {
+        *v = value;
+    }
+}
+
+/// The value of the number field `key` in a json object.
+fn number(fields: &[(&str, Json)], key: &str) -> Option<usize> {
+    match field(fields, key)? {
+        Json::Scalar(n) => n.parse().ok(),
+        _ => None,
+    }
+}
+
+/// Set the value of the number field `key` in a json object.
+fn set_number(fields: &mut [(&str, Json)], key: &str, value: usize) {
+    set_field(fields, key, Json::Scalar(value.to_string().into()));
+}
+
+/// Unescape a json string, as far as needed for a file name.
+fn unescape(s: &str) -> String {
+    s.replace("\\\"", "\"").replace("\\\\", "\\")
+}
+
+#[cfg(test)]
+mod test {
+    use super::{markers, Location, Marker, Markers};
+
+    const CODE: &str = "fn page_html() {\n\
+                        // ructe: templates/page.rs.html:3:5\n\
+                        x.to_html(_ructe_out_.by_ref())?;\n\
+                        }\n\
+                        // ructe: end\n\
+                        pub struct PageHtml {}\n";
+
+    #[test]
+    fn find_markers() {
+        assert_eq!(
+            markers(CODE),
+            [
+                Marker {
+                    line: 2,
+                    location: Some(Location {
+                        path: "templates/page.rs.html".into(),
+                        line: 3,
+                        col: 5,
+                    }),
+                },
+                Marker {
+                    line: 5,
+                    location: None,
+                },
+            ],
+        );
+    }
+
+    #[test]
+    fn map_span() {
+        let file = "/out/templates/template_page_html.rs";
+        let mut m = Markers::default();
+        m.0.insert(file.into(), markers(CODE));
+        let line = format!(
+            "{{\"message\":{{\"rendered\":\"error: x\\n --> {file}:3:1\\n\",\
+             \"spans\":[{{\"column_end\":2,\"column_start\":1,\
+             \"expansion\":null,\"file_name\":\"{file}\",\
+             \"line_end\":3,\"line_start\":3}}]}}}}"
+        );
+        assert_eq!(
+            m.map_line(&line),
+            "{\"message\":{\"rendered\":\
+             \"error: x\\n --> templates/page.rs.html:3:5\\n\",\
+             \"spans\":[{\"column_end\":6,\"column_start\":5,\
+             \"expansion\":null,\"file_name\":\"templates/page.rs.html\",\
+             \"line_end\":3,\"line_start\":3}]}}",
+        );
+    }
+
+    #[test]
+    fn map_spans_with_prefix_locations() {
+        let file = "/out/templates/template_page_html.rs";
+        let mut m = Markers::default();
+        m.0.insert(file.into(), markers(CODE));
+        let line = format!(
+            "{{\"rendered\":\" --> {file}:3:1\\n --> {file}:3:12\\n\",\
+             \"spans\":[{{\"column_end\":2,\"column_start\":1,\
+             \"file_name\":\"{file}\",\"line_end\":3,\"line_start\":3}},\
+             {{\"column_end\":14,\"column_start\":12,\
+             \"file_name\":\"{file}\",\"line_end\":3,\"line_start\":3}}]}}"
+        );
+        assert_eq!(
+            m.map_line(&line),
+            "{\"rendered\":\" --> templates/page.rs.html:3:5\\n \
+             --> templates/page.rs.html:3:5\\n\",\
+             \"spans\":[{\"column_end\":6,\"column_start\":5,\
+             \"file_name\":\"templates/page.rs.html\",\
+             \"line_end\":3,\"line_start\":3},\
+             {\"column_end\":7,\"column_start\":5,\
+             \"file_name\":\"templates/page.rs.html\",\
+             \"line_end\":3,\"line_start\":3}]}",
+        );
+    }
+
+    #[test]
+    fn span_after_end_unchanged() {
+        let file = "/out/templates/template_page_html.rs";
+        let mut m = Markers::default();
+        m.0.insert(file.into(), markers(CODE));
+        let line = format!(
+            "{{\"spans\":[{{\"file_name\":\"{file}\",\
+             \"line_start\":6,\"line_end\":6}}]}}"
+        );
+        assert_eq!(m.map_line(&line), line);
+    }
+
+    #[test]
+    fn other_files_unchanged() {
+        let line = "{\"spans\":[{\"file_name\":\"src/main.rs\",\
+                    \"line_start\":3,\"line_end\":3}]}";
+        assert_eq!(Markers::default().map_line(line), line);
+    }
+}
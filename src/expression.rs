@@ -1,10 +1,14 @@
 use crate::parseresult::PResult;
 use nom::branch::alt;
 use nom::bytes::complete::{escaped, is_a, is_not, tag};
-use nom::character::complete::{alpha1, char, digit1, none_of, one_of};
+use nom::character::complete::{
+    alpha1, char, digit1, multispace0, multispace1, none_of, one_of,
+};
 use nom::combinator::{map, map_res, not, opt, recognize, value};
 use nom::error::context;
-use nom::multi::{fold_many0, many0, separated_list0};
+use nom::multi::{
+    fold_many0, many0, many1, separated_list0, separated_list1,
+};
 use nom::sequence::{delimited, pair, preceded, terminated};
 use nom::Parser as _;
 use std::str::{from_utf8, Utf8Error};
@@ -16,6 +20,7 @@ pub fn expression(input: &[u8]) -> PResult<'_, &str> {
             (
                 alt((tag("&"), tag("*"), tag(""))),
                 alt((
+                    char_literal,
                     rust_name,
                     map_res(digit1, input_to_str),
                     quoted_string,
@@ -42,6 +47,135 @@ pub fn expression(input: &[u8]) -> PResult<'_, &str> {
     .parse(input)
 }
 
+/// A rust pattern, as used in a `match` arm, including or-patterns.
+pub fn pattern(input: &[u8]) -> PResult<'_, &str> {
+    map_res(
+        recognize(context(
+            "Expected rust pattern",
+            (
+                opt(terminated(char('|'), multispace0)),
+                separated_list1(
+                    delimited(multispace0, char('|'), multispace0),
+                    pattern_no_alt,
+                ),
+            ),
+        )),
+        input_to_str,
+    )
+    .parse(input)
+}
+
+fn pattern_no_alt(input: &[u8]) -> PResult<'_, ()> {
+    alt((
+        value(
+            (),
+            (
+                terminated(tag("&"), multispace0),
+                opt(terminated(tag("mut"), multispace1)),
+                pattern_no_alt,
+            ),
+        ),
+        value(
+            (),
+            (
+                opt(terminated(tag("ref"), multispace1)),
+                opt(terminated(tag("mut"), multispace1)),
+                pattern_atom,
+                opt(alt((
+                    value(
+                        (),
+                        (
+                            delimited(multispace0, char('@'), multispace0),
+                            pattern_no_alt,
+                        ),
+                    ),
+                    value(
+                        (),
+                        (
+                            delimited(
+                                multispace0,
+                                alt((tag("..="), tag("..."), tag(".."))),
+                                multispace0,
+                            ),
+                            opt(range_bound),
+                        ),
+                    ),
+                ))),
+            ),
+        ),
+        value((), (tag("..="), multispace0, range_bound)),
+    ))
+    .parse(input)
+}
+
+fn pattern_atom(input: &[u8]) -> PResult<'_, ()> {
+    alt((
+        value((), quoted_string),
+        value((), preceded(char('b'), quoted_string)),
+        range_bound,
+        value((), expr_in_parens),
+        value((), expr_in_brackets),
+    ))
+    .parse(input)
+}
+
+/// A literal or path, that may be used as a bound in a range pattern,
+/// or a path followed by tuple struct or struct fields.
+fn range_bound(input: &[u8]) -> PResult<'_, ()> {
+    alt((
+        value((), char_literal),
+        value(
+            (),
+            (
+                opt(terminated(char('-'), multispace0)),
+                digit1,
+                opt(is_a("0123456789_")),
+                opt((char('.'), digit1)),
+                opt(is_a("0123456789_abcdefABCDEFiousxz")),
+            ),
+        ),
+        value(
+            (),
+            (
+                opt(tag("::")),
+                separated_list1(tag("::"), rust_name),
+                opt(alt((
+                    value((), expr_in_parens),
+                    value((), preceded(multispace0, expr_in_braces)),
+                    value(
+                        (),
+                        preceded(
+                            char('!'),
+                            alt((
+                                expr_in_parens,
+                                expr_in_brackets,
+                                expr_in_braces,
+                            )),
+                        ),
+                    ),
+                ))),
+            ),
+        ),
+    ))
+    .parse(input)
+}
+
+fn char_literal(input: &[u8]) -> PResult<'_, &str> {
+    map_res(
+        recognize((
+            opt(char('b')),
+            char('\''),
+            alt((
+                recognize(pair(char('\\'), alt((tag("'"), is_not("'"))))),
+                recognize(none_of("\\'")),
+            )),
+            char('\''),
+        )),
+        input_to_str,
+    )
+    .parse(input)
+}
+
 pub fn input_to_str(s: &[u8]) -> Result<&str, Utf8Error> {
     from_utf8(s)
 }
@@ -72,7 +206,7 @@ fn expr_in_parens(input: &[u8]) -> PResult<'_, &str> {
     .parse(input)
 }
 
-fn expr_in_brackets(input: &[u8]) -> PResult<'_, &str> {
+pub fn expr_in_brackets(input: &[u8]) -> PResult<'_, &str> {
     map_res(
         recognize(delimited(
             tag("["),
@@ -128,6 +262,45 @@ pub fn expr_inside_parens(input: &[u8]) -> PResult<'_, &str> {
     .parse(input)
 }
 
+/// Rust code up to, but not including, a single `:` outside of any
+/// brackets.
+pub fn expr_before_colon(input: &[u8]) -> PResult<'_, &str> {
+    map_res(
+        recognize(many1(alt((
+            value((), is_not("{}[]()\":/")),
+            value((), tag("::")),
+            value((), expr_in_braces),
+            value((), expr_in_brackets),
+            value((), expr_in_parens),
+            value((), quoted_string),
+            value((), rust_comment),
+            value((), terminated(tag("/"), none_of("*"))),
+        )))),
+        input_to_str,
+    )
+    .parse(input)
+}
+
+/// Rust code up to, but not including, a `;` outside of any brackets.
+///
+/// The code may not contain a `@`, so a missing `;` is detected
+/// before the next template expression.
+pub fn expr_before_semicolon(input: &[u8]) -> PResult<'_, &str> {
+    map_res(
+        recognize(many1(alt((
+            value((), is_not("{}[]()\";/@")),
+            value((), expr_in_braces),
+            value((), expr_in_brackets),
+            value((), expr_in_parens),
+            value((), quoted_string),
+            value((), rust_comment),
+            value((), terminated(tag("/"), none_of("*;"))),
+        )))),
+        input_to_str,
+    )
+    .parse(input)
+}
+
 pub fn quoted_string(input: &[u8]) -> PResult<'_, &str> {
     map_res(
         recognize(delimited(
@@ -154,7 +327,7 @@ pub fn rust_comment(input: &[u8]) -> PResult<'_, &[u8]> {
 
 #[cfg(test)]
 mod test {
-    use super::expression;
+    use super::{expression, pattern};
 
     #[test]
     fn expression_1() {
@@ -197,6 +370,14 @@ mod test {
         check_expr("\"foo\"");
     }
     #[test]
+    fn expression_char() {
+        check_expr("'@'");
+    }
+    #[test]
+    fn expression_byte_char_method() {
+        check_expr("b'\\''.is_ascii()");
+    }
+    #[test]
     fn expression_str_paren() {
         check_expr("(\")\")");
     }
@@ -245,6 +426,98 @@ mod test {
         check_expr("(2 + 3*4 - 5/2)");
     }
 
+    #[test]
+    fn pattern_binding() {
+        check_pattern("x");
+    }
+    #[test]
+    fn pattern_wildcard() {
+        check_pattern("_");
+    }
+    #[test]
+    fn pattern_ref_mut() {
+        check_pattern("ref mut x");
+    }
+    #[test]
+    fn pattern_tuple_struct() {
+        check_pattern("Some((a, b))");
+    }
+    #[test]
+    fn pattern_struct() {
+        check_pattern("Point { x: 0, .. }");
+    }
+    #[test]
+    fn pattern_path() {
+        check_pattern("::std::cmp::Ordering::Less");
+    }
+    #[test]
+    fn pattern_reference() {
+        check_pattern("&(name, &mut age)");
+    }
+    #[test]
+    fn pattern_or() {
+        check_pattern("Status::Active | Status::Pending(_)");
+    }
+    #[test]
+    fn pattern_or_leading() {
+        check_pattern("| A | B");
+    }
+    #[test]
+    fn pattern_range_inclusive() {
+        check_pattern("1..=9");
+    }
+    #[test]
+    fn pattern_range_negative() {
+        check_pattern("-10..-1");
+    }
+    #[test]
+    fn pattern_range_from() {
+        check_pattern("100..");
+    }
+    #[test]
+    fn pattern_range_to() {
+        check_pattern("..=0");
+    }
+    #[test]
+    fn pattern_range_chars() {
+        check_pattern("'a'..='z' | 'A'..='Z' | '\\''");
+    }
+    #[test]
+    fn pattern_binding_at() {
+        check_pattern("n @ 1..=5");
+    }
+    #[test]
+    fn pattern_float() {
+        check_pattern("1.5e3_f64");
+    }
+    #[test]
+    fn pattern_hex() {
+        check_pattern("0xff_u8");
+    }
+    #[test]
+    fn pattern_strings() {
+        check_pattern("\"foo\" | b\"bar\"");
+    }
+    #[test]
+    fn pattern_slice() {
+        check_pattern("[first, .., last]");
+    }
+    #[test]
+    fn pattern_macro() {
+        check_pattern("my_const!()");
+    }
+    #[test]
+    fn pattern_stops_at_guard() {
+        assert_eq!(
+            pattern(b"Some(n) if n > 10 =>"),
+            Ok((&b" if n > 10 =>"[..], "Some(n)"))
+        );
+    }
+
+    fn check_pattern(pat: &str) {
+        assert_eq!(pattern(pat.as_bytes()), Ok((&b""[..], pat)));
+    }
+
     fn check_expr(expr: &str) {
         assert_eq!(expression(expr.as_bytes()), Ok((&b""[..], expr)));
     }
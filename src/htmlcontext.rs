@@ -0,0 +1,696 @@
+use crate::parseresult::Pos;
+
+/// Where in an html document the text written so far ends.
+///
+/// This is used to find how each expression in a template should be
+/// escaped.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum HtmlContext {
+    #[default]
+    Text,
+    Comment,
+    /// In the name of a start tag or (if `end` is true) an end tag.
+    TagName {
+        name: String,
+        end: bool,
+    },
+    /// In a tag, between attributes.
+    Tag {
+        name: String,
+        end: bool,
+    },
+    AttrName {
+        name: String,
+        attr: String,
+    },
+    /// After an attribute name, where a `=` may follow.
+    AfterAttrName {
+        name: String,
+        attr: String,
+    },
+    /// After the `=` of an attribute.
+    BeforeValue {
+        name: String,
+        attr: String,
+    },
+    Value {
+        name: String,
+        attr: String,
+        quote: Option<char>,
+        empty: bool,
+        /// Where in the javascript of an event handler attribute.
+        js: JsContext,
+    },
+    /// In the content of a `script` or `style` element.
+    RawText {
+        name: &'static str,
+        /// Where in the javascript of a `script` element.
+        js: JsContext,
+    },
+}
+
+/// Where in some javascript code the text written so far ends.
+///
+/// Only strings and comments are tracked, so a regular expression
+/// literal containing a quote is taken as the start of a string.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum JsContext {
+    #[default]
+    Code,
+    /// After a `/` in code, which may start a comment.
+    Slash,
+    /// In a string (or template literal) quoted by `quote`, after a
+    /// backslash if `escaped` is true.
+    String {
+        quote: char,
+        escaped: bool,
+    },
+    LineComment,
+    BlockComment,
+    /// After a `*` in a block comment, which may end it.
+    BlockStar,
+}
+
+/// The escaping needed for a value in some html context.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Escape {
+    Html,
+    Js,
+    Css,
+    Url,
+    UrlComponent,
+}
+
+impl Escape {
+    /// The escaping needed for a value in the attribute `attr`, at
+    /// the start of the value if `empty` is true.
+    pub fn of_attribute(attr: &str, empty: bool) -> Escape {
+        if attr.starts_with("on") {
+            Escape::Js
+        } else if attr == "style" {
+            Escape::Css
+        } else if URL_ATTRIBUTES.contains(&attr) {
+            if empty {
+                Escape::Url
+            } else {
+                Escape::UrlComponent
+            }
+        } else {
+            Escape::Html
+        }
+    }
+
+    /// The wrapper in `_utils` that gives this escaping, if any.
+    pub fn wrapper(self) -> Option<&'static str> {
+        match self {
+            Escape::Html => None,
+            Escape::Js => Some("JsString"),
+            Escape::Css => Some("CssEscaped"),
+            Escape::Url => Some("SafeUrl"),
+            Escape::UrlComponent => Some("UrlEncoded"),
+        }
+    }
+}
+
+/// A tag that ends in some text.
+pub struct TagEnd {
+    pub name: String,
+    pub end: bool,
+    pub self_closing: bool,
+}
+
+/// Check of the nesting of html elements in a template.
+#[derive(Clone, Debug, Default)]
+pub struct HtmlNesting {
+    context: HtmlContext,
+    /// The elements that are currently open, with the position of
+    /// their start tags.
+    open: Vec<(String, Pos)>,
+    /// The problems found so far.
+    errors: Vec<(Pos, String)>,
+}
+
+/// Elements that have no end tag.
+const VOID_ELEMENTS: &[&str] = &[
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link",
+    "meta", "source", "track", "wbr",
+];
+
+/// Elements that may be closed implicitly.
+const OPTIONAL_END: &[&str] = &[
+    "body", "colgroup", "dd", "dt", "head", "html", "li", "optgroup",
+    "option", "p", "rp", "rt", "tbody", "td", "tfoot", "th", "thead", "tr",
+];
+
+impl HtmlNesting {
+    /// Update the nesting for some literal text at `pos`.
+    pub fn feed(&mut self, text: &str, pos: &Pos) {
+        let HtmlNesting {
+            context,
+            open,
+            errors,
+        } = self;
+        context.feed_tags(text, |tag, offset| {
+            let pos = pos.add(offset);
+            if tag.end {
+                close(open, errors, &tag.name, pos);
+            } else if !tag.self_closing
+                && !VOID_ELEMENTS.contains(&tag.name.as_str())
+            {
+                if open.last().map_or(false, |(name, _)| {
+                    *name == tag.name && OPTIONAL_END.contains(&name.as_str())
+                }) {
+                    open.pop();
+                }
+                open.push((tag.name, pos));
+            }
+        });
+    }
+
+    /// Update the nesting for alternative `branches`, each given as a
+    /// function that updates a nesting.
+    ///
+    /// Each branch should leave the same elements open.
+    pub fn branches<B>(&mut self, branches: impl IntoIterator<Item = B>)
+    where
+        B: FnOnce(&mut HtmlNesting),
+    {
+        let mut result: Option<HtmlNesting> = None;
+        for branch in branches {
+            let mut nesting = HtmlNesting {
+                context: self.context.clone(),
+                open: self.open.clone(),
+                errors: Vec::new(),
+            };
+            branch(&mut nesting);
+            self.errors.append(&mut nesting.errors);
+            let Some(result) = &result else {
+                result = Some(nesting);
+                continue;
+            };
+            let (a, b) = (&result.open, &nesting.open);
+            let n = a.iter().zip(b).take_while(|(a, b)| a.0 == b.0).count();
+            if let Some((name, pos)) = a.get(n).or_else(|| b.get(n)) {
+                self.errors.push((
+                    pos.clone(),
+                    format!(
+                        "<{name}> is open after some branches but not \
+                         after others"
+                    ),
+                ));
+            }
+        }
+        if let Some(result) = result {
+            self.context = result.context;
+            self.open = result.open;
+        }
+    }
+
+    /// Check some content that is written elsewhere, such as a block
+    /// argument to another template, by itself.
+    pub fn apart(&mut self, check: impl FnOnce(&mut HtmlNesting)) {
+        let mut nesting = HtmlNesting::default();
+        check(&mut nesting);
+        self.errors.append(&mut nesting.finish());
+    }
+
+    /// Finish the check, and return the problems found.
+    pub fn finish(mut self) -> Vec<(Pos, String)> {
+        for (name, pos) in self.open {
+            if !OPTIONAL_END.contains(&name.as_str()) {
+                self.errors.push((pos, format!("Unclosed <{name}>")));
+            }
+        }
+        self.errors
+    }
+}
+
+/// Handle the end tag `</name>` at `pos`.
+fn close(
+    open: &mut Vec<(String, Pos)>,
+    errors: &mut Vec<(Pos, String)>,
+    name: &str,
+    pos: Pos,
+) {
+    let Some(i) = open.iter().rposition(|(open, _)| open == name) else {
+        let expected = open
+            .last()
+            .map(|(name, _)| format!(", expected </{name}>"))
+            .unwrap_or_default();
+        errors.push((pos, format!("Unexpected </{name}>{expected}")));
+        return;
+    };
+    for (name, pos) in open.drain(i..).skip(1) {
+        if !OPTIONAL_END.contains(&name.as_str()) {
+            errors.push((pos, format!("Unclosed <{name}>")));
+        }
+    }
+}
+
+/// Attributes with an url value.
+const URL_ATTRIBUTES: &[&str] =
+    &["action", "cite", "formaction", "href", "poster", "src"];
+
+impl JsContext {
+    /// The context after `c`.
+    fn feed(self, c: char) -> JsContext {
+        match self {
+            JsContext::Code | JsContext::Slash => match c {
+                '"' | '\'' | '`' => JsContext::String {
+                    quote: c,
+                    escaped: false,
+                },
+                '/' if self == JsContext::Slash => JsContext::LineComment,
+                '*' if self == JsContext::Slash => JsContext::BlockComment,
+                '/' => JsContext::Slash,
+                _ => JsContext::Code,
+            },
+            JsContext::String { quote, escaped } => match c {
+                _ if escaped => JsContext::String {
+                    quote,
+                    escaped: false,
+                },
+                '\\' => JsContext::String {
+                    quote,
+                    escaped: true,
+                },
+                c if c == quote => JsContext::Code,
+                _ => self,
+            },
+            JsContext::LineComment if c == '\n' => JsContext::Code,
+            JsContext::BlockComment | JsContext::BlockStar => match c {
+                '/' if self == JsContext::BlockStar => JsContext::Code,
+                '*' => JsContext::BlockStar,
+                _ => JsContext::BlockComment,
+            },
+            JsContext::LineComment => self,
+        }
+    }
+
+    /// The escaping needed for an expression in this context.
+    ///
+    /// A value can only be escaped in a string or a comment, so an
+    /// expression in code or in a template literal (where it could
+    /// be in a `${...}` substitution) is an error.
+    fn escape(self) -> Result<Escape, String> {
+        match self {
+            JsContext::Code | JsContext::Slash => {
+                Err("An expression in javascript must be in a quoted string \
+                     to be safely escaped"
+                    .into())
+            }
+            JsContext::String { quote: '`', .. } => {
+                Err("An expression in a javascript template literal can not \
+                     be safely escaped"
+                    .into())
+            }
+            _ => Ok(Escape::Js),
+        }
+    }
+}
+
+impl HtmlContext {
+    /// Update the context for some literal text.
+    pub fn feed(&mut self, text: &str) {
+        self.feed_tags(text, |_, _| ());
+    }
+
+    /// Update the context for some literal text, calling `on_tag`
+    /// for each tag that ends in it, with the offset of the `>`.
+    pub fn feed_tags(
+        &mut self,
+        text: &str,
+        mut on_tag: impl FnMut(TagEnd, usize),
+    ) {
+        let mut rest = text;
+        let mut slash = false;
+        while let Some(c) = rest.chars().next() {
+            let offset = text.len() - rest.len();
+            rest = &rest[c.len_utf8()..];
+            let tag =
+                self.tag().filter(|_| c == '>').map(|(name, end)| TagEnd {
+                    name: name.to_string(),
+                    end,
+                    self_closing: slash,
+                });
+            rest = self.feed_char(c, rest);
+            if let Some(tag) = tag {
+                on_tag(tag, offset);
+            }
+            slash = c == '/';
+        }
+    }
+
+    /// Update the context for some literal text, calling `on_char`
+    /// with the context before each character and its offset.
+    ///
+    /// Characters that are handled together with the one before
+    /// them, such as the `!--` of a comment start, are skipped.
+    pub fn feed_each(
+        &mut self,
+        text: &str,
+        mut on_char: impl FnMut(&HtmlContext, usize),
+    ) {
+        let mut rest = text;
+        while let Some(c) = rest.chars().next() {
+            on_char(self, text.len() - rest.len());
+            rest = self.feed_char(c, &rest[c.len_utf8()..]);
+        }
+    }
+
+    /// True if this context is in a quoted attribute value.
+    pub fn in_quoted_value(&self) -> bool {
+        matches!(self, HtmlContext::Value { quote: Some(_), .. })
+    }
+
+    /// The name of the tag this context is in, and if it is an end
+    /// tag.
+    fn tag(&self) -> Option<(&str, bool)> {
+        match self {
+            HtmlContext::TagName { name, end }
+            | HtmlContext::Tag { name, end } => Some((name, *end)),
+            HtmlContext::AttrName { name, .. }
+            | HtmlContext::AfterAttrName { name, .. }
+            | HtmlContext::BeforeValue { name, .. }
+            | HtmlContext::Value {
+                name, quote: None, ..
+            } => Some((name, false)),
+            _ => None,
+        }
+    }
+
+    /// Update the context for the character `c`, with `rest` as
+    /// the following text.
+    ///
+    /// Returns the part of `rest` that remains to be handled.
+    fn feed_char<'a>(&mut self, c: char, rest: &'a str) -> &'a str {
+        let state = std::mem::take(self);
+        let (state, rest) = match state {
+            HtmlContext::Text if c == '<' => {
+                if let Some(rest) = rest.strip_prefix("!--") {
+                    (HtmlContext::Comment, rest)
+                } else if let Some(rest) = rest.strip_prefix('/') {
+                    (tag_name(true), rest)
+                } else if rest.starts_with(|c: char| c.is_ascii_alphabetic())
+                {
+                    (tag_name(false), rest)
+                } else {
+                    (HtmlContext::Text, rest)
+                }
+            }
+            HtmlContext::Comment => match rest.strip_prefix("->") {
+                Some(rest) if c == '-' => (HtmlContext::Text, rest),
+                _ => (HtmlContext::Comment, rest),
+            },
+            HtmlContext::TagName { mut name, end } => {
+                if c.is_ascii_alphanumeric() || c == '-' {
+                    name.push(c.to_ascii_lowercase());
+                    (HtmlContext::TagName { name, end }, rest)
+                } else {
+                    (HtmlContext::Tag { name, end }.feed_tag(c), rest)
+                }
+            }
+            HtmlContext::Tag { name, end } => {
+                (HtmlContext::Tag { name, end }.feed_tag(c), rest)
+            }
+            state @ (HtmlContext::AttrName { .. }
+            | HtmlContext::AfterAttrName { .. }
+            | HtmlContext::BeforeValue { .. }
+            | HtmlContext::Value { .. }) => (state.feed_attr(c), rest),
+            HtmlContext::RawText { name, js } if c == '<' => {
+                match rest.strip_prefix('/') {
+                    Some(end) if starts_with_tag(end, name) => {
+                        (tag_name(true), end)
+                    }
+                    _ => (
+                        HtmlContext::RawText {
+                            name,
+                            js: js.feed(c),
+                        },
+                        rest,
+                    ),
+                }
+            }
+            HtmlContext::RawText { name, js } => (
+                HtmlContext::RawText {
+                    name,
+                    js: js.feed(c),
+                },
+                rest,
+            ),
+            state => (state, rest),
+        };
+        *self = state;
+        rest
+    }
+
+    /// The context after `c` in an attribute.
+    fn feed_attr(self, c: char) -> HtmlContext {
+        match self {
+            HtmlContext::AttrName { name, mut attr } => match c {
+                '=' => HtmlContext::BeforeValue { name, attr },
+                c if c.is_whitespace() => {
+                    HtmlContext::AfterAttrName { name, attr }
+                }
+                '>' | '/' => start_tag(name).feed_tag(c),
+                c => {
+                    attr.push(c.to_ascii_lowercase());
+                    HtmlContext::AttrName { name, attr }
+                }
+            },
+            HtmlContext::AfterAttrName { name, attr } => match c {
+                '=' => HtmlContext::BeforeValue { name, attr },
+                c if c.is_whitespace() => {
+                    HtmlContext::AfterAttrName { name, attr }
+                }
+                c => start_tag(name).feed_tag(c),
+            },
+            HtmlContext::BeforeValue { name, attr } => match c {
+                c if c.is_whitespace() => {
+                    HtmlContext::BeforeValue { name, attr }
+                }
+                '>' => start_tag(name).feed_tag(c),
+                c => {
+                    let quote = matches!(c, '"' | '\'').then_some(c);
+                    HtmlContext::Value {
+                        name,
+                        attr,
+                        quote,
+                        empty: quote.is_some(),
+                        js: JsContext::default(),
+                    }
+                }
+            },
+            HtmlContext::Value {
+                name,
+                attr,
+                quote,
+                js,
+                ..
+            } => match quote {
+                Some(q) if c == q => start_tag(name),
+                None if c.is_whitespace() || c == '>' => {
+                    start_tag(name).feed_tag(c)
+                }
+                _ => HtmlContext::Value {
+                    name,
+                    attr,
+                    quote,
+                    empty: false,
+                    js: js.feed(c),
+                },
+            },
+            state => state,
+        }
+    }
+
+    /// The context after `c` in a tag, between attributes.
+    fn feed_tag(self, c: char) -> HtmlContext {
+        let HtmlContext::Tag { name, end } = self else {
+            return self;
+        };
+        match c {
+            '>' if end => HtmlContext::Text,
+            '>' => ["script", "style"]
+                .into_iter()
+                .find(|raw| *raw == name)
+                .map_or(HtmlContext::Text, |name| HtmlContext::RawText {
+                    name,
+                    js: JsContext::default(),
+                }),
+            c if c.is_whitespace() || c == '/' || end => {
+                HtmlContext::Tag { name, end }
+            }
+            c => HtmlContext::AttrName {
+                name,
+                attr: c.to_ascii_lowercase().to_string(),
+            },
+        }
+    }
+
+    /// The escaping needed for an expression in this context.
+    ///
+    /// An unquoted attribute value can not be safely escaped, and
+    /// neither can javascript outside of a quoted string, so that is
+    /// an error.
+    pub fn escape(&self) -> Result<Escape, String> {
+        match self {
+            HtmlContext::BeforeValue { attr, .. }
+            | HtmlContext::Value {
+                attr, quote: None, ..
+            } => Err(format!(
+                "The value of attribute {attr} must be quoted to be \
+                 safely escaped"
+            )),
+            HtmlContext::Value {
+                attr, empty, js, ..
+            } => match Escape::of_attribute(attr, *empty) {
+                Escape::Js => js.escape(),
+                escape => Ok(escape),
+            },
+            HtmlContext::RawText { name: "script", js } => js.escape(),
+            HtmlContext::RawText { .. } => Ok(Escape::Css),
+            _ => Ok(Escape::Html),
+        }
+    }
+
+    /// Update the context for an expression in it.
+    pub fn feed_expression(&mut self) {
+        if let HtmlContext::Value { empty, .. } = self {
+            *empty = false;
+        }
+    }
+}
+
+fn tag_name(end: bool) -> HtmlContext {
+    HtmlContext::TagName {
+        name: String::new(),
+        end,
+    }
+}
+
+fn start_tag(name: String) -> HtmlContext {
+    HtmlContext::Tag { name, end: false }
+}
+
+/// True if `s` starts with the tag name `name`, in any case.
+pub fn starts_with_tag(s: &str, name: &str) -> bool {
+    s.get(..name.len())
+        .map_or(false, |start| start.eq_ignore_ascii_case(name))
+        && !s[name.len()..].starts_with(|c: char| c.is_ascii_alphanumeric())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Escape, HtmlContext, HtmlNesting};
+    use crate::parseresult::Pos;
+
+    fn escape_after(text: &str) -> Result<Escape, String> {
+        let mut context = HtmlContext::default();
+        context.feed(text);
+        context.escape()
+    }
+
+    #[test]
+    fn text_and_attributes() {
+        assert_eq!(escape_after("<p class=x>Hello "), Ok(Escape::Html));
+        assert_eq!(escape_after("<p title=\"a "), Ok(Escape::Html));
+        assert_eq!(escape_after("<a href='"), Ok(Escape::Url));
+        assert_eq!(escape_after("<a HREF=\"/x?q="), Ok(Escape::UrlComponent));
+        assert_eq!(escape_after("<a onclick=\"f('"), Ok(Escape::Js));
+        assert_eq!(escape_after("<a onclick='f(\"a\\\" "), Ok(Escape::Js));
+        assert_eq!(escape_after("<a style=\"color: "), Ok(Escape::Css));
+        assert_eq!(escape_after("<a href=\"x\" title=\""), Ok(Escape::Html));
+    }
+
+    #[test]
+    fn unquoted_attribute() {
+        assert_eq!(
+            escape_after("<a href="),
+            Err("The value of attribute href must be quoted to be \
+                 safely escaped"
+                .into()),
+        );
+        assert!(escape_after("<a class=x").is_err());
+    }
+
+    #[test]
+    fn script_and_style() {
+        assert_eq!(escape_after("<script>var x = \""), Ok(Escape::Js));
+        assert_eq!(escape_after("<Style>p { color: "), Ok(Escape::Css));
+        assert_eq!(
+            escape_after("<script>x = 1;</SCRIPT><p>"),
+            Ok(Escape::Html)
+        );
+        assert_eq!(escape_after("<!-- <script> -->"), Ok(Escape::Html));
+        assert_eq!(escape_after("<script>x = 'a\\'b"), Ok(Escape::Js));
+        assert_eq!(escape_after("<script>/* ' */ x = \""), Ok(Escape::Js));
+        assert_eq!(escape_after("<script>// \"\nx = '"), Ok(Escape::Js));
+    }
+
+    #[test]
+    fn unquoted_javascript() {
+        let unquoted = Err("An expression in javascript must be in a \
+                            quoted string to be safely escaped"
+            .to_string());
+        assert_eq!(escape_after("<script>var x = "), unquoted);
+        assert_eq!(escape_after("<script>x = 'a' + "), unquoted);
+        assert_eq!(escape_after("<script>x = a / "), unquoted);
+        assert_eq!(escape_after("<a onclick=\"f("), unquoted);
+        assert_eq!(
+            escape_after("<script>x = `${"),
+            Err("An expression in a javascript template literal can not \
+                 be safely escaped"
+                .into()),
+        );
+    }
+
+    fn nesting_errors(text: &str) -> Vec<String> {
+        let mut nesting = HtmlNesting::default();
+        nesting.feed(text, &Pos::of(text.as_bytes()));
+        nesting.finish().into_iter().map(|(_, msg)| msg).collect()
+    }
+
+    #[test]
+    fn nesting_ok() {
+        assert!(
+            nesting_errors("<div><p>Hi<br><img src=x /></div>").is_empty()
+        );
+        assert!(nesting_errors("<ul><li>a<li>b</ul><p>c").is_empty());
+        assert!(nesting_errors("<!-- <div> --><a>x</a>").is_empty());
+        assert!(
+            nesting_errors("<script>if (a<b) x('</p>')</script>").is_empty()
+        );
+    }
+
+    #[test]
+    fn nesting_errors_found() {
+        assert_eq!(
+            nesting_errors("<div><span>"),
+            ["Unclosed <div>", "Unclosed <span>"],
+        );
+        assert_eq!(
+            nesting_errors("<div></span>"),
+            ["Unexpected </span>, expected </div>", "Unclosed <div>",]
+        );
+        assert_eq!(nesting_errors("<div><b></div>"), ["Unclosed <b>"]);
+    }
+
+    #[test]
+    fn nesting_branches() {
+        let mut nesting = HtmlNesting::default();
+        nesting.feed("<div>", &Pos::default());
+        let branches: [fn(&mut HtmlNesting); 2] = [
+            |n| n.feed("<p>x</p>", &Pos::default()),
+            |n| n.feed("<section>", &Pos::default()),
+        ];
+        nesting.branches(branches);
+        nesting.feed("</div>", &Pos::default());
+        let errors = nesting.finish();
+        let errors = errors.iter().map(|(_, msg)| msg).collect::<Vec<_>>();
+        assert_eq!(
+            errors,
+            ["<section> is open after some branches but not after others"],
+        );
+    }
+}
@@ -111,6 +111,10 @@
 //!   version 0.3.x of the [mime] crate.
 //! * `warp03` -- Provide an extension to `Response::Builder` of the [warp]
 //!   framework (versions 0.3.x) to simplify template rendering.
+//! * `tokio1` -- Provide `Ructe::async_templates`, to also generate
+//!   an async variant of each template, `name_async`, writing to a
+//!   [tokio] (version 1.x) `AsyncWrite`.
+//!   See [`Template_syntax::i_Async_rendering`].
 //! * `http-types` -- Static files know their mime types, compatible with
 //!   the [http-types] crate.
 //! * `tide013`, `tide014`, `tide015`, `tide016` -- Support for the
@@ -124,6 +128,7 @@
 //! [mime]: https://crates.rs/crates/mime
 //! [warp]: https://crates.rs/crates/warp
 //! [tide]: https://crates.rs/crates/tide
+//! [tokio]: https://crates.rs/crates/tokio
 //! [http-types]: https://crates.rs/crates/http-types
 //!
 //! The `mime03`, and `http-types` features are mutually
@@ -142,8 +147,13 @@
 //! ```
 #![forbid(unsafe_code, missing_docs)]
 
+#[cfg(test)]
+extern crate alloc;
+
 pub mod Template_syntax;
+mod diagnostics;
 mod expression;
+mod htmlcontext;
 mod parseresult;
 mod spacelike;
 mod staticfiles;
@@ -151,14 +161,17 @@ mod template;
 mod templateexpression;
 
 use parseresult::show_errors;
+use std::collections::HashMap;
 use std::env;
 use std::error::Error;
 use std::fmt::{self, Debug, Display, Write as _};
 use std::fs::{create_dir_all, read_dir, File};
 use std::io::{self, Read};
 use std::path::{Path, PathBuf};
-use template::template;
+use template::{template, Escaping, FormalArgument, Template, Whitespace};
+use templates::ToHtml;
 
+pub use diagnostics::map_diagnostics;
 pub use staticfiles::StaticFiles;
 
 /// The main build-time interface of ructe.
@@ -212,6 +225,45 @@ pub use staticfiles::StaticFiles;
 pub struct Ructe {
     f: String,
     outdir: PathBuf,
+    options: Options,
+}
+
+/// Options for how templates are compiled.
+#[derive(Clone, Debug, Default)]
+#[expect(clippy::struct_excessive_bools, reason = "independent options")]
+struct Options {
+    whitespace: Whitespace,
+    escaping: Escaping,
+    check_html: bool,
+    fmt_write: bool,
+    template_structs: bool,
+    async_templates: bool,
+}
+
+impl Options {
+    /// Check that the options can be combined.
+    fn check(&self) -> Result<()> {
+        if self.fmt_write {
+            if self.async_templates {
+                return Err(RucteError::Options(
+                    "fmt_write can not be combined with async_templates"
+                        .into(),
+                ));
+            }
+            let features = [
+                ("warp03", cfg!(feature = "warp03")),
+                ("mime03", cfg!(feature = "mime03")),
+                ("http-types", cfg!(feature = "http-types")),
+            ];
+            if let Some((feature, _)) = features.iter().find(|f| f.1) {
+                return Err(RucteError::Options(format!(
+                    "fmt_write can not be combined with the {feature} \
+                     feature, that needs std::io"
+                )));
+            }
+        }
+        Ok(())
+    }
 }
 
 impl Ructe {
@@ -261,6 +313,13 @@ impl Ructe {
                 "/src/templates/utils.rs"
             )),
         )?;
+        write_if_changed(
+            &outdir.join("utils_common.rs"),
+            include_str!(concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/src/templates/utils_common.rs"
+            )),
+        )?;
         f.write_str(
             "#[doc(hidden)]\nmod _utils;\n\
              #[doc(inline)]\npub use self::_utils::*;\n\n",
@@ -278,7 +337,11 @@ impl Ructe {
                  #[doc(inline)]\npub use self::_utils_warp03::*;\n\n",
             )?;
         }
-        Ok(Ructe { f, outdir })
+        Ok(Ructe {
+            f,
+            outdir,
+            options: Options::default(),
+        })
     }
 
     /// Create a `templates` module in `outdir` containing rust code for
@@ -297,11 +360,19 @@ impl Ructe {
     /// backwards compatibility, but that will be removed in a future
     /// release.
     ///
+    /// Each template also gets a constant, such as
+    /// `TEMPLATE_HTML_SIZE_HINT`, with the length of its literal text.
+    /// It can be used as the initial capacity of a buffer to render
+    /// the template to.
+    /// The text of other templates called from the template is not
+    /// included.
+    ///
     /// # Errors
     ///
     /// Returns an error if in input dir can't be read, if the output can't
-    /// be written, or if there is a template that can't be converted to
-    /// rust code.
+    /// be written, if there is a template that can't be converted to
+    /// rust code, or if [`Ructe::fmt_write`] is combined with options or
+    /// features it can not be combined with.
     /// Note that while some errors in template code will result in an error
     /// from this method, other errors will return ok here but generate code
     /// that results in a compilation error.
@@ -309,7 +380,197 @@ impl Ructe {
     where
         P: AsRef<Path>,
     {
-        handle_entries(&mut self.f, indir.as_ref(), &self.outdir)
+        self.options.check()?;
+        handle_entries(
+            &mut self.f,
+            indir.as_ref(),
+            &self.outdir,
+            &self.options,
+            &mut Loaded::default(),
+        )
+    }
+
+    /// Collapse insignificant whitespace in templates compiled after
+    /// this call.
+    ///
+    /// Each run of whitespace in the text of a template is collapsed
+    /// to a single space, and whitespace that includes a line break
+    /// is removed completely between tags.
+    /// Whitespace next to an expression is kept as a single space.
+    /// Text inside `pre`, `textarea` and `script` elements, quoted
+    /// attribute values and `@raw` blocks is left as is.
+    ///
+    /// A single template can override this with a `@whitespace
+    /// preserve;` or `@whitespace collapse;` line before its
+    /// declaration.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use ructe::{Ructe, RucteError};
+    /// # fn main() -> Result<(), RucteError> {
+    /// let mut ructe = Ructe::from_env()?;
+    /// ructe.collapse_whitespace(true).compile_templates("templates")?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn collapse_whitespace(&mut self, collapse: bool) -> &mut Self {
+        self.options.whitespace = if collapse {
+            Whitespace::Collapse
+        } else {
+            Whitespace::Preserve
+        };
+        self
+    }
+
+    /// Escape expressions in templates compiled after this call
+    /// according to where in the html they are.
+    ///
+    /// By default, all expressions are escaped as html text.
+    /// With contextual escaping, an expression in a `script` element
+    /// or an `on...` attribute is escaped for a javascript string, an
+    /// expression in a `style` element or attribute is escaped for
+    /// css, and an expression in an url attribute such as `href` is
+    /// checked for a safe scheme or url encoded.
+    /// An expression as an unquoted attribute value is an error, as
+    /// it can not be escaped safely.
+    ///
+    /// A single template can override this with an `@escaping html;`
+    /// or `@escaping contextual;` line before its declaration.
+    pub fn contextual_escaping(&mut self, contextual: bool) -> &mut Self {
+        self.options.escaping = if contextual {
+            Escaping::Contextual
+        } else {
+            Escaping::Html
+        };
+        self
+    }
+
+    /// Check the nesting of html elements in `.rs.html` templates
+    /// compiled after this call.
+    ///
+    /// Mismatched end tags and unclosed elements in the text of each
+    /// template are reported as cargo warnings, with their line
+    /// numbers.
+    /// The branches of each `@if` and `@match`, and the body of each
+    /// loop, should leave the same elements open as when they start.
+    /// Elements with optional end tags, such as `li` and `p`, may be
+    /// left open.
+    pub fn check_html(&mut self, check: bool) -> &mut Self {
+        self.options.check_html = check;
+        self
+    }
+
+    /// Generate a struct for each template compiled after this call,
+    /// not only for templates with default values.
+    ///
+    /// The struct is named after the template function in CamelCase,
+    /// and has a public field for each argument of the template.
+    /// When the fields can be copied or cloned, it implements
+    /// [`ToHtml`], so it can be used as a value such as `@page` in
+    /// another template, and it has a `display` method for use with
+    /// `format!`.
+    ///
+    /// The argument types of each template must then be valid as field
+    /// types, so a lifetime that is elided in a type path, such as in
+    /// `&User` for a `User<'a>`, must be written out, as `&User<'_>`.
+    /// An `impl Trait` type is only supported as the whole type of an
+    /// argument.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use ructe::{Ructe, RucteError};
+    /// # fn main() -> Result<(), RucteError> {
+    /// let mut ructe = Ructe::from_env()?;
+    /// ructe.template_structs(true).compile_templates("templates")?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn template_structs(&mut self, structs: bool) -> &mut Self {
+        self.options.template_structs = structs;
+        self
+    }
+
+    /// Also generate an async variant of each template compiled after
+    /// this call.
+    ///
+    /// The variant of a template `name_html` is an async function
+    /// `name_html_async`, writing to a [tokio] `AsyncWrite`.
+    /// Every template called with `@:` from a template with an async
+    /// variant must also have one, so any rust function that is
+    /// called that way must have an async variant as well, or the
+    /// templates calling it must be compiled without this option.
+    /// See [`Template_syntax::i_Async_rendering`].
+    ///
+    /// [tokio]: https://crates.rs/crates/tokio
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use ructe::{Ructe, RucteError};
+    /// # fn main() -> Result<(), RucteError> {
+    /// let mut ructe = Ructe::from_env()?;
+    /// ructe.async_templates(true).compile_templates("templates")?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[cfg(feature = "tokio1")]
+    pub fn async_templates(&mut self, async_templates: bool) -> &mut Self {
+        self.options.async_templates = async_templates;
+        self
+    }
+
+    /// Generate templates that write to a `core::fmt::Write` rather
+    /// than a `std::io::Write`.
+    ///
+    /// Each template function then takes the output as a `&mut W`
+    /// where `W: core::fmt::Write`, and returns a `core::fmt::Result`.
+    /// There is also a `name_to_string` function for each template,
+    /// that renders it to a new `String`.
+    ///
+    /// The generated code (and the [`ToHtml`] trait that comes with it)
+    /// only uses `core` and `alloc`, so it can be used in a `no_std`
+    /// crate, which must have an `extern crate alloc;` declaration.
+    ///
+    /// This changes the `ToHtml` trait for the whole `templates`
+    /// module, so it should be called before any templates are
+    /// compiled.
+    /// It can not be combined with `async_templates` or with the
+    /// `warp03`, `mime03` or `http-types` features, and
+    /// [`Ructe::compile_templates`] returns an error if it is.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use ructe::{Ructe, RucteError};
+    /// # fn main() -> Result<(), RucteError> {
+    /// let mut ructe = Ructe::from_env()?;
+    /// ructe.fmt_write(true)?.compile_templates("templates")?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the output can't be written.
+    pub fn fmt_write(&mut self, fmt_write: bool) -> Result<&mut Self> {
+        const IO: &str = "mod _utils;\n";
+        const FMT: &str = "#[path = \"_utils_fmt.rs\"]\nmod _utils;\n";
+        if fmt_write {
+            write_if_changed(
+                &self.outdir.join("_utils_fmt.rs"),
+                include_str!(concat!(
+                    env!("CARGO_MANIFEST_DIR"),
+                    "/src/templates/utils_fmt.rs"
+                )),
+            )?;
+            self.f = self.f.replacen(IO, FMT, 1);
+        } else {
+            self.f = self.f.replacen(FMT, IO, 1);
+        }
+        self.options.fmt_write = fmt_write;
+        Ok(self)
     }
 
     /// Create a [`StaticFiles`] handler for this Ructe instance.
@@ -370,7 +631,22 @@ fn write_if_changed(path: &Path, content: &str) -> Result<()> {
     Ok(())
 }
 
-fn handle_entries(f: &mut String, indir: &Path, outdir: &Path) -> Result<()> {
+/// The templates loaded so far, by path, so that each template is
+/// parsed (and its errors reported) only once.
+#[derive(Default)]
+struct Loaded {
+    templates: HashMap<PathBuf, Option<Template>>,
+    /// The arguments of templates called by other templates.
+    args: HashMap<PathBuf, Option<Vec<FormalArgument>>>,
+}
+
+fn handle_entries(
+    f: &mut String,
+    indir: &Path,
+    outdir: &Path,
+    options: &Options,
+    loaded: &mut Loaded,
+) -> Result<()> {
     println!("cargo:rerun-if-changed={}", indir.display());
     for entry in read_dir(indir)? {
         let entry = entry?;
@@ -382,9 +658,9 @@ fn handle_entries(f: &mut String, indir: &Path, outdir: &Path) -> Result<()> {
                 let mut modrs = String::with_capacity(512);
                 modrs.push_str(
                     "#[allow(clippy::useless_attribute, unused)]\n\
-                     use super::{Html,ToHtml};\n",
+                     use super::{Html,ToHtml,_utils};\n",
                 );
-                handle_entries(&mut modrs, &path, &outdir)?;
+                handle_entries(&mut modrs, &path, &outdir, options, loaded)?;
                 write_if_changed(&outdir.join("mod.rs"), &modrs)?;
                 writeln!(f, "pub mod {filename};\n")?;
             }
@@ -394,14 +670,53 @@ fn handle_entries(f: &mut String, indir: &Path, outdir: &Path) -> Result<()> {
                     println!("cargo:rerun-if-changed={}", path.display());
                     let name =
                         format!("{prename}_{}", &suffix[".rs.".len()..]);
-                    if handle_template(&name, &path, outdir)? {
+                    if let Some(t) = handle_template(
+                        &name, &path, outdir, options, loaded,
+                    )? {
+                        let cfg = t.cfg_attributes();
+                        let uses = t.use_attributes();
                         writeln!(
                             f,
-                            "#[doc(hidden)]\n\
+                            "{cfg}#[doc(hidden)]\n\
                              mod template_{name};\n\
-                             #[doc(inline)]\n\
+                             {uses}#[doc(inline)]\n\
                              pub use self::template_{name}::{name};\n",
                         )?;
+                        if options.fmt_write {
+                            writeln!(
+                                f,
+                                "{uses}#[doc(inline)]\n\
+                                 pub use self::template_{name}::{name}_to_string;\n",
+                            )?;
+                        } else if options.async_templates {
+                            writeln!(
+                                f,
+                                "{uses}#[doc(inline)]\n\
+                                 pub use self::template_{name}::{name}_async;\n",
+                            )?;
+                        }
+                        writeln!(
+                            f,
+                            "{uses}#[doc(inline)]\n\
+                             pub use self::template_{name}::{};\n",
+                            Template::size_hint_name(&name),
+                        )?;
+                        let defaults = t.default_names(&name);
+                        if !defaults.is_empty() {
+                            writeln!(
+                                f,
+                                "{uses}#[doc(hidden)]\n\
+                                 pub use self::template_{name}::{{{}}};\n",
+                                defaults.join(", "),
+                            )?;
+                        }
+                        if let Some(props) = t.props_name(&name) {
+                            writeln!(
+                                f,
+                                "{uses}#[doc(inline)]\n\
+                                 pub use self::template_{name}::{props};\n",
+                            )?;
+                        }
                     }
                 }
             }
@@ -410,26 +725,190 @@ fn handle_entries(f: &mut String, indir: &Path, outdir: &Path) -> Result<()> {
     Ok(())
 }
 
-fn handle_template(name: &str, path: &Path, outdir: &Path) -> Result<bool> {
+fn handle_template(
+    name: &str,
+    path: &Path,
+    outdir: &Path,
+    options: &Options,
+    loaded: &mut Loaded,
+) -> Result<Option<Template>> {
+    let seen = &mut vec![path.to_path_buf()];
+    let Some(mut t) = load_template(path, seen, options, loaded)? else {
+        return Ok(None);
+    };
+    t.coalesce_text();
+    let mut data = String::new();
+    if options.fmt_write {
+        t.write_fmt_rust(&mut data, name)?;
+    } else {
+        t.write_rust(&mut data, name)?;
+        if options.async_templates {
+            t.write_async_rust(&mut data, name)?;
+        }
+    }
+    write_if_changed(&outdir.join(format!("template_{name}.rs")), &data)?;
+    if let Some(msg) = t.props_warning(name) {
+        println!("cargo:warning=In {path:?}: {msg}");
+    }
+    Ok(Some(t))
+}
+
+/// The template in `path`, with its `@extends` resolved, if any.
+///
+/// The template is parsed with [`read_template`] the first time,
+/// and then taken from `loaded`.
+fn load_template(
+    path: &Path,
+    seen: &mut Vec<PathBuf>,
+    options: &Options,
+    loaded: &mut Loaded,
+) -> Result<Option<Template>> {
+    if let Some(t) = loaded.templates.get(path) {
+        return Ok(t.clone());
+    }
+    let t = read_template(path, seen, options, loaded)?;
+    loaded.templates.insert(path.to_path_buf(), t.clone());
+    Ok(t)
+}
+
+/// Parse the template in `path`, and resolve its `@extends`, if any.
+///
+/// The `seen` paths are the extending templates that lead here, to
+/// detect cycles.
+/// Any error in the template is reported as a cargo warning, and
+/// `None` is returned.
+fn read_template(
+    path: &Path,
+    seen: &mut Vec<PathBuf>,
+    options: &Options,
+    loaded: &mut Loaded,
+) -> Result<Option<Template>> {
     let mut input = File::open(path)?;
     let mut buf = Vec::new();
     input.read_to_end(&mut buf)?;
-    match template(&buf) {
-        Ok((_, t)) => {
-            let mut data = String::new();
-            t.write_rust(&mut data, name)?;
-            write_if_changed(
-                &outdir.join(format!("template_{name}.rs")),
-                &data,
-            )?;
-            Ok(true)
-        }
+    let mut t = match template(&buf) {
+        Ok((_, t)) => t,
         Err(error) => {
             println!("cargo:warning=Template parse error in {path:?}:");
             show_errors(&mut io::stdout(), &buf, &error, "cargo:warning=");
-            Ok(false)
+            return Ok(None);
+        }
+    };
+    t.locate(&path.display().to_string(), &buf);
+    if options.check_html && path.to_string_lossy().ends_with(".rs.html") {
+        for (pos, msg) in t.check_nesting() {
+            let (line, col) = pos.line_col(&buf);
+            println!(
+                "cargo:warning=Html error in {path:?}, line {line}:{col}: {msg}"
+            );
+        }
+    }
+    if t.whitespace().unwrap_or(options.whitespace) == Whitespace::Collapse {
+        t.collapse_whitespace();
+    }
+    if options.template_structs {
+        t.generate_struct();
+    }
+    let contextual =
+        t.escaping().unwrap_or(options.escaping) == Escaping::Contextual;
+    if let Err(msg) = t
+        .check_loops()
+        .and_then(|()| {
+            t.resolve_includes(|name, escaped| {
+                include_file(&path.with_file_name(name), escaped)
+            })
+        })
+        .and_then(|()| t.escape_by_context(contextual))
+        .and_then(|()| {
+            t.resolve_calls(|name| {
+                let file = template_file(path, name)?;
+                loaded
+                    .args
+                    .entry(file)
+                    .or_insert_with_key(|file| template_args(file))
+                    .clone()
+            })
+        })
+    {
+        println!("cargo:warning=Error in {path:?}: {msg}");
+        return Ok(None);
+    }
+    let Some(extends) = t.extends() else {
+        return Ok(Some(t));
+    };
+    let parent_name = t.resolve_path(&extends.name, |name| {
+        template_file(path, name).is_some()
+    });
+    let Some(parent_path) = template_file(path, &parent_name) else {
+        println!(
+            "cargo:warning=Error in {path:?}: Cannot find template {} to extend",
+            extends.name,
+        );
+        return Ok(None);
+    };
+    if seen.contains(&parent_path) {
+        println!(
+            "cargo:warning=Error in {path:?}: Cyclic @extends of {parent_path:?}",
+        );
+        return Ok(None);
+    }
+    seen.push(parent_path.clone());
+    let Some(parent) = load_template(&parent_path, seen, options, loaded)?
+    else {
+        return Ok(None);
+    };
+    if let Err(msg) = t
+        .escape_blocks(&parent, contextual)
+        .and_then(|()| t.extend(&parent_name, parent))
+    {
+        println!("cargo:warning=Error in {path:?}: {msg}");
+        return Ok(None);
+    }
+    Ok(Some(t))
+}
+
+/// The contents of a file included by a template, escaped as html if
+/// `escaped` is true.
+fn include_file(path: &Path, escaped: bool) -> Result<String, String> {
+    println!("cargo:rerun-if-changed={}", path.display());
+    let error = |e: io::Error| format!("Cannot include {path:?}: {e}");
+    let text = std::fs::read_to_string(path).map_err(error)?;
+    if escaped {
+        let mut buf = Vec::new();
+        text.to_html(&mut buf).map_err(error)?;
+        Ok(String::from_utf8_lossy(&buf).into_owned())
+    } else {
+        Ok(text)
+    }
+}
+
+/// The arguments of the template in `path`, if it can be parsed.
+fn template_args(path: &Path) -> Option<Vec<FormalArgument>> {
+    let buf = std::fs::read(path).ok()?;
+    let (_, t) = template(&buf).ok()?;
+    Some(t.into_args())
+}
+
+/// Find the file for the template `name`, as seen from the template
+/// in `path`.
+///
+/// The name is a rust path from the module of the template, so both
+/// `base_html` and `super::base_html` are in the same directory,
+/// while `super::super::base_html` is in the parent directory.
+fn template_file(path: &Path, name: &str) -> Option<PathBuf> {
+    let mut dir = path.parent()?.to_path_buf();
+    let name = name.strip_prefix("super::").unwrap_or(name);
+    let (module, name) = name.rsplit_once("::").unwrap_or(("", name));
+    for part in module.split("::").filter(|p| !p.is_empty()) {
+        if part != "super" {
+            dir.push(part);
+        } else if !dir.pop() {
+            return None;
         }
     }
+    let (prename, ext) = name.rsplit_once('_')?;
+    let file = dir.join(format!("{prename}.rs.{ext}"));
+    file.is_file().then_some(file)
 }
 
 pub mod templates;
@@ -446,6 +925,8 @@ pub enum RucteError {
     Env(String, env::VarError),
     /// A build-time formatting error in Ructe
     Fmt(fmt::Error),
+    /// Options that can not be combined.
+    Options(String),
     #[cfg(feature = "sass")]
     /// Error bundling a sass stylesheet as css.
     Sass(rsass::Error),
@@ -457,6 +938,7 @@ impl Error for RucteError {
             Self::Io(e) => Some(e),
             Self::Env(_, e) => Some(e),
             Self::Fmt(e) => Some(e),
+            Self::Options(_) => None,
             #[cfg(feature = "sass")]
             RucteError::Sass(e) => Some(e),
         }
@@ -474,6 +956,7 @@ impl Debug for RucteError {
             RucteError::Io(err) => Display::fmt(err, out),
             RucteError::Env(var, err) => write!(out, "{var:?}: {err}"),
             Self::Fmt(err) => Display::fmt(err, out),
+            Self::Options(msg) => out.write_str(msg),
             #[cfg(feature = "sass")]
             RucteError::Sass(err) => Debug::fmt(err, out),
         }
@@ -500,3 +983,46 @@ impl From<rsass::Error> for RucteError {
 
 /// A result where the error type is a [`RucteError`].
 pub type Result<T, E = RucteError> = std::result::Result<T, E>;
+
+#[cfg(test)]
+mod test {
+    use super::Options;
+
+    #[test]
+    fn fmt_write_with_async_templates() {
+        let options = Options {
+            fmt_write: true,
+            async_templates: true,
+            ..Options::default()
+        };
+        assert_eq!(
+            options.check().map_err(|e| e.to_string()),
+            Err("Error: fmt_write can not be combined with async_templates"
+                .into()),
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "mime03")]
+    fn fmt_write_with_mime03() {
+        let options = Options {
+            fmt_write: true,
+            ..Options::default()
+        };
+        assert_eq!(
+            options.check().map_err(|e| e.to_string()),
+            Err("Error: fmt_write can not be combined with the mime03 \
+                 feature, that needs std::io"
+                .into()),
+        );
+    }
+
+    #[test]
+    fn async_templates_without_fmt_write() {
+        let options = Options {
+            async_templates: true,
+            ..Options::default()
+        };
+        assert!(options.check().is_ok());
+    }
+}
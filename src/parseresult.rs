@@ -1,11 +1,102 @@
-use nom::{Err, IResult};
+use nom::combinator::{map, rest_len};
+use nom::{Err, IResult, Parser as _};
 use nom_language::error::{VerboseError, VerboseErrorKind};
+use std::fmt;
 use std::io::Write;
+use std::rc::Rc;
 use std::str::from_utf8;
 
 /// Parser result, with verbose error.
 pub type PResult<'a, O> = IResult<&'a [u8], O, VerboseError<&'a [u8]>>;
 
+/// A position in the source of a template.
+///
+/// The position is stored as the length of the source from there to
+/// the end, so it can be found while parsing, where only the rest of
+/// the source is known.
+/// When the template is loaded, the position can be located in its
+/// file, to mark the code generated from it.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Pos {
+    rest: usize,
+    location: Option<(Rc<str>, usize, usize)>,
+}
+
+impl Pos {
+    /// The position where `rest` starts.
+    pub fn of(rest: &[u8]) -> Pos {
+        Pos {
+            rest: rest.len(),
+            location: None,
+        }
+    }
+
+    /// The position `n` bytes after this.
+    pub fn add(&self, n: usize) -> Pos {
+        Pos {
+            rest: self.rest.saturating_sub(n),
+            location: None,
+        }
+    }
+
+    /// The line and column number of this position in `buf`.
+    pub fn line_col(&self, buf: &[u8]) -> (usize, usize) {
+        let pos = buf.len().saturating_sub(self.rest);
+        let line_start = buf[..pos]
+            .iter()
+            .rposition(|c| *c == b'\n')
+            .map_or(0, |i| i + 1);
+        let line_no = bytecount::count(&buf[..line_start], b'\n') + 1;
+        let col = from_utf8(&buf[line_start..pos])
+            .map_or(pos - line_start, |s| s.chars().count());
+        (line_no, col + 1)
+    }
+
+    /// Locate this position in `buf`, the source of the template
+    /// file `path`.
+    pub fn locate(&mut self, path: &Rc<str>, buf: &[u8]) {
+        let (line, col) = self.line_col(buf);
+        self.location = Some((path.clone(), line, col));
+    }
+
+    /// Write a comment marking the code generated from this position,
+    /// if it is located.
+    pub fn write_marker(&self, out: &mut impl fmt::Write) -> fmt::Result {
+        match &self.location {
+            Some((path, line, col)) => {
+                writeln!(out, "{MARKER}{path}:{line}:{col}")
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Write a comment marking the end of the code generated from
+    /// this position and the positions after it, if it is located.
+    pub fn write_end_marker(&self, out: &mut impl fmt::Write) -> fmt::Result {
+        match &self.location {
+            Some(_) => writeln!(out, "{MARKER}{END}"),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The position where the input is, without consuming any of it.
+pub fn position(input: &[u8]) -> PResult<'_, Pos> {
+    map(rest_len, |rest| Pos {
+        rest,
+        location: None,
+    })
+    .parse(input)
+}
+
+/// The start of a comment marking generated code with its position
+/// in a template file.
+pub const MARKER: &str = "// ructe: ";
+
+/// The location in a marker for the end of code generated from a
+/// template, such as the props struct after the template function.
+pub const END: &str = "end";
+
 pub fn show_errors(
     out: &mut impl Write,
     buf: &[u8],
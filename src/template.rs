@@ -1,139 +1,1789 @@
-use crate::expression::{input_to_str, rust_name};
-use crate::parseresult::PResult;
+use crate::expression::{expr_in_brackets, input_to_str, rust_name};
+use crate::htmlcontext::{HtmlContext, HtmlNesting};
+use crate::parseresult::{position, PResult, Pos};
 use crate::spacelike::spacelike;
-use crate::templateexpression::{template_expression, TemplateExpression};
+use crate::templateexpression::{
+    block2, block_names, check_loops, check_nesting, coalesce_text,
+    collapse_whitespace, escape_by_context, locate, logic_expression,
+    override_blocks, rebase_calls, size_hint, template_argument,
+    template_block, template_expression, Flavor, TemplateArgument,
+    TemplateExpression,
+};
 use nom::branch::alt;
 use nom::bytes::complete::is_not;
 use nom::bytes::complete::tag;
-use nom::character::complete::{char, multispace0};
-use nom::combinator::{map, map_res, opt, recognize, value};
+use nom::character::complete::{char, multispace0, multispace1};
+use nom::combinator::{cut, map, map_res, not, opt, recognize, value};
 use nom::error::context;
 use nom::multi::{many0, many_till, separated_list0, separated_list1};
-use nom::sequence::{delimited, preceded, terminated};
+use nom::sequence::{delimited, pair, preceded, terminated};
 use nom::Parser as _;
-use std::fmt::Write;
+use std::fmt::{self, Write};
+use std::rc::Rc;
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Template {
+    doc: Vec<String>,
+    attributes: Vec<String>,
     preamble: Vec<String>,
     type_args: String,
-    args: Vec<String>,
+    where_clause: Option<String>,
+    args: Vec<FormalArgument>,
+    extends: Option<Extends>,
     body: Vec<TemplateExpression>,
+    helpers: Vec<Helper>,
+    whitespace: Option<Whitespace>,
+    escaping: Option<Escaping>,
+    value_struct: bool,
+    /// The html context at the start of each `@block`, including the
+    /// blocks of the parent, if any.
+    blocks: Vec<(String, HtmlContext)>,
+    /// The position of the declaration.
+    pos: Pos,
+}
+
+/// How whitespace in the text of a template is handled.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Whitespace {
+    /// Write all text as is.
+    #[default]
+    Preserve,
+    /// Collapse whitespace between tags, except inside `pre`,
+    /// `textarea` and `script` elements.
+    Collapse,
+}
+
+/// How expressions in a template are escaped.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Escaping {
+    /// Escape all expressions as html text.
+    #[default]
+    Html,
+    /// Escape each expression according to where in the html it is,
+    /// e.g. in a `script` element or an url attribute.
+    Contextual,
+}
+
+/// A line in the preamble of a template.
+enum PreambleItem {
+    Line(String),
+    Attribute(String),
+    Whitespace(Whitespace),
+    Escaping(Escaping),
+}
+
+/// A `@fn` helper function defined in a template.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Helper {
+    name: String,
+    type_args: String,
+    where_clause: Option<String>,
+    args: Vec<FormalArgument>,
+    body: Vec<TemplateExpression>,
+    pos: Pos,
+}
+
+/// Something at the top level of a template body.
+enum BodyItem {
+    Expression(TemplateExpression),
+    Helper(Helper),
+}
+
+/// An argument in the declaration of a template.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FormalArgument {
+    pub doc: Vec<String>,
+    pub name: String,
+    pub ty: String,
+    pub default: Option<String>,
+    pub pos: Pos,
+}
+
+/// The `@extends` declaration of a template.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Extends {
+    pub name: String,
+    args: Vec<TemplateArgument>,
 }
 
 impl Template {
+    /// The template this template extends, if any.
+    pub fn extends(&self) -> Option<&Extends> {
+        self.extends.as_ref()
+    }
+
+    /// The arguments of this template.
+    pub fn into_args(self) -> Vec<FormalArgument> {
+        self.args
+    }
+
+    /// The `#[cfg(...)]` attributes of this template, one per line.
+    ///
+    /// These apply to the module of the template as well as to the
+    /// template function.
+    pub fn cfg_attributes(&self) -> String {
+        let mut result = String::new();
+        for attribute in &self.attributes {
+            if attribute.starts_with("#[cfg(") {
+                result.push_str(attribute);
+                result.push('\n');
+            }
+        }
+        result
+    }
+
+    /// The attributes for re-exports of this template, one per line.
+    ///
+    /// A re-export of a deprecated template is allowed, so that only
+    /// actual uses of it are warned about.
+    pub fn use_attributes(&self) -> String {
+        let mut attributes = self.cfg_attributes();
+        if self.is_deprecated() {
+            attributes.push_str("#[allow(deprecated)]\n");
+        }
+        attributes
+    }
+
+    fn is_deprecated(&self) -> bool {
+        self.attributes
+            .iter()
+            .any(|a| a.starts_with("#[deprecated"))
+    }
+
+    /// The whitespace mode declared in this template, if any.
+    pub fn whitespace(&self) -> Option<Whitespace> {
+        self.whitespace
+    }
+
+    /// Collapse insignificant whitespace in the text of this template.
+    pub fn collapse_whitespace(&mut self) {
+        let mut raw = None;
+        let mut context = HtmlContext::default();
+        for body in self.bodies_mut() {
+            collapse_whitespace(body, &mut raw, &mut context);
+        }
+    }
+
+    /// Locate the expressions of this template in `buf`, the source
+    /// of the template file `path`, to mark the generated code with
+    /// their positions.
+    pub fn locate(&mut self, path: &str, buf: &[u8]) {
+        let path = Rc::from(path);
+        for body in self.bodies_mut() {
+            locate(body, &path, buf);
+        }
+        let heads = std::iter::once((&mut self.pos, &mut self.args)).chain(
+            self.helpers.iter_mut().map(|h| (&mut h.pos, &mut h.args)),
+        );
+        for (pos, args) in heads {
+            pos.locate(&path, buf);
+            for arg in args {
+                arg.pos.locate(&path, buf);
+            }
+        }
+    }
+
+    /// Merge adjacent text in this template, and remove comments, so
+    /// that each run of static markup is written at once.
+    ///
+    /// This should be done after all other changes to the template.
+    pub fn coalesce_text(&mut self) {
+        for body in self.bodies_mut() {
+            coalesce_text(body);
+        }
+    }
+
+    /// Check the nesting of html elements in this template.
+    ///
+    /// Returns the problems found, with their positions.
+    pub fn check_nesting(&self) -> Vec<(Pos, String)> {
+        let mut nesting = HtmlNesting::default();
+        check_nesting(&self.body, &mut nesting);
+        for helper in &self.helpers {
+            nesting.apart(|nesting| check_nesting(&helper.body, nesting));
+        }
+        nesting.finish()
+    }
+
+    /// Check that each `@break` and `@continue` in this template is
+    /// inside a loop.
+    pub fn check_loops(&self) -> Result<(), String> {
+        check_loops(&self.body, &[])?;
+        for helper in &self.helpers {
+            check_loops(&helper.body, &[])?;
+        }
+        Ok(())
+    }
+
+    /// The escaping mode declared in this template, if any.
+    pub fn escaping(&self) -> Option<Escaping> {
+        self.escaping
+    }
+
+    /// Escape each expression in this template according to where in
+    /// the html it is, if `escape` is true, and find the html context
+    /// at the start of each `@block`.
+    ///
+    /// The blocks of a template that extends another are done by
+    /// [`escape_blocks`](Self::escape_blocks) instead, since they
+    /// start where the blocks of the parent do.
+    pub fn escape_by_context(&mut self, escape: bool) -> Result<(), String> {
+        if self.extends.is_none() {
+            let context = &mut HtmlContext::default();
+            escape_by_context(
+                &mut self.body,
+                context,
+                escape,
+                &mut self.blocks,
+            )?;
+        }
+        for helper in &mut self.helpers {
+            let context = &mut HtmlContext::default();
+            escape_by_context(
+                &mut helper.body,
+                context,
+                escape,
+                &mut Vec::new(),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Escape the blocks of this template, that override blocks of
+    /// `parent`, each starting from the html context of the block it
+    /// overrides.
+    pub fn escape_blocks(
+        &mut self,
+        parent: &Template,
+        escape: bool,
+    ) -> Result<(), String> {
+        self.blocks.clone_from(&parent.blocks);
+        for e in &mut self.body {
+            if let TemplateExpression::Block { name, body, .. } = e {
+                let mut context = parent
+                    .blocks
+                    .iter()
+                    .find(|(n, _)| n == name)
+                    .map(|(_, context)| context.clone())
+                    .unwrap_or_default();
+                escape_by_context(
+                    body,
+                    &mut context,
+                    escape,
+                    &mut self.blocks,
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Replace the included files in this template with their
+    /// contents, as given by `read`.
+    pub fn resolve_includes(
+        &mut self,
+        mut read: impl FnMut(&str, bool) -> Result<String, String>,
+    ) -> Result<(), String> {
+        for body in self.bodies_mut() {
+            for e in body {
+                e.resolve_includes(&mut read)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Put named arguments and trailing blocks of calls in this
+    /// template in place.
+    ///
+    /// The `params` function gives the arguments of the called
+    /// template, given its path as seen from the module of this
+    /// template, or `None` if there is no template at that path
+    /// (see [`Template::resolve_path`]).
+    pub fn resolve_calls(
+        &mut self,
+        mut params: impl FnMut(&str) -> Option<Vec<FormalArgument>>,
+    ) -> Result<(), String> {
+        let preamble = &self.preamble;
+        let helpers = self
+            .helpers
+            .iter()
+            .map(|h| (h.name.clone(), h.args.clone()))
+            .collect::<Vec<_>>();
+        let mut params = |name: &str| {
+            helpers
+                .iter()
+                .find(|(helper, _)| helper == name)
+                .map(|(_, args)| args.clone())
+                .or_else(|| {
+                    let (path, mut args) =
+                        resolve_paths(preamble, name).into_iter().find_map(
+                            |path| Some((path.clone(), params(&path)?)),
+                        )?;
+                    for arg in &mut args {
+                        if arg.default.is_some() {
+                            arg.default =
+                                Some(default_call(&path, &arg.name));
+                        }
+                    }
+                    Some(args)
+                })
+        };
+        for body in std::iter::once(&mut self.body)
+            .chain(self.helpers.iter_mut().map(|h| &mut h.body))
+        {
+            for e in body {
+                e.resolve_calls(&mut params)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The body of this template and the bodies of its helpers.
+    fn bodies_mut(
+        &mut self,
+    ) -> impl Iterator<Item = &mut Vec<TemplateExpression>> {
+        std::iter::once(&mut self.body)
+            .chain(self.helpers.iter_mut().map(|h| &mut h.body))
+    }
+
+    /// The path of `name` as seen from the module of this template.
+    ///
+    /// The first part of `name` is looked up among the `@use`
+    /// statements of the template.
+    /// A glob `@use` only gives the path if `is_template` tells that
+    /// there is a template at it.
+    /// If it is not found there, the name is a template in the
+    /// same directory.
+    pub fn resolve_path(
+        &self,
+        name: &str,
+        is_template: impl FnMut(&String) -> bool,
+    ) -> String {
+        let mut paths = resolve_paths(&self.preamble, name);
+        let same_dir = paths.pop().unwrap_or_default();
+        paths.into_iter().find(is_template).unwrap_or(same_dir)
+    }
+
+    /// Resolve the `@extends` declaration of this template.
+    ///
+    /// The `parent` must be the template named in the declaration,
+    /// found at `path` (see [`Template::resolve_path`]), with its own
+    /// `@extends` already resolved.
+    /// The body of this template (which contains only `@block`
+    /// definitions) overrides the blocks of the parent.
+    /// Paths in the `@use` statements of the parent and in its
+    /// template calls are made relative to the module of this
+    /// template.
+    pub fn extend(
+        &mut self,
+        path: &str,
+        mut parent: Template,
+    ) -> Result<(), String> {
+        let Some(Extends { name, mut args }) = self.extends.take() else {
+            return Ok(());
+        };
+        args.extend(parent.args.iter().skip(args.len()).map_while(|arg| {
+            arg.default.as_ref().map(|_| {
+                TemplateArgument::Rust(default_call(path, &arg.name))
+            })
+        }));
+        if args.len() != parent.args.len() {
+            return Err(format!(
+                "{name} takes {} arguments, but {} were given",
+                parent.args.len(),
+                args.len(),
+            ));
+        }
+        let blocks = std::mem::take(&mut self.body)
+            .into_iter()
+            .filter_map(|e| match e {
+                TemplateExpression::Block { name, body, .. } => {
+                    Some((name, body))
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let names =
+            blocks.iter().map(|(n, _)| n.as_str()).collect::<Vec<_>>();
+        let mut known = Vec::new();
+        block_names(&parent.body, &mut known);
+        for (i, block) in names.iter().enumerate() {
+            if names[..i].contains(block) {
+                return Err(format!("Block {block} is defined twice"));
+            }
+            if !known.contains(block) {
+                return Err(format!("{name} has no block named {block}"));
+            }
+        }
+        override_blocks(&mut parent.body, &names);
+        // Types that cannot be named outside of the parent, as they
+        // use its generic parameters or lifetimes or are `impl`
+        // types, are inferred from the values.
+        let generics = split_top_level(&parent.type_args)
+            .into_iter()
+            .map(param_name)
+            .collect::<Vec<_>>();
+        for arg in &mut parent.args {
+            if arg.ty != "Content"
+                && type_words(&arg.ty)
+                    .any(|word| word == "impl" || generics.contains(&word))
+            {
+                arg.ty = "_".into();
+            }
+        }
+        let module = path.rsplit_once("::").map_or("", |(m, _)| m);
+        let rebase = |path: &str| rebase_path(module, path);
+        rebase_calls(&mut parent.body, &rebase);
+        for helper in &mut parent.helpers {
+            rebase_calls(&mut helper.body, &rebase);
+        }
+        for line in parent.preamble {
+            let line = match line.trim().strip_prefix("use ") {
+                Some(tree) => format!("use {}", rebase(tree.trim())),
+                None => line,
+            };
+            if !self.preamble.contains(&line) {
+                self.preamble.push(line);
+            }
+        }
+        for helper in parent.helpers {
+            if self.helpers.iter().any(|h| h.name == helper.name) {
+                return Err(format!(
+                    "Helper {} is defined in both {name} and its parent",
+                    helper.name,
+                ));
+            }
+            self.helpers.push(helper);
+        }
+        self.body = vec![TemplateExpression::Parent {
+            blocks,
+            args: parent.args.into_iter().zip(args).collect(),
+            body: parent.body,
+            pos: Pos::default(),
+        }];
+        Ok(())
+    }
+
     pub fn write_rust(
         &self,
         out: &mut impl Write,
         name: &str,
     ) -> std::fmt::Result {
+        self.write_module(out, name, Flavor::Sync)
+    }
+
+    /// Write this template as a function writing to a
+    /// `core::fmt::Write`, and a `{name}_to_string` function.
+    ///
+    /// This is written instead of the output of
+    /// [`write_rust`](Self::write_rust).
+    pub fn write_fmt_rust(
+        &self,
+        out: &mut impl Write,
+        name: &str,
+    ) -> fmt::Result {
+        self.write_module(out, name, Flavor::Fmt)?;
+        self.write_to_string(out, name)
+    }
+
+    fn write_module(
+        &self,
+        out: &mut impl Write,
+        name: &str,
+        flavor: Flavor,
+    ) -> fmt::Result {
+        out.write_str(match flavor {
+            Flavor::Fmt => "use core::fmt::{self, Write};\n",
+            _ => "use std::io::{self, Write};\n",
+        })?;
         out.write_str(
-            "use std::io::{self, Write};\n\
-             #[allow(clippy::useless_attribute, unused)]\n\
-             use super::{Html,ToHtml};\n",
+            "#[allow(clippy::useless_attribute, unused)]\n\
+             use super::{Html,ToHtml,_utils};\n",
         )?;
         for line in &self.preamble {
+            // Glob imports, such as of static files, are common in
+            // templates.
+            if line.trim().starts_with("use ") && line.contains('*') {
+                out.write_str("#[allow(clippy::wildcard_imports)]\n")?;
+            }
             writeln!(out, "{line};")?;
         }
+        let mut head = String::new();
+        self.write_doc(&mut head)?;
+        for attribute in &self.attributes {
+            writeln!(head, "{attribute}")?;
+        }
+        write!(head, "pub fn {name}")?;
+        write_fn(
+            out,
+            &head,
+            &self.type_args,
+            self.where_clause.as_deref(),
+            &self.args,
+            &self.body,
+            &self.pos,
+            flavor,
+        )?;
+        for helper in &self.helpers {
+            write_fn(
+                out,
+                &format!("fn {}", helper.name),
+                &helper.type_args,
+                helper.where_clause.as_deref(),
+                &helper.args,
+                &helper.body,
+                &helper.pos,
+                flavor,
+            )?;
+        }
         writeln!(
             out,
-            "\n\
-             #[allow(clippy::used_underscore_binding, \
-             clippy::too_many_lines)]\n\
-             pub fn {name}<{ta}{ta_sep}W>(\
-             \n  #[allow(unused_mut)] mut _ructe_out_: W,",
-            name = name,
-            ta = self.type_args,
-            ta_sep = if self.type_args.is_empty() { "" } else { ", " },
+            "\n/// The length of the literal text of [`{name}`], as a hint \
+             for the\n/// capacity of a buffer to render it to.\n\
+             pub const {}: usize = {};",
+            Template::size_hint_name(name),
+            size_hint(&self.body),
         )?;
+        self.write_defaults(out, name)?;
+        self.write_props(out, name, flavor)
+    }
+
+    /// The names of the functions for the default values of the
+    /// arguments of this template.
+    pub fn default_names(&self, name: &str) -> Vec<String> {
+        self.args
+            .iter()
+            .filter(|arg| arg.default.is_some())
+            .map(|arg| format!("{name}_default_{}", arg.name))
+            .collect()
+    }
+
+    /// Write a function for the default value of each argument that
+    /// has one, so that a call that leaves the argument out gets the
+    /// value as evaluated in the module of this template.
+    ///
+    /// Each function gets the lifetimes and type parameters of the
+    /// template that its type needs.
+    fn write_defaults(
+        &self,
+        out: &mut impl Write,
+        name: &str,
+    ) -> fmt::Result {
+        let params = split_top_level(&self.type_args);
+        let bounds = self
+            .where_clause
+            .as_deref()
+            .map(split_top_level)
+            .unwrap_or_default();
         for arg in &self.args {
+            let Some(default) = &arg.default else {
+                continue;
+            };
+            let ty = explicit_lifetimes(&arg.ty);
+            let used = |p: &&str| type_words(&ty).any(|w| w == param_name(p));
+            let params = ty
+                .contains("'ructe")
+                .then_some("'ructe")
+                .into_iter()
+                .chain(params.iter().copied().filter(used))
+                .collect::<Vec<_>>();
+            let bounds =
+                bounds.iter().copied().filter(used).collect::<Vec<_>>();
+            out.write_str("\n")?;
+            arg.pos.write_marker(out)?;
             writeln!(
                 out,
-                "  {},",
-                arg.replace(
-                    " Content",
-                    " impl FnOnce(&mut W) -> io::Result<()>"
-                )
+                "#[doc(hidden)]\n\
+                 #[must_use]\n\
+                 pub fn {name}_default_{arg}{params}() -> {ty}{bounds} {{\n\
+                 {default}\n\
+                 }}",
+                arg = arg.name,
+                params = if params.is_empty() {
+                    String::new()
+                } else {
+                    format!("<{}>", params.join(", "))
+                },
+                bounds = if bounds.is_empty() {
+                    String::new()
+                } else {
+                    format!("\nwhere {}", bounds.join(", "))
+                },
             )?;
+            arg.pos.write_end_marker(out)?;
         }
+        Ok(())
+    }
+
+    /// The name of the size hint constant for a template.
+    pub fn size_hint_name(name: &str) -> String {
+        format!("{}_SIZE_HINT", name.to_uppercase())
+    }
+
+    /// Write a `{name}_to_string` function, rendering the template
+    /// to a new `String`.
+    fn write_to_string(
+        &self,
+        out: &mut impl Write,
+        name: &str,
+    ) -> fmt::Result {
         writeln!(
             out,
-            ") -> io::Result<()>\n\
-             where W: Write {{",
+            "\n/// Render [`{name}`] to a new `String`.\n\
+             ///\n\
+             /// # Panics\n\
+             ///\n\
+             /// If a `ToHtml` implementation returns an error.\n\
+             #[allow(clippy::used_underscore_binding)]",
         )?;
-        for b in &self.body {
-            b.write_code(out)?;
+        for attribute in &self.attributes {
+            writeln!(out, "{attribute}")?;
+        }
+        if self.is_deprecated() {
+            out.write_str("#[allow(deprecated)]\n")?;
+        }
+        write!(out, "#[must_use]\npub fn {name}_to_string")?;
+        if !self.type_args.is_empty() {
+            write!(out, "<{}>", self.type_args)?;
+        }
+        out.write_str("(\n")?;
+        for arg in &self.args {
+            if arg.ty == "Content" {
+                writeln!(
+                    out,
+                    "  {}: impl FnOnce(&mut alloc::string::String) \
+                     -> fmt::Result,",
+                    arg.name,
+                )?;
+            } else {
+                writeln!(out, "  {}: {},", arg.name, arg.ty)?;
+            }
+        }
+        out.write_str(") -> alloc::string::String\n")?;
+        if let Some(where_clause) = &self.where_clause {
+            writeln!(out, "where {where_clause}")?;
+        }
+        write!(
+            out,
+            "{{\nlet mut _ructe_out_ = \
+             alloc::string::String::with_capacity({});\n\
+             {name}(&mut _ructe_out_",
+            Template::size_hint_name(name),
+        )?;
+        for arg in &self.args {
+            write!(out, ", {}", arg.name)?;
+        }
+        out.write_str(
+            ")\n.expect(\"a ToHtml implementation returned an error \
+             unexpectedly\");\n\
+             _ructe_out_\n}\n",
+        )
+    }
+
+    /// Write an async variant of this template, `{name}_async`,
+    /// writing to a `tokio::io::AsyncWrite`.
+    ///
+    /// This is written to the same module as the output of
+    /// [`write_rust`](Self::write_rust).
+    pub fn write_async_rust(
+        &self,
+        out: &mut impl Write,
+        name: &str,
+    ) -> fmt::Result {
+        for line in &self.preamble {
+            if let Some(tree) = line.trim().strip_prefix("use ") {
+                if let Some(tree) = async_use(tree.trim()) {
+                    writeln!(out, "#[allow(unused_imports)]\nuse {tree};")?;
+                }
+            }
+        }
+        let mut head = String::new();
+        self.write_doc(&mut head)?;
+        for attribute in &self.attributes {
+            writeln!(head, "{attribute}")?;
+        }
+        write!(head, "pub async fn {name}_async")?;
+        write_fn(
+            out,
+            &head,
+            &self.type_args,
+            self.where_clause.as_deref(),
+            &self.args,
+            &self.body,
+            &self.pos,
+            Flavor::Async(&[]),
+        )?;
+        for helper in &self.helpers {
+            write_fn(
+                out,
+                &format!("async fn {}_async", helper.name),
+                &helper.type_args,
+                helper.where_clause.as_deref(),
+                &helper.args,
+                &helper.body,
+                &helper.pos,
+                Flavor::Async(&[]),
+            )?;
         }
-        writeln!(out, "Ok(())\n}}")?;
         Ok(())
     }
+
+    /// Write the doc comment of this template, with the docs of its
+    /// arguments, as rustdoc.
+    fn write_doc(&self, out: &mut impl Write) -> fmt::Result {
+        for line in &self.doc {
+            write_doc_line(out, line)?;
+        }
+        if self.args.iter().all(|arg| arg.doc.is_empty()) {
+            return Ok(());
+        }
+        if !self.doc.is_empty() {
+            out.write_str("///\n")?;
+        }
+        out.write_str("/// # Arguments\n///\n")?;
+        for arg in self.args.iter().filter(|arg| !arg.doc.is_empty()) {
+            for (i, line) in arg.doc.iter().enumerate() {
+                if i == 0 {
+                    write_doc_line(
+                        out,
+                        &format!("* `{}`: {line}", arg.name),
+                    )?;
+                } else {
+                    write_doc_line(out, format!("  {line}").trim_end())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The name of the props struct for this template, if it has one.
+    ///
+    /// A props struct is generated for templates that have default
+    /// values for some arguments, and for all templates when
+    /// [`generate_struct`](Self::generate_struct) is called.
+    pub fn props_name(&self, name: &str) -> Option<String> {
+        if self.value_struct || self.args.iter().any(|a| a.default.is_some())
+        {
+            Some(name.split('_').map(capitalize).collect())
+        } else {
+            None
+        }
+    }
+
+    /// Generate a props struct for this template, even if it has no
+    /// default values.
+    pub fn generate_struct(&mut self) {
+        self.value_struct = true;
+    }
+
+    fn write_props(
+        &self,
+        out: &mut impl Write,
+        name: &str,
+        flavor: Flavor,
+    ) -> fmt::Result {
+        let Some(props) = self.props_name(name) else {
+            return Ok(());
+        };
+        let (params, names, bounds, fields) = self.props_types(flavor);
+        writeln!(
+            out,
+            "\n/// The arguments of [`{name}`].\n\
+             ///\n\
+             /// Create it with `new`, giving all required arguments, and \
+             change any\n\
+             /// optional arguments before calling `render`.\n\
+             pub struct {props}{params} {{",
+        )?;
+        for (arg, ty) in self.args.iter().zip(&fields) {
+            for line in &arg.doc {
+                write_doc_line(out, line)?;
+            }
+            writeln!(out, "  pub {}: {ty},", arg.name)?;
+        }
+        writeln!(
+            out,
+            "}}\n\n{ALLOW_LIFETIMES}\nimpl{params} {props}{names}"
+        )?;
+        if !bounds.is_empty() {
+            writeln!(out, "where {}", bounds.join(", "))?;
+        }
+        writeln!(
+            out,
+            "{{\n\
+             #[allow(clippy::too_many_arguments)]\n\
+             #[must_use]\n\
+             pub fn new(",
+        )?;
+        let required = self.args.iter().zip(&fields);
+        for (arg, ty) in required.filter(|(a, _)| a.default.is_none()) {
+            writeln!(out, "  {}: {ty},", arg.name)?;
+        }
+        writeln!(out, ") -> Self {{\n{props} {{")?;
+        for arg in &self.args {
+            if arg.default.is_some() {
+                writeln!(out, "  {0}: {name}_default_{0}(),", arg.name)?;
+            } else {
+                writeln!(out, "  {},", arg.name)?;
+            }
+        }
+        out.write_str(
+            "}\n}\n\n\
+             /// Render the template with these arguments.\n\
+             ///\n\
+             /// # Errors\n\
+             ///\n\
+             /// Any error writing to `out` is returned.\n",
+        )?;
+        if self.is_deprecated() {
+            out.write_str("#[allow(deprecated)]\n")?;
+        }
+        writeln!(
+            out,
+            "pub fn render<W: Write>(self, out: {}) -> {} {{",
+            if let Flavor::Fmt = flavor {
+                "&mut W"
+            } else {
+                "W"
+            },
+            flavor.result(),
+        )?;
+        out.write_str("let Self {")?;
+        for arg in &self.args {
+            write!(out, " {},", arg.name)?;
+        }
+        write!(out, " }} = self;\n{name}(out")?;
+        for arg in &self.args {
+            if arg.ty == "Content" {
+                write!(out, ", |o: &mut W| {}(o)", arg.name)?;
+            } else {
+                write!(out, ", {}", arg.name)?;
+            }
+        }
+        writeln!(out, ")\n}}\n}}")?;
+        if self.args.iter().all(|arg| arg.default.is_some()) {
+            let where_clause = self
+                .where_clause
+                .as_ref()
+                .map(|w| format!("\nwhere {w}"))
+                .unwrap_or_default();
+            writeln!(
+                out,
+                "\n{ALLOW_LIFETIMES}\n\
+                 impl{params} Default for {props}{names}{where_clause} {{\n\
+                 fn default() -> Self {{\n\
+                 Self::new()\n\
+                 }}\n\
+                 }}",
+            )?;
+        }
+        self.write_to_html(out, name, flavor)
+    }
+
+    /// Implement `ToHtml` for the props struct, and a `display` method
+    /// to format it.
+    ///
+    /// The arguments are given from `&self`, so each must be copied
+    /// or cloned, and a `Content` must be `Fn`.
+    /// If the type of an argument is not known to be `Copy` or
+    /// `Clone`, no impl is written (see
+    /// [`props_warning`](Self::props_warning)).
+    fn write_to_html(
+        &self,
+        out: &mut impl Write,
+        name: &str,
+        flavor: Flavor,
+    ) -> fmt::Result {
+        let Some(props) = self.props_name(name) else {
+            return Ok(());
+        };
+        let (params, names, mut bounds, fields) = self.props_types(flavor);
+        let Ok(values) = self.props_values(&fields, &mut bounds, flavor)
+        else {
+            return Ok(());
+        };
+        let where_clause = if bounds.is_empty() {
+            String::new()
+        } else {
+            format!("\nwhere {}", bounds.join(", "))
+        };
+        let allow_deprecated = if self.is_deprecated() {
+            "#[allow(deprecated)]\n"
+        } else {
+            ""
+        };
+        let (out_param, out_arg) = if let Flavor::Fmt = flavor {
+            ("mut out", "&mut out")
+        } else {
+            ("out", "out")
+        };
+        writeln!(
+            out,
+            "\n{ALLOW_LIFETIMES}\n{allow_deprecated}\
+             impl{params} ToHtml for {props}{names}{where_clause} {{\n\
+             fn to_html(&self, {out_param}: &mut dyn Write) -> {result} {{\n\
+             {name}({out_arg}{sep}{values})\n\
+             }}\n\
+             }}\n\n\
+             {ALLOW_LIFETIMES}\n\
+             impl{params} {props}{names}{where_clause} {{\n\
+             /// Display the rendered template, e.g. with `format!`.\n\
+             #[must_use]\n\
+             pub fn display(&self) -> _utils::HtmlDisplay<'_, Self> {{\n\
+             _utils::HtmlDisplay(self)\n\
+             }}\n\
+             }}",
+            result = flavor.result(),
+            sep = if values.is_empty() { "" } else { ", " },
+            values = values.join(", "),
+        )
+    }
+
+    /// A warning that the props struct of this template does not
+    /// implement `ToHtml`, if it has a props struct that does not.
+    pub fn props_warning(&self, name: &str) -> Option<String> {
+        let props = self.props_name(name)?;
+        let (_, _, mut bounds, fields) = self.props_types(Flavor::Sync);
+        let arg = self
+            .props_values(&fields, &mut bounds, Flavor::Sync)
+            .err()?;
+        Some(format!(
+            "{props} does not implement ToHtml, since the type {} of {} \
+             is not known to be Copy or Clone",
+            arg.ty, arg.name,
+        ))
+    }
+
+    /// The arguments to the template function from the `&self` of its
+    /// props struct, with the `fields` types, adding any needed
+    /// `bounds`.
+    ///
+    /// A type that uses a generic parameter is bounded to be `Clone`.
+    /// The first argument that can not be copied or cloned is the
+    /// error.
+    fn props_values(
+        &self,
+        fields: &[String],
+        bounds: &mut Vec<String>,
+        flavor: Flavor,
+    ) -> Result<Vec<String>, &FormalArgument> {
+        let generics = split_top_level(&self.type_args)
+            .into_iter()
+            .filter(|p| !p.starts_with('\''))
+            .map(param_name)
+            .collect::<Vec<_>>();
+        let mut values = Vec::new();
+        for (arg, ty) in self.args.iter().zip(fields) {
+            if arg.ty == "Content" {
+                bounds.push(format!(
+                    "{ty}: Fn(&mut dyn Write) -> {}",
+                    flavor.result(),
+                ));
+                values.push(format!("|o: &mut _| (self.{})(o)", arg.name));
+            } else if is_copy(ty) {
+                values.push(format!("self.{}", arg.name));
+            } else if arg.ty.starts_with("impl ")
+                || type_words(ty).any(|word| generics.contains(&word))
+            {
+                bounds.push(format!("{ty}: Clone"));
+                values.push(format!("self.{}.clone()", arg.name));
+            } else if ty == "String" {
+                values.push(format!("self.{}.clone()", arg.name));
+            } else {
+                return Err(arg);
+            }
+        }
+        Ok(values)
+    }
+
+    /// The generic parameters as declared and as used, their bounds,
+    /// and the field types of the props struct.
+    ///
+    /// Any elided lifetimes are made explicit, and `impl Trait` and
+    /// `Content` arguments get a type parameter each.
+    /// A `Content` field takes a `&mut dyn Write`, since the type of
+    /// the output is not known until the props are rendered.
+    fn props_types(
+        &self,
+        flavor: Flavor,
+    ) -> (String, String, Vec<String>, Vec<String>) {
+        let mut types = Vec::new();
+        let mut bounds = Vec::new();
+        let fields = self
+            .args
+            .iter()
+            .map(|arg| {
+                let generic = if arg.ty == "Content" {
+                    Some(format!(
+                        "FnOnce(&mut dyn Write) -> {}",
+                        flavor.result(),
+                    ))
+                } else {
+                    arg.ty.strip_prefix("impl ").map(String::from)
+                };
+                if let Some(bound) = generic {
+                    let param = format!("{}T", capitalize(&arg.name));
+                    bounds.push(format!("{param}: {bound}"));
+                    types.push(param.clone());
+                    param
+                } else {
+                    explicit_lifetimes(&arg.ty)
+                }
+            })
+            .collect::<Vec<_>>();
+        let ructe_lifetime = fields
+            .iter()
+            .any(|ty| ty.contains("'ructe"))
+            .then_some("'ructe");
+        let (lifetimes, params): (Vec<_>, Vec<_>) =
+            split_top_level(&self.type_args)
+                .into_iter()
+                .partition(|p| p.starts_with('\''));
+        let params = lifetimes
+            .into_iter()
+            .chain(ructe_lifetime)
+            .chain(params)
+            .map(String::from)
+            .chain(types)
+            .collect::<Vec<_>>();
+        let names = params.iter().map(|p| param_name(p)).collect::<Vec<_>>();
+        bounds.extend(self.where_clause.clone());
+        if params.is_empty() {
+            (String::new(), String::new(), bounds, fields)
+        } else {
+            (
+                format!("<{}>", params.join(", ")),
+                format!("<{}>", names.join(", ")),
+                bounds,
+                fields,
+            )
+        }
+    }
+}
+
+/// True if `ty` is known to be `Copy`, such as a shared reference or
+/// a number.
+fn is_copy(ty: &str) -> bool {
+    const PRIMITIVES: &[&str] = &[
+        "bool", "char", "f32", "f64", "i8", "i16", "i32", "i64", "i128",
+        "isize", "u8", "u16", "u32", "u64", "u128", "usize",
+    ];
+    let ty = ty.trim();
+    if let Some(inner) = ty.strip_prefix("Option<") {
+        inner.strip_suffix('>').map_or(false, is_copy)
+    } else if let Some(inner) = ty.strip_prefix('(') {
+        inner.strip_suffix(')').map_or(false, |inner| {
+            split_top_level(inner).into_iter().all(is_copy)
+        })
+    } else if let Some(referent) = ty.strip_prefix('&') {
+        let referent = match referent.strip_prefix('\'') {
+            Some(lifetime) => lifetime.split_once(' ').map_or("", |(_, r)| r),
+            None => referent,
+        };
+        !referent.trim_start().starts_with("mut ")
+    } else {
+        PRIMITIVES.contains(&ty)
+    }
+}
+
+/// Write a line of rustdoc.
+fn write_doc_line(out: &mut impl Write, line: &str) -> fmt::Result {
+    if line.is_empty() {
+        out.write_str("///\n")
+    } else {
+        writeln!(out, "/// {line}")
+    }
+}
+
+/// The name of a generic parameter declaration, such as `T` for
+/// `T: Display` or `N` for `const N: usize`.
+fn param_name(param: &str) -> &str {
+    let param = param.strip_prefix("const ").unwrap_or(param);
+    param.split_once(':').map_or(param, |(name, _)| name).trim()
+}
+
+/// Write a template function (or a `@fn` helper).
+///
+/// The `head` is the start of the function declaration, up to the
+/// generic parameters.
+/// An async function takes its output by value and returns it, and
+/// its content closures return boxed futures that may borrow for the
+/// `'ructe` lifetime.
+///
+/// The function is marked with the position `pos` of its declaration
+/// and the positions of its arguments, and followed by an end marker.
+#[allow(clippy::too_many_arguments)]
+fn write_fn(
+    out: &mut impl Write,
+    head: &str,
+    type_args: &str,
+    where_clause: Option<&str>,
+    args: &[FormalArgument],
+    body: &[TemplateExpression],
+    pos: &Pos,
+    flavor: Flavor,
+) -> fmt::Result {
+    let content = args
+        .iter()
+        .filter(|arg| arg.ty == "Content")
+        .map(|arg| arg.name.clone())
+        .collect::<Vec<_>>();
+    let flavor = match flavor {
+        Flavor::Async(_) => Flavor::Async(&content),
+        flavor => flavor,
+    };
+    let ructe = matches!(flavor, Flavor::Async(_)) && !content.is_empty();
+    out.write_str("\n")?;
+    pos.write_marker(out)?;
+    writeln!(
+        out,
+        "#[allow(clippy::used_underscore_binding, \
+         clippy::too_many_lines, clippy::uninlined_format_args)]\n\
+         {head}<{lt}{type_args}{ta_sep}W>(\n  {out_arg},",
+        lt = if ructe { "'ructe, " } else { "" },
+        ta_sep = if type_args.is_empty() { "" } else { ", " },
+        out_arg = match flavor {
+            Flavor::Fmt => "_ructe_out_: &mut W",
+            _ => "#[allow(unused_mut)] mut _ructe_out_: W",
+        },
+    )?;
+    for arg in args {
+        arg.pos.write_marker(out)?;
+        writeln!(
+            out,
+            "  {}: {},",
+            arg.name,
+            match flavor {
+                _ if arg.ty != "Content" => &arg.ty,
+                Flavor::Sync => "impl FnOnce(&mut W) -> io::Result<()>",
+                Flavor::Fmt => "impl FnOnce(&mut W) -> fmt::Result",
+                Flavor::Async(_) => {
+                    "impl FnOnce(W) -> std::pin::Pin<Box<dyn \
+                     std::future::Future<Output = io::Result<W>> \
+                     + Send + 'ructe>> + Send"
+                }
+            },
+        )?;
+    }
+    pos.write_marker(out)?;
+    writeln!(
+        out,
+        ") -> {}\n\
+         where W: {}{}{} {{",
+        flavor.result(),
+        match flavor {
+            Flavor::Sync | Flavor::Fmt => "Write",
+            Flavor::Async(_) if ructe => {
+                "tokio::io::AsyncWrite + Unpin + Send + 'ructe"
+            }
+            Flavor::Async(_) => "tokio::io::AsyncWrite + Unpin + Send",
+        },
+        if where_clause.is_some() { ", " } else { "" },
+        where_clause.unwrap_or_default(),
+    )?;
+    if let Flavor::Async(_) = flavor {
+        out.write_str(
+            "#[allow(unused_imports)]\n\
+             use tokio::io::AsyncWriteExt as _;\n",
+        )?;
+    }
+    for b in body {
+        b.write_code(out, flavor)?;
+    }
+    if let Flavor::Async(_) = flavor {
+        writeln!(out, "Ok(_ructe_out_)\n}}")?;
+    } else {
+        writeln!(out, "Ok(())\n}}")?;
+    }
+    pos.write_end_marker(out)
+}
+
+/// Split a list of generic parameters or arguments at the commas
+/// that are not nested in brackets.
+fn split_top_level(list: &str) -> Vec<&str> {
+    let mut result = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    let mut prev = ' ';
+    for (i, c) in list.char_indices() {
+        match c {
+            '<' | '(' | '[' | '{' => depth += 1,
+            '>' if prev == '-' => {}
+            '>' | ')' | ']' | '}' => depth -= 1,
+            ',' if depth == 0 => {
+                result.push(list[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+        prev = c;
+    }
+    result.push(list[start..].trim());
+    result.retain(|p| !p.is_empty());
+    result
+}
+
+/// The `'ructe` lifetime of a props struct may be elidable in impls.
+const ALLOW_LIFETIMES: &str = "#[allow(unknown_lints, \
+     clippy::needless_lifetimes, clippy::elidable_lifetime_names)]";
+
+fn capitalize(word: &str) -> String {
+    let mut chars = word.chars();
+    chars
+        .next()
+        .map(|c| c.to_uppercase().chain(chars).collect())
+        .unwrap_or_default()
+}
+
+/// Make any elided reference lifetimes in `ty` explicit as `'ructe`.
+fn explicit_lifetimes(ty: &str) -> String {
+    let mut result = String::with_capacity(ty.len());
+    let mut rest = ty;
+    while let Some(i) = rest.find(['&', '\'']) {
+        result.push_str(&rest[..i]);
+        rest = &rest[i..];
+        if let Some(tail) = rest.strip_prefix("'_") {
+            result.push_str("'ructe");
+            rest = tail;
+        } else if let Some(tail) = rest.strip_prefix('&') {
+            result.push('&');
+            if !tail.trim_start().starts_with('\'') {
+                result.push_str("'ructe ");
+            }
+            rest = tail;
+        } else {
+            result.push('\'');
+            rest = &rest[1..];
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+/// The paths that `name` may have as seen from a template with the
+/// `@use` statements in `preamble`, best first.
+///
+/// A name that is used by name has just that path.
+/// Otherwise, the path through each glob `use` may be the right
+/// one, and the last path is the one in the same directory.
+fn resolve_paths(preamble: &[String], name: &str) -> Vec<String> {
+    let (first, rest) = name.split_once("::").unwrap_or((name, ""));
+    if first == "super" || first == "crate" {
+        return vec![name.to_string()];
+    }
+    let uses = preamble
+        .iter()
+        .filter_map(|line| line.trim().strip_prefix("use "))
+        .map(str::trim)
+        .collect::<Vec<_>>();
+    let with_rest = |path: String| match rest {
+        "" => path,
+        rest => format!("{path}::{rest}"),
+    };
+    if let Some(path) = uses.iter().find_map(|tree| use_path("", tree, first))
+    {
+        return vec![with_rest(path)];
+    }
+    uses.iter()
+        .filter_map(|tree| glob_path("", tree, first))
+        .map(with_rest)
+        .chain(std::iter::once(format!("super::{name}")))
+        .collect()
+}
+
+/// A call of the function for the default value of the argument
+/// `arg` of the template at `path`.
+fn default_call(path: &str, arg: &str) -> String {
+    format!("{path}_default_{arg}()")
+}
+
+/// The words of a type, such as `'a`, `impl` and `T` in
+/// `&'a impl Into<T>`.
+fn type_words(ty: &str) -> impl Iterator<Item = &str> {
+    ty.split(|c: char| !(c.is_alphanumeric() || c == '_' || c == '\''))
+        .filter(|word| !word.is_empty())
+}
+
+/// The path `path`, relative to the module `module`, as seen from
+/// the module that `module` is relative to.
+///
+/// Only paths starting with `super::` are relative, others are
+/// returned as they are.
+fn rebase_path(module: &str, path: &str) -> String {
+    let Some(mut rest) = path.strip_prefix("super::") else {
+        return path.to_string();
+    };
+    let mut parts = module.split("::").collect::<Vec<_>>();
+    while let Some(tail) = rest.strip_prefix("super::") {
+        match parts.last() {
+            Some(&last) if last != "super" => {
+                parts.pop();
+            }
+            _ => parts.push("super"),
+        }
+        rest = tail;
+    }
+    if parts.is_empty() {
+        rest.to_string()
+    } else {
+        format!("{}::{rest}", parts.join("::"))
+    }
+}
+
+/// The `use` of the async variants of the templates in `tree`, if any.
+///
+/// Glob imports already include the async variants.
+fn async_use(tree: &str) -> Option<String> {
+    let is_template = |path: &str| {
+        let name = path.rsplit("::").next().unwrap_or(path).trim();
+        ["_html", "_svg", "_xml"].iter().any(|s| name.ends_with(s))
+    };
+    if let Some((base, group)) = tree.split_once("::{") {
+        let items = split_top_level(group.strip_suffix('}')?)
+            .into_iter()
+            .filter_map(async_use)
+            .collect::<Vec<_>>();
+        (!items.is_empty())
+            .then(|| format!("{base}::{{{}}}", items.join(", ")))
+    } else if let Some((path, alias)) = tree.split_once(" as ") {
+        is_template(path).then(|| {
+            format!("{}_async as {}_async", path.trim(), alias.trim())
+        })
+    } else {
+        (!tree.ends_with('*') && is_template(tree))
+            .then(|| format!("{tree}_async"))
+    }
+}
+
+/// Find the path that a `use` of `tree` gives to `name` by name,
+/// if any.
+fn use_path(prefix: &str, tree: &str, name: &str) -> Option<String> {
+    if let Some((base, group)) = tree.split_once("::{") {
+        let prefix = format!("{prefix}{base}::");
+        let group = group.strip_suffix('}')?;
+        split_top_level(group)
+            .into_iter()
+            .find_map(|item| use_path(&prefix, item, name))
+    } else if let Some((path, alias)) = tree.split_once(" as ") {
+        (alias.trim() == name).then(|| format!("{prefix}{}", path.trim()))
+    } else {
+        (tree.rsplit("::").next() == Some(name))
+            .then(|| format!("{prefix}{tree}"))
+    }
+}
+
+/// Find the path that a glob in a `use` of `tree` would give to
+/// `name`, if `tree` has a glob.
+fn glob_path(prefix: &str, tree: &str, name: &str) -> Option<String> {
+    if let Some((base, group)) = tree.split_once("::{") {
+        let prefix = format!("{prefix}{base}::");
+        let group = group.strip_suffix('}')?;
+        split_top_level(group)
+            .into_iter()
+            .find_map(|item| glob_path(&prefix, item, name))
+    } else {
+        let base = tree.strip_suffix("::*")?;
+        Some(format!("{prefix}{base}::{name}"))
+    }
 }
 
 pub fn template(input: &[u8]) -> PResult<'_, Template> {
     map(
         (
-            spacelike,
-            many0(map(
-                delimited(
-                    tag("@"),
-                    map_res(is_not(";()"), input_to_str),
-                    terminated(tag(";"), spacelike),
+            preceded(multispace0, terminated(opt(doc_comment), spacelike)),
+            many0(alt((
+                map(whitespace_mode, PreambleItem::Whitespace),
+                map(escaping_mode, PreambleItem::Escaping),
+                map(
+                    delimited(tag("@#"), expr_in_brackets, spacelike),
+                    |attr| PreambleItem::Attribute(format!("#{attr}")),
                 ),
-                String::from,
-            )),
-            context("expected '@('...')' template declaration.", tag("@")),
-            opt(delimited(
-                terminated(tag("<"), multispace0),
+                map(
+                    delimited(
+                        tag("@"),
+                        map_res(is_not(";()"), input_to_str),
+                        terminated(tag(";"), spacelike),
+                    ),
+                    |line| PreambleItem::Line(line.into()),
+                ),
+            ))),
+            terminated(
+                position,
                 context(
-                    "expected type argument or '>'",
-                    map_res(
-                        recognize(separated_list1(
-                            terminated(tag(","), multispace0),
-                            context(
-                                "expected lifetime declaration",
-                                preceded(tag("'"), rust_name),
+                    "expected '@('...')' template declaration.",
+                    tag("@"),
+                ),
+            ),
+            opt(generic_params),
+            formal_arguments,
+            opt(terminated(where_clause, spacelike)),
+            alt((
+                map(pair(extends, cut(extending_body)), |(e, body)| {
+                    (Some(e), body)
+                }),
+                map(
+                    many_till(
+                        alt((
+                            map(helper, BodyItem::Helper),
+                            map(
+                                context(
+                                    "Error in expression starting here:",
+                                    template_expression,
+                                ),
+                                BodyItem::Expression,
                             ),
                         )),
-                        input_to_str,
+                        end_of_file,
                     ),
+                    |(body, ())| (None, body),
                 ),
-                tag(">"),
             )),
+        ),
+        |(
+            doc,
+            preamble_items,
+            pos,
+            type_args,
+            args,
+            where_clause,
+            (extends, items),
+        )| {
+            let mut preamble = Vec::new();
+            let mut attributes = Vec::new();
+            let mut whitespace = None;
+            let mut escaping = None;
+            for item in preamble_items {
+                match item {
+                    PreambleItem::Line(line) => preamble.push(line),
+                    PreambleItem::Attribute(a) => attributes.push(a),
+                    PreambleItem::Whitespace(w) => whitespace = Some(w),
+                    PreambleItem::Escaping(e) => escaping = Some(e),
+                }
+            }
+            let mut body = Vec::new();
+            let mut helpers = Vec::new();
+            for item in items {
+                match item {
+                    BodyItem::Expression(e) => body.push(e),
+                    BodyItem::Helper(h) => helpers.push(h),
+                }
+            }
+            Template {
+                doc: doc.unwrap_or_default(),
+                attributes,
+                preamble,
+                type_args: type_args.map(String::from).unwrap_or_default(),
+                where_clause: where_clause.map(String::from),
+                args,
+                extends,
+                body,
+                helpers,
+                whitespace,
+                escaping,
+                value_struct: false,
+                blocks: Vec::new(),
+                pos,
+            }
+        },
+    )
+    .parse(input)
+}
+
+/// A `@** ... **@` doc comment, as lines of rustdoc.
+fn doc_comment(input: &[u8]) -> PResult<'_, Vec<String>> {
+    map(
+        delimited(
+            tag("@**"),
+            map_res(
+                recognize(many0(alt((
+                    value((), is_not("*")),
+                    value(
+                        (),
+                        terminated(
+                            char('*'),
+                            not(alt((tag("*@"), tag("@")))),
+                        ),
+                    ),
+                )))),
+                input_to_str,
+            ),
+            tag("**@"),
+        ),
+        doc_lines,
+    )
+    .parse(input)
+}
+
+/// The lines of a doc comment, without their common indentation
+/// and any blank lines at the start and end.
+fn doc_lines(text: &str) -> Vec<String> {
+    let indent = text
+        .lines()
+        .skip(1)
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.len() - line.trim_start().len())
+        .min()
+        .unwrap_or(0);
+    let mut lines = text
+        .lines()
+        .enumerate()
+        .map(|(i, line)| match line.get(indent..) {
+            Some(line) if i > 0 => line.trim_end().to_string(),
+            _ => line.trim().to_string(),
+        })
+        .skip_while(String::is_empty)
+        .collect::<Vec<_>>();
+    while lines.last().map_or(false, String::is_empty) {
+        lines.pop();
+    }
+    lines
+}
+
+/// A `/// ...` doc line for a template argument.
+fn doc_line(input: &[u8]) -> PResult<'_, String> {
+    map(
+        delimited(
+            tag("///"),
+            map_res(recognize(opt(is_not("\n"))), input_to_str),
+            multispace0,
+        ),
+        |line| {
+            let line = line.trim_end();
+            line.strip_prefix(' ').unwrap_or(line).to_string()
+        },
+    )
+    .parse(input)
+}
+
+fn escaping_mode(input: &[u8]) -> PResult<'_, Escaping> {
+    preceded(
+        tag("@escaping "),
+        cut(context(
+            "Expected escaping mode contextual or html",
             delimited(
-                context(
-                    "expected '('...')' template arguments declaration.",
-                    terminated(tag("("), multispace0),
-                ),
-                separated_list0(
+                multispace0,
+                alt((
+                    value(Escaping::Contextual, tag("contextual")),
+                    value(Escaping::Html, tag("html")),
+                )),
+                (multispace0, tag(";"), spacelike),
+            ),
+        )),
+    )
+    .parse(input)
+}
+
+fn whitespace_mode(input: &[u8]) -> PResult<'_, Whitespace> {
+    preceded(
+        tag("@whitespace "),
+        cut(context(
+            "Expected whitespace mode collapse or preserve",
+            delimited(
+                multispace0,
+                alt((
+                    value(Whitespace::Collapse, tag("collapse")),
+                    value(Whitespace::Preserve, tag("preserve")),
+                )),
+                (multispace0, tag(";"), spacelike),
+            ),
+        )),
+    )
+    .parse(input)
+}
+
+fn generic_params(input: &[u8]) -> PResult<'_, &str> {
+    delimited(
+        terminated(tag("<"), multispace0),
+        context(
+            "expected type argument or '>'",
+            map_res(
+                recognize(separated_list1(
                     terminated(tag(","), multispace0),
-                    context(
-                        "expected formal argument",
-                        map(formal_argument, String::from),
-                    ),
-                ),
-                context(
-                    "expected ',' or ')'.",
-                    delimited(multispace0, tag(")"), spacelike),
-                ),
+                    context("expected generic parameter", generic_param),
+                )),
+                input_to_str,
             ),
-            many_till(
-                context(
-                    "Error in expression starting here:",
-                    template_expression,
+        ),
+        preceded(multispace0, tag(">")),
+    )
+    .parse(input)
+}
+
+fn formal_arguments(input: &[u8]) -> PResult<'_, Vec<FormalArgument>> {
+    delimited(
+        context(
+            "expected '('...')' template arguments declaration.",
+            terminated(tag("("), multispace0),
+        ),
+        separated_list0(
+            terminated(tag(","), multispace0),
+            context("expected formal argument", formal_argument),
+        ),
+        context(
+            "expected ',' or ')'.",
+            delimited(multispace0, tag(")"), spacelike),
+        ),
+    )
+    .parse(input)
+}
+
+fn helper(input: &[u8]) -> PResult<'_, Helper> {
+    map(
+        pair(
+            terminated(position, tag("@fn ")),
+            cut(context(
+                "Error in helper function:",
+                (
+                    preceded(multispace0, rust_name),
+                    opt(generic_params),
+                    formal_arguments,
+                    opt(terminated(where_clause, spacelike)),
+                    terminated(template_block, spacelike),
                 ),
-                end_of_file,
-            ),
+            )),
         ),
-        |((), preamble, _, type_args, args, body)| Template {
-            preamble,
+        |(pos, (name, type_args, args, where_clause, body))| Helper {
+            name: name.into(),
             type_args: type_args.map(String::from).unwrap_or_default(),
+            where_clause: where_clause.map(String::from),
             args,
-            body: body.0,
+            body,
+            pos,
         },
     )
     .parse(input)
 }
 
+/// A lifetime, type or const parameter, with any bounds.
+fn generic_param(input: &[u8]) -> PResult<'_, ()> {
+    alt((
+        value(
+            (),
+            (
+                tag("const "),
+                spacelike,
+                rust_name,
+                delimited(spacelike, char(':'), spacelike),
+                type_expression,
+            ),
+        ),
+        value(
+            (),
+            pair(
+                alt((lifetime, value((), rust_name))),
+                opt(preceded(
+                    delimited(spacelike, char(':'), spacelike),
+                    bounds,
+                )),
+            ),
+        ),
+    ))
+    .parse(input)
+}
+
+/// Trait and lifetime bounds, separated by `+`.
+fn bounds(input: &[u8]) -> PResult<'_, ()> {
+    value(
+        (),
+        separated_list1(
+            delimited(spacelike, char('+'), spacelike),
+            alt((lifetime, preceded(opt(char('?')), type_expression))),
+        ),
+    )
+    .parse(input)
+}
+
+fn where_clause(input: &[u8]) -> PResult<'_, &str> {
+    preceded(
+        terminated(tag("where"), multispace1),
+        map_res(
+            recognize(terminated(
+                separated_list1(
+                    terminated(char(','), spacelike),
+                    (
+                        alt((lifetime, type_expression)),
+                        delimited(spacelike, char(':'), spacelike),
+                        bounds,
+                    ),
+                ),
+                opt(char(',')),
+            )),
+            input_to_str,
+        ),
+    )
+    .parse(input)
+}
+
+fn extends(input: &[u8]) -> PResult<'_, Extends> {
+    preceded(
+        tag("@extends "),
+        cut(context(
+            "Error in extends declaration:",
+            map(
+                pair(
+                    delimited(
+                        spacelike,
+                        context(
+                            "Expected template name",
+                            map_res(
+                                recognize(separated_list1(
+                                    tag("::"),
+                                    rust_name,
+                                )),
+                                input_to_str,
+                            ),
+                        ),
+                        spacelike,
+                    ),
+                    delimited(
+                        char('('),
+                        separated_list0(
+                            terminated(tag(","), spacelike),
+                            template_argument,
+                        ),
+                        terminated(
+                            context("expected ',' or ')'.", char(')')),
+                            spacelike,
+                        ),
+                    ),
+                ),
+                |(name, args)| Extends {
+                    name: name.to_string(),
+                    args,
+                },
+            ),
+        )),
+    )
+    .parse(input)
+}
+
+/// The body of a template that extends another may only contain
+/// `@block` definitions, whitespace and comments.
+fn extending_body(input: &[u8]) -> PResult<'_, Vec<BodyItem>> {
+    map(
+        many_till(
+            alt((
+                map(helper, BodyItem::Helper),
+                map(
+                    terminated(
+                        preceded(
+                            context(
+                                "Expected @block after @extends",
+                                tag("@block "),
+                            ),
+                            block2,
+                        ),
+                        spacelike,
+                    ),
+                    BodyItem::Expression,
+                ),
+            )),
+            end_of_file,
+        ),
+        |(items, ())| items,
+    )
+    .parse(input)
+}
+
 fn end_of_file(input: &[u8]) -> PResult<'_, ()> {
     if input.is_empty() {
         Ok((input, ()))
@@ -145,21 +1795,42 @@ fn end_of_file(input: &[u8]) -> PResult<'_, ()> {
     }
 }
 
-fn formal_argument(input: &[u8]) -> PResult<'_, &str> {
-    map_res(
-        recognize((
-            rust_name,
-            spacelike,
-            char(':'),
-            spacelike,
-            type_expression,
-        )),
-        input_to_str,
+fn formal_argument(input: &[u8]) -> PResult<'_, FormalArgument> {
+    let (input, (doc, pos, name, _, ty)) = (
+        many0(doc_line),
+        position,
+        rust_name,
+        delimited(spacelike, char(':'), spacelike),
+        map_res(recognize(type_expression), input_to_str),
     )
-    .parse(input)
+        .parse(input)?;
+    let default = preceded(
+        delimited(spacelike, char('='), spacelike),
+        context("Expected default value", logic_expression),
+    );
+    let (input, default) = if ty == "Content" || ty.starts_with("impl ") {
+        let (input, ()) = cut(context(
+            "A Content or impl argument cannot have a default value",
+            not(default),
+        ))
+        .parse(input)?;
+        (input, None)
+    } else {
+        opt(default).parse(input)?
+    };
+    Ok((
+        input,
+        FormalArgument {
+            doc,
+            name: name.to_string(),
+            ty: ty.to_string(),
+            default: default.map(String::from),
+            pos,
+        },
+    ))
 }
 
-fn type_expression(input: &[u8]) -> PResult<'_, ()> {
+pub fn type_expression(input: &[u8]) -> PResult<'_, ()> {
     value(
         (),
         (
@@ -173,8 +1844,25 @@ fn type_expression(input: &[u8]) -> PResult<'_, ()> {
             context(
                 "Expected rust type expression",
                 alt((
-                    value((), rust_name),
-                    delimited(tag("["), value((), type_expression), tag("]")),
+                    type_path,
+                    delimited(
+                        tag("["),
+                        value(
+                            (),
+                            pair(
+                                type_expression,
+                                opt(preceded(
+                                    delimited(
+                                        spacelike,
+                                        char(';'),
+                                        spacelike,
+                                    ),
+                                    is_not("]"),
+                                )),
+                            ),
+                        ),
+                        tag("]"),
+                    ),
                     delimited(
                         tag("("),
                         value((), comma_type_expressions),
@@ -182,7 +1870,35 @@ fn type_expression(input: &[u8]) -> PResult<'_, ()> {
                     ),
                 )),
             ),
-            opt(delimited(tag("<"), comma_type_expressions, tag(">"))),
+        ),
+    )
+    .parse(input)
+}
+
+/// A path to a type or trait, with any generic arguments, including
+/// the `Fn(A) -> B` form.
+fn type_path(input: &[u8]) -> PResult<'_, ()> {
+    value(
+        (),
+        (
+            separated_list1(
+                tag("::"),
+                pair(
+                    rust_name,
+                    opt(delimited(
+                        tag("<"),
+                        comma_type_expressions,
+                        tag(">"),
+                    )),
+                ),
+            ),
+            opt(pair(
+                delimited(tag("("), comma_type_expressions, tag(")")),
+                opt(preceded(
+                    delimited(spacelike, tag("->"), spacelike),
+                    type_expression,
+                )),
+            )),
         ),
     )
     .parse(input)
@@ -194,7 +1910,18 @@ pub fn comma_type_expressions(input: &[u8]) -> PResult<'_, ()> {
         terminated(
             separated_list0(
                 preceded(tag(","), multispace0),
-                alt((type_expression, lifetime)),
+                alt((
+                    value(
+                        (),
+                        (
+                            rust_name,
+                            delimited(spacelike, char('='), spacelike),
+                            type_expression,
+                        ),
+                    ),
+                    type_expression,
+                    lifetime,
+                )),
             ),
             opt(preceded(tag(","), multispace0)),
         ),
@@ -208,7 +1935,208 @@ fn lifetime(input: &[u8]) -> PResult<'_, ()> {
 
 #[cfg(test)]
 mod test {
-    use super::type_expression;
+    use super::{
+        explicit_lifetimes, rebase_path, resolve_paths, template,
+        type_expression, Escaping, Pos, TemplateExpression, Whitespace,
+    };
+    use crate::parseresult::show_errors;
+    use crate::templateexpression::clear_pos;
+
+    #[test]
+    fn check_nesting_lines() {
+        let src = b"@(a: bool)\n<div>\n@if a {\n  <p><b>x</p>\n}\n</div>\n";
+        let (_, t) = template(src).unwrap();
+        let errors = t
+            .check_nesting()
+            .into_iter()
+            .map(|(pos, msg)| (pos.line_col(src), msg))
+            .collect::<Vec<_>>();
+        assert_eq!(errors, [((4, 8), "Unclosed <b>".to_string())]);
+    }
+
+    #[test]
+    fn check_nesting_branches() {
+        let (_, t) =
+            template(b"@(a: bool)\n@if a {<div>} else {<section>}\n</div>\n")
+                .unwrap();
+        let errors = t.check_nesting();
+        let errors = errors.iter().map(|(_, msg)| msg).collect::<Vec<_>>();
+        assert_eq!(
+            errors,
+            ["<div> is open after some branches but not after others"],
+        );
+    }
+
+    #[test]
+    fn extend_template() {
+        let (_, mut t) =
+            template(b"@(a: u8)\n@extends base_html(a, {x})\n@block b {B}\n")
+                .unwrap();
+        assert_eq!(t.extends().map(|e| e.name.as_str()), Some("base_html"));
+        let (_, parent) =
+            template(b"@(a: u8, c: Content)\n@block b {b}@block d {d}")
+                .unwrap();
+        assert_eq!(t.extend("super::base_html", parent), Ok(()));
+        assert_eq!(t.extends(), None);
+    }
+
+    #[test]
+    fn extend_unknown_block() {
+        let (_, mut t) =
+            template(b"@()\n@extends base_html()\n@block x {X}").unwrap();
+        let (_, parent) = template(b"@()\n@block b {b}").unwrap();
+        assert_eq!(
+            t.extend("super::base_html", parent),
+            Err("base_html has no block named x".to_string()),
+        );
+    }
+
+    #[test]
+    fn extend_template_in_other_directory() {
+        let (_, mut t) =
+            template(b"@()\n@extends page::base_html(\"s\", 1, g, 2)\n")
+                .unwrap();
+        let (_, parent) = template(
+            b"@use super::super::x_html;\n@use crate::y;\n\
+              @<'a, T: Display>(s: &'a str, t: T, f: impl Fn(), n: u8)\n\
+              @:super::header_html()@:x_html()",
+        )
+        .unwrap();
+        assert_eq!(t.extend("super::page::base_html", parent), Ok(()));
+        assert_eq!(t.preamble, ["use super::x_html", "use crate::y"]);
+        let mut out = String::new();
+        t.write_rust(&mut out, "t_html").unwrap();
+        assert!(out.contains(
+            "let s: _ = \"s\";\nlet t: _ = 1;\nlet f: _ = g;\n\
+             let n: u8 = 2;\n"
+        ));
+        assert!(out.contains("super::page::header_html(_ructe_out_"));
+    }
+
+    #[test]
+    fn rebase() {
+        assert_eq!(rebase_path("super::page", "super::x"), "super::page::x");
+        assert_eq!(rebase_path("super::page", "super::super::x"), "super::x");
+        assert_eq!(
+            rebase_path("super", "super::super::x"),
+            "super::super::x"
+        );
+        assert_eq!(rebase_path("super::page", "crate::x"), "crate::x");
+    }
+
+    #[test]
+    fn resolve_path_through_use() {
+        let (_, t) = template(
+            b"@use super::page::{self, base_html as b};\n\
+              @use super::*;\n@()\n",
+        )
+        .unwrap();
+        let any = |_: &String| true;
+        assert_eq!(t.resolve_path("b", any), "super::page::base_html");
+        assert_eq!(
+            t.resolve_path("page::x_html", any),
+            "super::page::x_html"
+        );
+        assert_eq!(t.resolve_path("other_html", any), "super::other_html");
+        assert_eq!(t.resolve_path("super::x_html", any), "super::x_html");
+    }
+
+    #[test]
+    fn resolve_path_through_glob_use() {
+        let (_, t) = template(
+            b"@use super::statics::*;\n@use super::parts::*;\n@()\n",
+        )
+        .unwrap();
+        assert_eq!(
+            resolve_paths(&t.preamble, "x_html"),
+            [
+                "super::statics::x_html",
+                "super::parts::x_html",
+                "super::x_html"
+            ],
+        );
+        assert_eq!(
+            t.resolve_path("x_html", |p| p == "super::parts::x_html"),
+            "super::parts::x_html",
+        );
+        assert_eq!(t.resolve_path("x_html", |_| false), "super::x_html");
+    }
+
+    #[test]
+    fn default_argument() {
+        let (_, t) =
+            template(b"@(a: &str, b: Option<u8> = Some(-1), c: Content)\n")
+                .unwrap();
+        assert_eq!(t.props_name("t_html"), Some("THtml".to_string()));
+        assert_eq!(
+            t.into_args()
+                .into_iter()
+                .map(|a| a.default)
+                .collect::<Vec<_>>(),
+            [None, Some("Some(-1)".to_string()), None],
+        );
+    }
+
+    #[test]
+    fn default_functions() {
+        let (_, t) = template(
+            b"@<'a, T: Display, U>(s: &'a str = \"x\", \
+              t: Option<T> = None, n: &str = \"y\", u: U)\n",
+        )
+        .unwrap();
+        assert_eq!(
+            t.default_names("t_html"),
+            ["t_html_default_s", "t_html_default_t", "t_html_default_n"],
+        );
+        let mut out = String::new();
+        t.write_rust(&mut out, "t_html").unwrap();
+        assert!(out.contains("pub fn t_html_default_s<'a>() -> &'a str {"));
+        assert!(out.contains(
+            "pub fn t_html_default_t<T: Display>() -> Option<T> {"
+        ));
+        assert!(out
+            .contains("pub fn t_html_default_n<'ructe>() -> &'ructe str {"));
+    }
+
+    #[test]
+    fn no_default_for_content() {
+        let input = b"@(c: Content = x)\n";
+        let error = template(input).unwrap_err();
+        assert!(matches!(error, nom::Err::Failure(_)));
+        let mut out = Vec::new();
+        show_errors(&mut out, input, &error, "");
+        assert!(String::from_utf8(out).unwrap().contains(
+            "A Content or impl argument cannot have a default value"
+        ));
+    }
+
+    #[test]
+    fn props_warning() {
+        let (_, t) = template(b"@(a: &str, b: String = x())\n").unwrap();
+        assert_eq!(t.props_warning("t_html"), None);
+        let (_, t) = template(b"@(a: Vec<u8>, b: u8 = 1)\n").unwrap();
+        assert_eq!(
+            t.props_warning("t_html").as_deref(),
+            Some(
+                "THtml does not implement ToHtml, since the type Vec<u8> \
+                 of a is not known to be Copy or Clone"
+            ),
+        );
+    }
+
+    #[test]
+    fn explicit_lifetimes_in_props() {
+        assert_eq!(
+            explicit_lifetimes("Option<&[&'a str]>"),
+            "Option<&'ructe [&'a str]>",
+        );
+        assert_eq!(explicit_lifetimes("Cow<'_, str>"), "Cow<'ructe, str>");
+    }
+
+    #[test]
+    fn text_after_extends() {
+        assert!(template(b"@()\n@extends base_html()\n<p>x</p>").is_err());
+    }
 
     #[test]
     fn tuple() {
@@ -255,6 +2183,365 @@ mod test {
         check_type_expr("ONE_TWO_THREE");
     }
 
+    #[test]
+    fn path_with_generics() {
+        check_type_expr("std::collections::HashMap<u8, std::rc::Rc<str>>");
+    }
+
+    #[test]
+    fn associated_type_binding() {
+        check_type_expr("impl Iterator<Item = &'a str>");
+    }
+
+    #[test]
+    fn fn_trait() {
+        check_type_expr("impl Fn(&str, u8) -> String");
+    }
+
+    #[test]
+    fn array() {
+        check_type_expr("[&'a T; N]");
+    }
+
+    #[test]
+    fn generic_params_and_where() {
+        let (_, t) = template(
+            b"@<'a, T: Display + 'a, const N: usize>(x: [&'a T; N])\n\
+              where T: ?Sized,\n\
+              <p>@x.len()</p>\n",
+        )
+        .unwrap();
+        let mut code = String::new();
+        t.write_rust(&mut code, "t_html").unwrap();
+        assert!(code.contains(
+            "pub fn t_html<'a, T: Display + 'a, const N: usize, W>("
+        ));
+        assert!(code.contains("where W: Write, T: ?Sized, {"));
+    }
+
+    #[test]
+    fn text_starting_with_where() {
+        let (_, t) = template(b"@(x: u8)\nwhere is @x?\n").unwrap();
+        assert_eq!(t.where_clause, None);
+    }
+
+    #[test]
+    fn helper_function() {
+        let (_, mut t) = template(
+            b"@(items: &[u8])\n\
+              @fn cell(x: u8, ok: bool = true) {<td>@x @ok</td>}\n\
+              @for i in items {@:cell(*i)}\n",
+        )
+        .unwrap();
+        assert_eq!(t.helpers.len(), 1);
+        t.resolve_calls(|_| None).unwrap();
+        let mut code = String::new();
+        t.write_rust(&mut code, "t_html").unwrap();
+        assert!(code.contains("fn cell<W>("));
+        assert!(!code.contains("pub fn cell"));
+        assert!(code.contains("cell(_ructe_out_.by_ref(), *i, true)?;"));
+    }
+
+    #[test]
+    fn async_variant() {
+        let (_, mut t) = template(
+            b"@use super::page::{self, base_html as b, Thing};\n\
+              @(items: &[u8], body: Content)\n\
+              @fn cell(x: u8) {<td>@x</td>}\n\
+              @for i in items {@:cell(*i)}@:body()\n",
+        )
+        .unwrap();
+        t.resolve_calls(|_| None).unwrap();
+        let mut code = String::new();
+        t.write_async_rust(&mut code, "t_html").unwrap();
+        assert!(
+            code.contains("use super::page::{base_html_async as b_async};")
+        );
+        assert!(code.contains("pub async fn t_html_async<'ructe, W>("));
+        assert!(code.contains(") -> io::Result<W>\n"));
+        assert!(code.contains(
+            "where W: tokio::io::AsyncWrite + Unpin + Send + 'ructe {"
+        ));
+        assert!(code.contains("async fn cell_async<W>("));
+        assert!(!code.contains("pub async fn cell"));
+        assert!(code.contains("_ructe_out_ = cell_async(_ructe_out_, *i)"));
+        assert!(code.contains("_ructe_out_ = body(_ructe_out_).await?;"));
+    }
+
+    #[test]
+    fn fmt_variant() {
+        let (_, mut t) = template(
+            b"@(items: &[u8], body: Content)\n\
+              @for i in items {<b>@i</b>}@:body()\n",
+        )
+        .unwrap();
+        t.resolve_calls(|_| None).unwrap();
+        let mut code = String::new();
+        t.write_fmt_rust(&mut code, "t_html").unwrap();
+        assert!(code.starts_with("use core::fmt::{self, Write};\n"));
+        assert!(code.contains(
+            "pub fn t_html<W>(\n  _ructe_out_: &mut W,\n  items: &[u8],\n  \
+             body: impl FnOnce(&mut W) -> fmt::Result,\n) -> fmt::Result\n"
+        ));
+        assert!(code.contains("_ructe_out_.write_str(\"<b>\")?;"));
+        assert!(code.contains("i.to_html(&mut *_ructe_out_)?;"));
+        assert!(code.contains("body(&mut *_ructe_out_)?;"));
+        assert!(code.contains(
+            "pub fn t_html_to_string(\n  items: &[u8],\n  \
+             body: impl FnOnce(&mut alloc::string::String) -> fmt::Result,\n\
+             ) -> alloc::string::String\n"
+        ));
+    }
+
+    #[test]
+    fn value_struct() {
+        let (_, mut t) =
+            template(b"@(title: &str, n: usize, x: impl Display)\n").unwrap();
+        assert_eq!(t.props_name("t_html"), None);
+        t.generate_struct();
+        assert_eq!(t.props_name("t_html"), Some("THtml".to_string()));
+        let mut code = String::new();
+        t.write_rust(&mut code, "t_html").unwrap();
+        assert!(code.contains(
+            "impl<'ructe, XT> ToHtml for THtml<'ructe, XT>\n\
+             where XT: Display, XT: Clone {\n\
+             fn to_html(&self, out: &mut dyn Write) -> io::Result<()> {\n\
+             t_html(out, self.title, self.n, self.x.clone())\n"
+        ));
+        assert!(code.contains(
+            "pub fn display(&self) -> _utils::HtmlDisplay<'_, Self> {"
+        ));
+    }
+
+    #[test]
+    fn size_hint() {
+        let (_, t) = template(
+            b"@(x: bool)\n<p>@if x {yes} else {not x}</p>\n\
+              @for i in 0..3 {<b>@i</b>}\n",
+        )
+        .unwrap();
+        let mut code = String::new();
+        t.write_rust(&mut code, "t_html").unwrap();
+        // "<p>", "not x", "</p>\n", "<b>", "</b>" and "\n".
+        assert!(code.contains("pub const T_HTML_SIZE_HINT: usize = 21;\n"));
+    }
+
+    #[test]
+    fn position_markers() {
+        let buf = b"@(x: bool)\n<p>@if x {\n  @x.then(|| 1)\n}</p>\n";
+        let (_, mut t) = template(buf).unwrap();
+        t.locate("templates/t.rs.html", buf);
+        let mut code = String::new();
+        t.write_rust(&mut code, "t_html").unwrap();
+        assert!(code.contains("// ructe: templates/t.rs.html:2:4\nif x {"));
+        assert!(code.contains(
+            "// ructe: templates/t.rs.html:3:3\nx.then(|| 1).to_html("
+        ));
+    }
+
+    #[test]
+    fn signature_and_end_markers() {
+        let buf = b"@(x: u8,\n  y: u8 = 1)\n@:h(x)\n@fn h(z: u8) {@z}\n";
+        let (_, mut t) = template(buf).unwrap();
+        t.locate("t.rs.html", buf);
+        let mut code = String::new();
+        t.write_rust(&mut code, "t_html").unwrap();
+        let marked = |marker: &str, code_line: &str| {
+            code.contains(&format!("// ructe: {marker}\n{code_line}"))
+        };
+        assert!(marked("t.rs.html:1:1", "#[allow("));
+        assert!(marked("t.rs.html:1:3", "  x: u8,"));
+        assert!(marked("t.rs.html:2:3", "  y: u8,"));
+        assert!(marked("t.rs.html:1:1", ") -> io::Result<()>"));
+        assert!(marked("t.rs.html:4:1", "#[allow("));
+        assert!(marked("t.rs.html:4:7", "  z: u8,"));
+        assert!(marked("t.rs.html:2:3", "#[doc(hidden)]"));
+        // Each function ends with an end marker, so nothing after
+        // them, such as the props struct, is mapped.
+        assert_eq!(code.matches("// ructe: end\n").count(), 3);
+        let rest = &code[code.rfind("// ructe: ").unwrap()..];
+        assert!(rest.starts_with("// ructe: end\n"));
+        assert!(rest.contains("pub struct THtml"));
+    }
+
+    #[test]
+    fn whitespace_mode() {
+        let (_, mut t) = template(
+            b"@whitespace collapse;\n@use super::x;\n@()\n<p>\n  @x()\n</p>\n",
+        )
+        .unwrap();
+        assert_eq!(t.whitespace(), Some(Whitespace::Collapse));
+        assert_eq!(t.preamble, ["use super::x"]);
+        t.collapse_whitespace();
+        clear_pos(&mut t.body);
+        assert_eq!(
+            t.body,
+            [
+                TemplateExpression::text("<p> "),
+                TemplateExpression::Expression {
+                    expr: "x()".into(),
+                    pos: Pos::default(),
+                },
+                TemplateExpression::text(" </p>"),
+            ],
+        );
+    }
+
+    #[test]
+    fn coalesce_text() {
+        let (_, mut t) =
+            template(b"@()\n<p>@* note *@</p>\n@if x {a@* b *@c}\n").unwrap();
+        t.coalesce_text();
+        clear_pos(&mut t.body);
+        assert_eq!(
+            t.body,
+            [
+                TemplateExpression::text("<p></p>\n"),
+                TemplateExpression::IfBlock {
+                    expr: "x".into(),
+                    body: vec![TemplateExpression::text("ac")],
+                    else_body: None,
+                    pos: Pos::default(),
+                },
+                TemplateExpression::text("\n"),
+            ],
+        );
+    }
+
+    #[test]
+    fn doc_comment_and_attributes() {
+        let (_, t) = template(
+            b"@** Show a price.\n\n    Indented *bold*.\n  **@\n\
+              @#[cfg(feature = \"shop\")]\n\
+              @#[deprecated = \"Use price2\"]\n\
+              @(\n  /// The price.\n  /// In SEK.\n  price: f64 = 1.0)\n\
+              <p>@price</p>\n",
+        )
+        .unwrap();
+        assert_eq!(t.doc, ["Show a price.", "", "Indented *bold*."]);
+        assert_eq!(t.args[0].doc, ["The price.", "In SEK."]);
+        assert_eq!(t.cfg_attributes(), "#[cfg(feature = \"shop\")]\n");
+        let mut code = String::new();
+        t.write_rust(&mut code, "price_html").unwrap();
+        assert!(code.contains(
+            "/// Show a price.\n///\n/// Indented *bold*.\n///\n\
+             /// # Arguments\n///\n\
+             /// * `price`: The price.\n///   In SEK.\n\
+             #[cfg(feature = \"shop\")]\n\
+             #[deprecated = \"Use price2\"]\n\
+             pub fn price_html<W>("
+        ));
+        assert!(
+            code.contains("/// The price.\n/// In SEK.\n  pub price: f64,")
+        );
+        assert!(code.contains("#[allow(deprecated)]\npub fn render<W"));
+        assert_eq!(
+            t.use_attributes(),
+            "#[cfg(feature = \"shop\")]\n#[allow(deprecated)]\n",
+        );
+    }
+
+    #[test]
+    fn plain_comment_is_not_doc() {
+        let (_, t) = template(b"@* a *@\n@** b *@\n@()\nx\n").unwrap();
+        assert!(t.doc.is_empty());
+    }
+
+    #[test]
+    fn contextual_escaping() {
+        let (_, mut t) = template(
+            b"@escaping contextual;\n@(x: &str)\n\
+              <a href=\"@x?q=@x\" onclick=\"f('@x')\">@x</a>\n",
+        )
+        .unwrap();
+        assert_eq!(t.escaping(), Some(Escaping::Contextual));
+        t.escape_by_context(true).unwrap();
+        let mut code = String::new();
+        t.write_rust(&mut code, "t_html").unwrap();
+        assert!(code.contains("_utils::SafeUrl(&x).to_html("));
+        assert!(code.contains("_utils::UrlEncoded(&x).to_html("));
+        assert!(code.contains("_utils::JsString(&x).to_html("));
+        assert!(code.contains("\nx.to_html("));
+    }
+
+    #[test]
+    fn contextual_escaping_unquoted() {
+        let (_, mut t) = template(b"@(x: &str)\n<a href=@x>\n").unwrap();
+        assert_eq!(
+            t.escape_by_context(true),
+            Err("Cannot escape @x: The value of attribute href must be \
+                 quoted to be safely escaped"
+                .to_string()),
+        );
+    }
+
+    #[test]
+    fn contextual_escaping_unquoted_javascript() {
+        let (_, mut t) =
+            template(b"@(v: &str)\n<script>var x = @v;</script>\n").unwrap();
+        assert_eq!(
+            t.escape_by_context(true),
+            Err("Cannot escape @v: An expression in javascript must be in \
+                 a quoted string to be safely escaped"
+                .to_string()),
+        );
+        let (_, mut t) =
+            template(b"@(v: Option<&str>)\n<a @?onclick=v>\n").unwrap();
+        assert_eq!(
+            t.escape_by_context(true),
+            Err("Cannot escape @?onclick=v: The value of an event handler \
+                 attribute is javascript code, that can not be safely \
+                 escaped"
+                .to_string()),
+        );
+    }
+
+    #[test]
+    fn contextual_escaping_of_attributes() {
+        let (_, mut t) =
+            template(b"@(x: Option<&str>)\n<a @?href=x @?title=x>\n")
+                .unwrap();
+        t.escape_by_context(true).unwrap();
+        let mut code = String::new();
+        t.write_rust(&mut code, "t_html").unwrap();
+        assert!(code.contains(
+            "_utils::EscapedAttr(\"href\", &x, \
+             |v, o| _utils::SafeUrl(v).to_html(o)).to_html("
+        ));
+        assert!(code.contains("_utils::Attr(\"title\", &x).to_html("));
+    }
+
+    #[test]
+    fn contextual_escaping_in_branches() {
+        let (_, mut t) = template(
+            b"@(x: &str, a: bool)\n\
+              @if a {<script>var y = \"} else {<a href=\"@x\">}@x\n",
+        )
+        .unwrap();
+        t.escape_by_context(true).unwrap();
+        let mut code = String::new();
+        t.write_rust(&mut code, "t_html").unwrap();
+        assert!(code.contains("_utils::SafeUrl(&x).to_html("));
+        assert!(code.contains("_utils::JsString(&x).to_html("));
+    }
+
+    #[test]
+    fn contextual_escaping_of_overriding_blocks() {
+        let (_, mut parent) =
+            template(b"@(x: &str)\n<script>@block js {}</script>\n").unwrap();
+        parent.escape_by_context(true).unwrap();
+        let (_, mut t) = template(
+            b"@(x: &str)\n@extends base_html(x)\n@block js {f('@x')}\n",
+        )
+        .unwrap();
+        t.escape_by_context(true).unwrap();
+        t.escape_blocks(&parent, true).unwrap();
+        t.extend("super::base_html", parent).unwrap();
+        let mut code = String::new();
+        t.write_rust(&mut code, "t_html").unwrap();
+        assert!(code.contains("_utils::JsString(&x).to_html("));
+    }
+
     fn check_type_expr(expr: &str) {
         assert_eq!(type_expression(expr.as_bytes()), Ok((&b""[..], ())));
     }
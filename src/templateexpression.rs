@@ -1,684 +1,3675 @@
 use crate::expression::{
-    comma_expressions, expr_in_braces, expr_inside_parens, expression,
-    input_to_str, rust_name,
+    comma_expressions, expr_before_colon, expr_before_semicolon,
+    expr_in_braces, expr_inside_parens, expression, input_to_str, pattern,
+    rust_name,
 };
-use crate::parseresult::PResult;
+use crate::htmlcontext::{starts_with_tag, Escape, HtmlContext, HtmlNesting};
+use crate::parseresult::{PResult, Pos};
 use crate::spacelike::{comment_tail, spacelike};
+use crate::template::{type_expression, FormalArgument};
 use nom::branch::alt;
-use nom::bytes::complete::is_not;
-use nom::bytes::complete::tag;
-use nom::character::complete::char;
-use nom::combinator::{map, map_res, opt, recognize, value};
+use nom::bytes::complete::{is_a, is_not, tag, take, take_while1};
+use nom::character::complete::{
+    char, digit1, multispace0, multispace1, none_of, one_of, satisfy,
+};
+use nom::combinator::{cut, map, map_res, not, opt, peek, recognize, value};
 use nom::error::context;
-use nom::multi::{many0, many_till, separated_list0};
-use nom::sequence::{delimited, pair, preceded, terminated};
+use nom::multi::{many0, many_till, separated_list0, separated_list1};
+use nom::sequence::{delimited, pair, preceded, separated_pair, terminated};
 use nom::Parser as _;
-use std::fmt::{self, Display, Write};
+use std::fmt::{self, Write};
+use std::rc::Rc;
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum TemplateExpression {
     Comment,
     Text {
         text: String,
+        pos: Pos,
+    },
+    /// The verbatim text of a `@raw` block.
+    ///
+    /// This is written just like text, but its whitespace is never
+    /// collapsed.
+    Raw {
+        text: String,
+        pos: Pos,
     },
     Expression {
         expr: String,
+        pos: Pos,
+    },
+    /// An optional attribute, `@?name=value`.
+    Attribute {
+        name: String,
+        value: String,
+        pos: Pos,
+    },
+    /// An expression written with a format spec, `@(expr:spec)`.
+    Formatted {
+        expr: String,
+        spec: String,
+        pos: Pos,
     },
     ForLoop {
+        label: Option<String>,
         name: String,
         expr: String,
+        info: Option<String>,
+        body: Vec<TemplateExpression>,
+        sep: Option<Vec<TemplateExpression>>,
+        else_body: Option<Vec<TemplateExpression>>,
+        pos: Pos,
+    },
+    WhileLoop {
+        label: Option<String>,
+        expr: String,
         body: Vec<TemplateExpression>,
+        pos: Pos,
+    },
+    Break {
+        label: Option<String>,
+        pos: Pos,
+    },
+    Continue {
+        label: Option<String>,
+        pos: Pos,
     },
     IfBlock {
         expr: String,
         body: Vec<TemplateExpression>,
         else_body: Option<Vec<TemplateExpression>>,
+        pos: Pos,
     },
     MatchBlock {
         expr: String,
-        arms: Vec<(String, Vec<TemplateExpression>)>,
+        arms: Vec<MatchArm>,
+        pos: Pos,
     },
     CallTemplate {
         name: String,
         args: Vec<TemplateArgument>,
+        named: Vec<(String, TemplateArgument)>,
+        block: Option<Vec<TemplateExpression>>,
+        pos: Pos,
+    },
+    Code {
+        code: String,
+        pos: Pos,
+    },
+    Block {
+        name: String,
+        body: Vec<TemplateExpression>,
+        pos: Pos,
+    },
+    /// The body of an extended template, with the overriding blocks
+    /// of the extending template and the arguments of the parent.
+    Parent {
+        blocks: Vec<(String, Vec<TemplateExpression>)>,
+        args: Vec<(FormalArgument, TemplateArgument)>,
+        body: Vec<TemplateExpression>,
+        pos: Pos,
+    },
+    /// A file to include, until it is replaced by its contents.
+    Include {
+        path: String,
+        escaped: bool,
+        pos: Pos,
     },
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MatchArm {
+    pattern: String,
+    guard: Option<String>,
+    body: Vec<TemplateExpression>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum TemplateArgument {
     Rust(String),
     Body(Vec<TemplateExpression>),
 }
 
-impl Display for TemplateArgument {
-    fn fmt(&self, out: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-        match *self {
-            TemplateArgument::Rust(ref s) => out.write_str(s),
-            TemplateArgument::Body(ref v) if v.is_empty() => {
-                out.write_str("|_| Ok(())")
-            }
-            TemplateArgument::Body(ref v) => {
-                out.write_str("#[allow(clippy::used_underscore_binding)] |mut _ructe_out_| {\n")?;
-                for b in v {
-                    b.write_code(out)?;
-                }
-                out.write_str("Ok(())\n}\n")
+impl TemplateArgument {
+    fn body(&self) -> Option<&[TemplateExpression]> {
+        match self {
+            TemplateArgument::Body(body) => Some(body),
+            TemplateArgument::Rust(_) => None,
+        }
+    }
+    fn body_mut(&mut self) -> Option<&mut Vec<TemplateExpression>> {
+        match self {
+            TemplateArgument::Body(body) => Some(body),
+            TemplateArgument::Rust(_) => None,
+        }
+    }
+}
+
+impl TemplateArgument {
+    fn write(&self, out: &mut impl Write, flavor: Flavor) -> fmt::Result {
+        match self {
+            TemplateArgument::Rust(s) => out.write_str(s),
+            TemplateArgument::Body(v) => write_content(out, v, flavor),
+        }
+    }
+}
+
+/// The kind of code written for a template.
+#[derive(Clone, Copy, Debug)]
+pub enum Flavor<'a> {
+    /// Code writing to a `std::io::Write`.
+    Sync,
+    /// Async code writing to a `tokio::io::AsyncWrite`.
+    ///
+    /// The names are the content closures in scope, which are called
+    /// as they are, while other calls are to the `_async` variant of
+    /// a template.
+    Async(&'a [String]),
+    /// Code writing to a `core::fmt::Write`, given as a `&mut W`.
+    Fmt,
+}
+
+impl Flavor<'_> {
+    /// The suffix to wait for a write.
+    fn wait(self) -> &'static str {
+        match self {
+            Flavor::Sync | Flavor::Fmt => "",
+            Flavor::Async(_) => ".await",
+        }
+    }
+
+    /// The output, to give to a `ToHtml` or a called template.
+    fn out(self) -> &'static str {
+        match self {
+            Flavor::Sync => "_ructe_out_.by_ref()",
+            Flavor::Fmt => "&mut *_ructe_out_",
+            Flavor::Async(_) => "_ructe_out_",
+        }
+    }
+
+    /// The result type of a template function or closure.
+    pub fn result(self) -> &'static str {
+        match self {
+            Flavor::Sync => "io::Result<()>",
+            Flavor::Fmt => "fmt::Result",
+            Flavor::Async(_) => "io::Result<W>",
+        }
+    }
+}
+
+/// Write a template block as a `Content` closure.
+///
+/// An async content closure takes the output by value, and gives it
+/// back when done.
+fn write_content(
+    out: &mut impl Write,
+    body: &[TemplateExpression],
+    flavor: Flavor,
+) -> fmt::Result {
+    match flavor {
+        Flavor::Sync | Flavor::Fmt if body.is_empty() => {
+            out.write_str("|_| Ok(())")
+        }
+        Flavor::Sync | Flavor::Fmt => {
+            out.write_str("#[allow(clippy::used_underscore_binding)] |")?;
+            if let Flavor::Sync = flavor {
+                out.write_str("mut ")?;
             }
+            out.write_str("_ructe_out_| {\n")?;
+            write_body(out, body, flavor)?;
+            out.write_str("Ok(())\n}\n")
+        }
+        Flavor::Async(_) => {
+            out.write_str(
+                "#[allow(clippy::used_underscore_binding)] \
+                 |_ructe_out_| Box::pin(async {\n\
+                 let mut _ructe_out_ = _ructe_out_;\n",
+            )?;
+            write_body(out, body, flavor)?;
+            out.write_str("Ok::<_, io::Error>(_ructe_out_)\n})\n")
         }
     }
 }
 
 impl TemplateExpression {
+    #[cfg(test)]
     pub fn text(text: &str) -> Self {
+        TemplateExpression::text_at(text, Pos::default())
+    }
+    fn text_at(text: &str, pos: Pos) -> Self {
         TemplateExpression::Text {
             text: text.to_string(),
+            pos,
         }
     }
-    pub fn write_code(&self, out: &mut impl Write) -> fmt::Result {
-        match *self {
-            TemplateExpression::Comment => Ok(()),
-            TemplateExpression::Text { ref text } if text.is_ascii() => {
-                writeln!(out, "_ructe_out_.write_all(b{text:?})?;")
+    /// The position of this expression in the template source.
+    ///
+    /// A comment has no position, since it generates no code.
+    fn pos(&self) -> Option<&Pos> {
+        match self {
+            TemplateExpression::Comment => None,
+            TemplateExpression::Text { pos, .. }
+            | TemplateExpression::Raw { pos, .. }
+            | TemplateExpression::Expression { pos, .. }
+            | TemplateExpression::Attribute { pos, .. }
+            | TemplateExpression::Formatted { pos, .. }
+            | TemplateExpression::ForLoop { pos, .. }
+            | TemplateExpression::WhileLoop { pos, .. }
+            | TemplateExpression::Break { pos, .. }
+            | TemplateExpression::Continue { pos, .. }
+            | TemplateExpression::IfBlock { pos, .. }
+            | TemplateExpression::MatchBlock { pos, .. }
+            | TemplateExpression::CallTemplate { pos, .. }
+            | TemplateExpression::Code { pos, .. }
+            | TemplateExpression::Block { pos, .. }
+            | TemplateExpression::Parent { pos, .. }
+            | TemplateExpression::Include { pos, .. } => Some(pos),
+        }
+    }
+
+    fn pos_mut(&mut self) -> Option<&mut Pos> {
+        match self {
+            TemplateExpression::Comment => None,
+            TemplateExpression::Text { pos, .. }
+            | TemplateExpression::Raw { pos, .. }
+            | TemplateExpression::Expression { pos, .. }
+            | TemplateExpression::Attribute { pos, .. }
+            | TemplateExpression::Formatted { pos, .. }
+            | TemplateExpression::ForLoop { pos, .. }
+            | TemplateExpression::WhileLoop { pos, .. }
+            | TemplateExpression::Break { pos, .. }
+            | TemplateExpression::Continue { pos, .. }
+            | TemplateExpression::IfBlock { pos, .. }
+            | TemplateExpression::MatchBlock { pos, .. }
+            | TemplateExpression::CallTemplate { pos, .. }
+            | TemplateExpression::Code { pos, .. }
+            | TemplateExpression::Block { pos, .. }
+            | TemplateExpression::Parent { pos, .. }
+            | TemplateExpression::Include { pos, .. } => Some(pos),
+        }
+    }
+
+    /// Locate the position of this expression, and all expressions
+    /// in it, in `buf`, the source of the template file `path`.
+    pub fn locate(&mut self, path: &Rc<str>, buf: &[u8]) {
+        if let Some(pos) = self.pos_mut() {
+            pos.locate(path, buf);
+        }
+        for body in self.bodies_mut() {
+            locate(body, path, buf);
+        }
+    }
+
+    /// Write the code for this expression.
+    ///
+    /// Code other than literal text is preceded by a marker of the
+    /// position of the expression in its template file, if located.
+    pub fn write_code(
+        &self,
+        out: &mut impl Write,
+        flavor: Flavor,
+    ) -> fmt::Result {
+        match self {
+            TemplateExpression::Text { text, .. }
+            | TemplateExpression::Raw { text, .. } => {
+                write_text(out, text, flavor)
             }
-            TemplateExpression::Text { ref text } => {
-                writeln!(out, "_ructe_out_.write_all({text:?}.as_bytes())?;")
+            _ => {
+                if let Some(pos) = self.pos() {
+                    pos.write_marker(out)?;
+                }
+                self.write_expression(out, flavor)
             }
-            TemplateExpression::Expression { ref expr } => {
-                writeln!(out, "{expr}.to_html(_ructe_out_.by_ref())?;")
+        }
+    }
+
+    /// Write the code for this expression, other than literal text,
+    /// without a marker.
+    fn write_expression(
+        &self,
+        out: &mut impl Write,
+        flavor: Flavor,
+    ) -> fmt::Result {
+        match *self {
+            TemplateExpression::Comment
+            | TemplateExpression::Text { .. }
+            | TemplateExpression::Raw { .. } => Ok(()),
+            TemplateExpression::Expression { ref expr, .. } => {
+                write_html(out, expr, flavor)
             }
+            TemplateExpression::Attribute {
+                ref name,
+                ref value,
+                ..
+            } => write_html(
+                out,
+                &format!("_utils::Attr({name:?}, &{value})"),
+                flavor,
+            ),
+            TemplateExpression::Formatted {
+                ref expr, ref spec, ..
+            } => write_formatted(out, expr, spec, flavor),
+            TemplateExpression::Include { ref path, .. } => writeln!(
+                out,
+                "compile_error!({:?});",
+                format!("Included file {path:?} was not resolved"),
+            ),
             TemplateExpression::ForLoop {
+                ref label,
                 ref name,
                 ref expr,
+                ref info,
                 ref body,
-            } => {
-                writeln!(out, "for {name} in {expr} {{")?;
-                for b in body {
-                    b.write_code(out)?;
-                }
-                out.write_str("}\n")
+                ref sep,
+                ref else_body,
+                ..
+            } => write_for(
+                out,
+                label.as_deref(),
+                name,
+                expr,
+                info.as_deref(),
+                body,
+                sep.as_deref(),
+                else_body.as_deref(),
+                flavor,
+            ),
+            TemplateExpression::WhileLoop {
+                ref label,
+                ref expr,
+                ref body,
+                ..
+            } => write_loop(
+                out,
+                label.as_deref(),
+                &format!("while {expr}"),
+                body,
+                flavor,
+            ),
+            TemplateExpression::Break { ref label, .. } => {
+                write_jump(out, "break", label.as_deref())
+            }
+            TemplateExpression::Continue { ref label, .. } => {
+                write_jump(out, "continue", label.as_deref())
             }
             TemplateExpression::IfBlock {
                 ref expr,
                 ref body,
                 ref else_body,
+                ..
+            } => write_if(out, expr, body, else_body.as_deref(), flavor),
+            TemplateExpression::MatchBlock {
+                ref expr, ref arms, ..
+            } => write_match(out, expr, arms, flavor),
+            TemplateExpression::CallTemplate {
+                ref name,
+                ref args,
+                ref named,
+                ref block,
+                ..
+            } => write_call(out, name, args, named, block.as_deref(), flavor),
+            TemplateExpression::Code { ref code, .. } => {
+                writeln!(out, "{code}")
+            }
+            TemplateExpression::Block { ref body, .. } => {
+                write_body(out, body, flavor)
+            }
+            TemplateExpression::Parent {
+                ref blocks,
+                ref args,
+                ref body,
+                ..
+            } => write_parent(out, blocks, args, body, flavor),
+        }
+    }
+
+    /// Replace each `@block` with one of the given `names` with a
+    /// call to the closure for the overriding block.
+    pub fn override_blocks(&mut self, names: &[&str]) {
+        match self {
+            TemplateExpression::Block { name, pos, .. }
+                if names.contains(&name.as_str()) =>
+            {
+                *self = TemplateExpression::CallTemplate {
+                    name: format!("_ructe_block_{name}"),
+                    args: Vec::new(),
+                    named: Vec::new(),
+                    block: None,
+                    pos: pos.clone(),
+                };
+                return;
+            }
+            TemplateExpression::Parent { blocks, .. } => {
+                blocks.retain(|(name, _)| !names.contains(&name.as_str()));
+            }
+            _ => {}
+        }
+        for body in self.bodies_mut() {
+            override_blocks(body, names);
+        }
+    }
+
+    /// Add the names of all `@block`s in this expression to `names`,
+    /// including blocks that are already overridden.
+    pub fn block_names<'a>(&'a self, names: &mut Vec<&'a str>) {
+        match self {
+            TemplateExpression::Block { name, .. } => names.push(name),
+            TemplateExpression::Parent { blocks, .. } => {
+                names.extend(blocks.iter().map(|(name, _)| name.as_str()));
+            }
+            _ => {}
+        }
+        for body in self.bodies() {
+            block_names(body, names);
+        }
+    }
+
+    /// The length of the literal text written by this expression.
+    ///
+    /// For a choice between bodies, such as the branches of an `@if`
+    /// or the body and `else` of a `@for`, the longest one counts, and
+    /// a loop body counts once.
+    pub fn size_hint(&self) -> usize {
+        match self {
+            TemplateExpression::Text { text, .. }
+            | TemplateExpression::Raw { text, .. } => text.len(),
+            TemplateExpression::ForLoop { .. }
+            | TemplateExpression::IfBlock { .. }
+            | TemplateExpression::MatchBlock { .. } => {
+                self.bodies().into_iter().map(size_hint).max().unwrap_or(0)
+            }
+            _ => self.bodies().into_iter().map(size_hint).sum(),
+        }
+    }
+
+    /// Replace each `@include_raw` and `@include_escaped` in this
+    /// expression with the text of the included file.
+    ///
+    /// The `read` function gives the contents of a file, escaped if
+    /// requested.
+    pub fn resolve_includes(
+        &mut self,
+        read: &mut impl FnMut(&str, bool) -> Result<String, String>,
+    ) -> Result<(), String> {
+        if let TemplateExpression::Include { path, escaped, pos } = self {
+            let text = read(path, *escaped)?;
+            *self = TemplateExpression::text_at(&text, pos.clone());
+        }
+        for body in self.bodies_mut() {
+            for e in body {
+                e.resolve_includes(read)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Put the named arguments and trailing block of each template
+    /// call in place among the positional arguments.
+    ///
+    /// The `params` function gives the arguments of a called
+    /// template, if it can be found.
+    /// Arguments that are not given get their default value.
+    pub fn resolve_calls(
+        &mut self,
+        params: &mut impl FnMut(&str) -> Option<Vec<FormalArgument>>,
+    ) -> Result<(), String> {
+        for body in self.bodies_mut() {
+            for e in body {
+                e.resolve_calls(params)?;
+            }
+        }
+        if let TemplateExpression::CallTemplate {
+            name,
+            args,
+            named,
+            block,
+            ..
+        } = self
+        {
+            match params(name) {
+                Some(params) => {
+                    *args = place_arguments(
+                        name,
+                        &params,
+                        std::mem::take(args),
+                        std::mem::take(named),
+                        block.take(),
+                    )?;
+                }
+                None if named.is_empty() => {
+                    args.extend(block.take().map(TemplateArgument::Body));
+                }
+                None => {
+                    return Err(format!(
+                        "Cannot find template {name} for named arguments"
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The template code blocks directly inside this expression.
+    fn bodies(&self) -> Vec<&[TemplateExpression]> {
+        match self {
+            TemplateExpression::ForLoop {
+                body,
+                sep,
+                else_body,
+                ..
+            } => [Some(body), sep.as_ref(), else_body.as_ref()]
+                .into_iter()
+                .flatten()
+                .map(Vec::as_slice)
+                .collect(),
+            TemplateExpression::IfBlock {
+                body, else_body, ..
+            } => [Some(body), else_body.as_ref()]
+                .into_iter()
+                .flatten()
+                .map(Vec::as_slice)
+                .collect(),
+            TemplateExpression::MatchBlock { arms, .. } => {
+                arms.iter().map(|arm| arm.body.as_slice()).collect()
+            }
+            TemplateExpression::CallTemplate {
+                args, named, block, ..
+            } => args
+                .iter()
+                .chain(named.iter().map(|(_, arg)| arg))
+                .filter_map(TemplateArgument::body)
+                .chain(block.as_deref())
+                .collect(),
+            TemplateExpression::Parent {
+                blocks, args, body, ..
+            } => blocks
+                .iter()
+                .map(|(_, body)| body.as_slice())
+                .chain(args.iter().filter_map(|(_, arg)| arg.body()))
+                .chain([body.as_slice()])
+                .collect(),
+            TemplateExpression::Block { body, .. }
+            | TemplateExpression::WhileLoop { body, .. } => vec![body],
+            TemplateExpression::Comment
+            | TemplateExpression::Text { .. }
+            | TemplateExpression::Raw { .. }
+            | TemplateExpression::Expression { .. }
+            | TemplateExpression::Attribute { .. }
+            | TemplateExpression::Formatted { .. }
+            | TemplateExpression::Code { .. }
+            | TemplateExpression::Include { .. }
+            | TemplateExpression::Break { .. }
+            | TemplateExpression::Continue { .. } => vec![],
+        }
+    }
+
+    /// The template code blocks directly inside this expression.
+    fn bodies_mut(&mut self) -> Vec<&mut Vec<TemplateExpression>> {
+        match self {
+            TemplateExpression::ForLoop {
+                body,
+                sep,
+                else_body,
+                ..
+            } => [Some(body), sep.as_mut(), else_body.as_mut()]
+                .into_iter()
+                .flatten()
+                .collect(),
+            TemplateExpression::IfBlock {
+                body, else_body, ..
+            } => [Some(body), else_body.as_mut()]
+                .into_iter()
+                .flatten()
+                .collect(),
+            TemplateExpression::MatchBlock { arms, .. } => {
+                arms.iter_mut().map(|arm| &mut arm.body).collect()
+            }
+            TemplateExpression::CallTemplate {
+                args, named, block, ..
+            } => args
+                .iter_mut()
+                .chain(named.iter_mut().map(|(_, arg)| arg))
+                .filter_map(TemplateArgument::body_mut)
+                .chain(block.as_mut())
+                .collect(),
+            TemplateExpression::Parent {
+                blocks, args, body, ..
+            } => blocks
+                .iter_mut()
+                .map(|(_, body)| body)
+                .chain(args.iter_mut().filter_map(|(_, arg)| arg.body_mut()))
+                .chain([body])
+                .collect(),
+            TemplateExpression::Block { body, .. }
+            | TemplateExpression::WhileLoop { body, .. } => vec![body],
+            TemplateExpression::Comment
+            | TemplateExpression::Text { .. }
+            | TemplateExpression::Raw { .. }
+            | TemplateExpression::Expression { .. }
+            | TemplateExpression::Attribute { .. }
+            | TemplateExpression::Formatted { .. }
+            | TemplateExpression::Code { .. }
+            | TemplateExpression::Include { .. }
+            | TemplateExpression::Break { .. }
+            | TemplateExpression::Continue { .. } => vec![],
+        }
+    }
+}
+
+/// Check that each `@break` and `@continue` in `body` is inside a
+/// loop, given the labels of the `loops` around it.
+pub fn check_loops(
+    body: &[TemplateExpression],
+    loops: &[Option<&str>],
+) -> Result<(), String> {
+    for e in body {
+        match e {
+            TemplateExpression::Break { label, .. } => {
+                check_loop_label("break", label.as_deref(), loops)?;
+            }
+            TemplateExpression::Continue { label, .. } => {
+                check_loop_label("continue", label.as_deref(), loops)?;
+            }
+            TemplateExpression::ForLoop {
+                label,
+                body,
+                sep,
+                else_body,
+                ..
             } => {
-                writeln!(out, "if {expr} {{")?;
-                for b in body {
-                    b.write_code(out)?;
-                }
-                out.write_str("}")?;
-                match else_body.as_deref() {
-                    Some([e @ TemplateExpression::IfBlock { .. }]) => {
-                        out.write_str(" else ")?;
-                        e.write_code(out)
-                    }
-                    Some(body) => {
-                        out.write_str(" else {\n")?;
-                        for b in body {
-                            b.write_code(out)?;
-                        }
-                        out.write_str("}\n")
-                    }
-                    None => out.write_char('\n'),
+                let inner = [loops, &[label.as_deref()]].concat();
+                check_loops(body, &inner)?;
+                if let Some(sep) = sep {
+                    check_loops(sep, &inner)?;
+                }
+                if let Some(else_body) = else_body {
+                    check_loops(else_body, loops)?;
                 }
             }
-            TemplateExpression::MatchBlock { ref expr, ref arms } => {
-                write!(out, "match {expr} {{")?;
-                for (expr, body) in arms {
-                    write!(out, "\n  {expr} => {{")?;
-                    for b in body {
-                        b.write_code(out)?;
-                    }
-                    write!(out, "}}")?;
+            TemplateExpression::WhileLoop { label, body, .. } => {
+                check_loops(body, &[loops, &[label.as_deref()]].concat())?;
+            }
+            // Blocks given to other templates are closures, where no
+            // loop around them can be reached.
+            TemplateExpression::CallTemplate { .. }
+            | TemplateExpression::Parent { .. } => {
+                for body in e.bodies() {
+                    check_loops(body, &[])?;
                 }
-                writeln!(out, "\n}}")
             }
-            TemplateExpression::CallTemplate { ref name, ref args } => {
-                write!(out, "{name}(_ructe_out_.by_ref()")?;
-                for arg in args {
-                    write!(out, ", {arg}")?;
+            _ => {
+                for body in e.bodies() {
+                    check_loops(body, loops)?;
                 }
-                writeln!(out, ")?;")
             }
         }
     }
+    Ok(())
 }
 
-pub fn template_expression(input: &[u8]) -> PResult<'_, TemplateExpression> {
-    match opt(preceded(
-        char('@'),
-        alt((
-            tag("*"),
-            tag(":"),
-            tag("@"),
-            tag("{"),
-            tag("}"),
-            tag("("),
-            terminated(alt((tag("if"), tag("for"), tag("match"))), tag(" ")),
-            value(&b""[..], tag("")),
+fn check_loop_label(
+    keyword: &str,
+    label: Option<&str>,
+    loops: &[Option<&str>],
+) -> Result<(), String> {
+    match label {
+        None if loops.is_empty() => {
+            Err(format!("@{keyword} outside of a loop"))
+        }
+        Some(label) if !loops.contains(&Some(label)) => Err(format!(
+            "@{keyword} '{label} outside of a loop labeled '{label}"
         )),
-    ))
-    .parse(input)?
-    {
-        (i, Some(b":")) => map(
-            pair(
-                rust_name,
-                delimited(
-                    char('('),
-                    separated_list0(
-                        terminated(tag(","), spacelike),
-                        template_argument,
-                    ),
-                    char(')'),
-                ),
+        _ => Ok(()),
+    }
+}
+
+/// Check the nesting of html elements in `body`.
+///
+/// The branches of `@if` and `@match`, and the bodies of loops, should
+/// leave the same elements open as when they start.
+pub fn check_nesting(body: &[TemplateExpression], nesting: &mut HtmlNesting) {
+    for e in body {
+        match e {
+            TemplateExpression::Text { text, pos }
+            | TemplateExpression::Raw { text, pos } => {
+                nesting.feed(text, pos);
+            }
+            TemplateExpression::ForLoop { .. }
+            | TemplateExpression::WhileLoop { .. } => check_branches(
+                std::iter::once(&[][..]).chain(e.bodies()),
+                nesting,
             ),
-            |(name, args)| TemplateExpression::CallTemplate {
-                name: name.to_string(),
-                args,
-            },
-        )
-        .parse(i),
-        (i, Some(b"@")) => Ok((i, TemplateExpression::text("@"))),
-        (i, Some(b"{")) => Ok((i, TemplateExpression::text("{"))),
-        (i, Some(b"}")) => Ok((i, TemplateExpression::text("}"))),
-        (i, Some(b"*")) => {
-            map(comment_tail, |()| TemplateExpression::Comment).parse(i)
+            TemplateExpression::IfBlock {
+                body, else_body, ..
+            } => check_branches(
+                [body, else_body.as_deref().unwrap_or_default()],
+                nesting,
+            ),
+            TemplateExpression::MatchBlock { .. } => {
+                check_branches(e.bodies(), nesting);
+            }
+            TemplateExpression::CallTemplate { .. } => {
+                for body in e.bodies() {
+                    nesting.apart(|nesting| check_nesting(body, nesting));
+                }
+            }
+            _ => {
+                for body in e.bodies() {
+                    check_nesting(body, nesting);
+                }
+            }
         }
-        (i, Some(b"if")) => if2(i),
-        (i, Some(b"for")) => for2(i),
-        (i, Some(b"match")) => match2(i),
-        (i, Some(b"(")) => {
-            map(terminated(expr_inside_parens, tag(")")), |expr| {
-                TemplateExpression::Expression {
-                    expr: format!("({expr})"),
+    }
+}
+
+/// Check alternative `branches` of a template.
+fn check_branches<'a>(
+    branches: impl IntoIterator<Item = &'a [TemplateExpression]>,
+    nesting: &mut HtmlNesting,
+) {
+    nesting.branches(
+        branches
+            .into_iter()
+            .map(|body| move |n: &mut HtmlNesting| check_nesting(body, n)),
+    );
+}
+
+/// Escape each expression in `body` according to where in the html
+/// it is, if `escape` is true.
+///
+/// The `context` is updated for the text of `body`, starting from the
+/// given context.
+/// Each branch of an `@if` or `@match`, and each loop body, starts
+/// from the context before it, and the context after the first
+/// branch is kept.
+/// Content given to another template starts as html text by itself.
+/// The context at the start of each `@block` is added to `blocks`.
+pub fn escape_by_context(
+    body: &mut [TemplateExpression],
+    context: &mut HtmlContext,
+    escape: bool,
+    blocks: &mut Vec<(String, HtmlContext)>,
+) -> Result<(), String> {
+    for e in body {
+        match e {
+            TemplateExpression::Text { text, .. }
+            | TemplateExpression::Raw { text, .. } => context.feed(text),
+            TemplateExpression::Expression { expr, .. } => {
+                if escape {
+                    let escape = context.escape().map_err(|msg| {
+                        format!("Cannot escape @{expr}: {msg}")
+                    })?;
+                    if let Some(wrapper) = escape.wrapper() {
+                        *expr = format!("_utils::{wrapper}(&{expr})");
+                    }
                 }
-            })
-            .parse(i)
+                context.feed_expression();
+            }
+            TemplateExpression::Attribute { name, value, pos } => {
+                let attr = name.to_ascii_lowercase();
+                let escaping = Escape::of_attribute(&attr, true);
+                if escape && escaping == Escape::Js {
+                    return Err(format!(
+                        "Cannot escape @?{name}={value}: The value of an \
+                         event handler attribute is javascript code, that \
+                         can not be safely escaped"
+                    ));
+                }
+                if let Some(wrapper) = escaping.wrapper().filter(|_| escape) {
+                    *e = TemplateExpression::Expression {
+                        expr: format!(
+                            "_utils::EscapedAttr({name:?}, &{value}, \
+                             |v, o| _utils::{wrapper}(v).to_html(o))",
+                        ),
+                        pos: pos.clone(),
+                    };
+                }
+            }
+            TemplateExpression::Formatted { expr, spec, pos } => {
+                if escape {
+                    let escape = context.escape().map_err(|msg| {
+                        format!("Cannot escape @({expr}:{spec}): {msg}")
+                    })?;
+                    if let Some(wrapper) = escape.wrapper() {
+                        *e = TemplateExpression::Expression {
+                            expr: format!(
+                                "_utils::{wrapper}(&format_args!({}))",
+                                format_arguments(expr, spec),
+                            ),
+                            pos: pos.clone(),
+                        };
+                    }
+                }
+                context.feed_expression();
+            }
+            TemplateExpression::Block { name, body, .. } => {
+                if !blocks.iter().any(|(n, _)| n == name) {
+                    blocks.push((name.clone(), context.clone()));
+                }
+                escape_by_context(body, context, escape, blocks)?;
+            }
+            TemplateExpression::ForLoop { .. }
+            | TemplateExpression::WhileLoop { .. } => escape_branches(
+                std::iter::once(&mut Vec::new()).chain(e.bodies_mut()),
+                context,
+                escape,
+                blocks,
+            )?,
+            TemplateExpression::IfBlock { .. }
+            | TemplateExpression::MatchBlock { .. } => {
+                escape_branches(e.bodies_mut(), context, escape, blocks)?;
+            }
+            TemplateExpression::CallTemplate { .. } => {
+                for body in e.bodies_mut() {
+                    let context = &mut HtmlContext::default();
+                    escape_by_context(body, context, escape, blocks)?;
+                }
+            }
+            _ => {
+                for body in e.bodies_mut() {
+                    escape_by_context(body, context, escape, blocks)?;
+                }
+            }
         }
-        (i, Some(b"")) => {
-            map(expression, |expr| TemplateExpression::Expression {
-                expr: expr.to_string(),
-            })
-            .parse(i)
+    }
+    Ok(())
+}
+
+/// Escape alternative `branches` of a template, each starting from
+/// `context`, which is then the context after the first branch.
+fn escape_branches<'a>(
+    branches: impl IntoIterator<Item = &'a mut Vec<TemplateExpression>>,
+    context: &mut HtmlContext,
+    escape: bool,
+    blocks: &mut Vec<(String, HtmlContext)>,
+) -> Result<(), String> {
+    let before = context.clone();
+    for (i, body) in branches.into_iter().enumerate() {
+        let mut branch = before.clone();
+        escape_by_context(body, &mut branch, escape, blocks)?;
+        if i == 0 {
+            *context = branch;
         }
-        (_i, Some(_)) => unreachable!(),
-        (i, None) => map(map_res(is_not("@{}"), input_to_str), |text| {
-            TemplateExpression::Text {
-                text: text.to_string(),
+    }
+    Ok(())
+}
+
+/// Collapse insignificant whitespace in the text of `body`.
+///
+/// The `raw` state is the element (`pre`, `textarea` or `script`)
+/// that the text is currently inside, if any.
+/// Text inside such an element is left as is, and so is text in a
+/// quoted attribute value, as told by the html `context`.
+pub fn collapse_whitespace(
+    body: &mut [TemplateExpression],
+    raw: &mut Option<&'static str>,
+    context: &mut HtmlContext,
+) {
+    for i in 0..body.len() {
+        match &body[i] {
+            TemplateExpression::Text { text, .. } => {
+                let before = next_char(&body[..i], false, false);
+                let after = next_char(&body[i + 1..], true, false);
+                let text = collapse_text(text, before, after, raw, context);
+                if let TemplateExpression::Text { text: t, .. } = &mut body[i]
+                {
+                    *t = text;
+                }
             }
-        })
-        .parse(i),
+            TemplateExpression::Raw { text, .. } => context.feed(text),
+            _ => {}
+        }
+        for body in body[i].bodies_mut() {
+            collapse_whitespace(body, raw, context);
+        }
     }
 }
 
-fn if2(input: &[u8]) -> PResult<'_, TemplateExpression> {
-    context(
-        "Error in conditional expression:",
-        map(
-            (
-                delimited(spacelike, cond_expression, spacelike),
-                template_block,
-                opt(preceded(
-                    delimited(spacelike, tag("else"), spacelike),
-                    alt((
-                        preceded(tag("if"), map(if2, |e| vec![e])),
-                        template_block,
-                    )),
-                )),
-            ),
-            |(expr, body, else_body)| TemplateExpression::IfBlock {
-                expr,
-                body,
-                else_body,
-            },
-        ),
-    )
-    .parse(input)
+/// The character next to a text that is just before (if `after` is
+/// false) or just after `exprs`.
+///
+/// Comments are skipped, and a block such as `@if` or `@for` counts
+/// as the text its (first) body starts or ends with, ignoring the
+/// whitespace there if `trim` is true.
+/// Any other expression counts as `@`, so whitespace next to it is
+/// kept, and there is no character at the start or end of a body.
+fn next_char(
+    exprs: &[TemplateExpression],
+    after: bool,
+    trim: bool,
+) -> Option<char> {
+    let mut exprs = exprs
+        .iter()
+        .filter(|e| !matches!(e, TemplateExpression::Comment));
+    let e = if after {
+        exprs.next()
+    } else {
+        exprs.next_back()
+    };
+    match e? {
+        TemplateExpression::Text { text, .. }
+        | TemplateExpression::Raw { text, .. } => {
+            let text = match (trim, after) {
+                (false, _) => text.as_str(),
+                (true, true) => text.trim_start(),
+                (true, false) => text.trim_end(),
+            };
+            if after {
+                text.chars().next()
+            } else {
+                text.chars().next_back()
+            }
+        }
+        e @ (TemplateExpression::IfBlock { .. }
+        | TemplateExpression::MatchBlock { .. }
+        | TemplateExpression::ForLoop { .. }
+        | TemplateExpression::WhileLoop { .. }
+        | TemplateExpression::Block { .. }) => {
+            next_char(e.bodies().first()?, after, true)
+        }
+        _ => Some('@'),
+    }
 }
 
-fn for2(input: &[u8]) -> PResult<'_, TemplateExpression> {
-    map(
-        (
-            for_variable,
-            delimited(
-                terminated(context("Expected \"in\"", tag("in")), spacelike),
-                context("Expected iterable expression", loop_expression),
-                spacelike,
-            ),
-            context("Error in loop block:", template_block),
+/// Collapse each run of whitespace in `text` to a single space.
+///
+/// A run that includes a line break is removed completely when it
+/// is between two tags, or between a tag and the start or end of a
+/// body.
+/// The characters `before` and `after` the text are used at its
+/// edges, so whitespace next to an expression is kept.
+/// Quoted attribute values are left as is.
+fn collapse_text(
+    text: &str,
+    before: Option<char>,
+    after: Option<char>,
+    raw: &mut Option<&'static str>,
+    context: &mut HtmlContext,
+) -> String {
+    let mut quoted = vec![false; text.len()];
+    context.feed_each(text, |context, i| {
+        quoted[i] = context.in_quoted_value();
+    });
+    let mut result = String::with_capacity(text.len());
+    let mut chars = text.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        if quoted[i] {
+            result.push(c);
+        } else if c == '<' {
+            let tag = &text[i + 1..];
+            *raw = match *raw {
+                Some(elem) => tag
+                    .strip_prefix('/')
+                    .map_or(true, |end| !starts_with_tag(end, elem))
+                    .then_some(elem),
+                None => ["pre", "textarea", "script"]
+                    .into_iter()
+                    .find(|elem| starts_with_tag(tag, elem)),
+            };
+            result.push(c);
+        } else if c.is_whitespace() && raw.is_none() {
+            let mut newline = c == '\n';
+            while let Some((_, c)) = chars.next_if(|(_, c)| c.is_whitespace())
+            {
+                newline |= c == '\n';
+            }
+            let before = if i == 0 {
+                before
+            } else {
+                result.chars().next_back()
+            };
+            let after = chars.peek().map_or(after, |(_, c)| Some(*c));
+            if !(newline
+                && matches!(before, None | Some('>'))
+                && matches!(after, None | Some('<')))
+            {
+                result.push(' ');
+            }
+        } else {
+            result.push(c);
+        }
+    }
+    result
+}
+
+/// Merge each run of adjacent text in `body` into a single text, so
+/// that it is written at once, and remove comments and empty text.
+/// Raw blocks count as text here.
+///
+/// This is done for all bodies in `body` as well.
+pub fn coalesce_text(body: &mut Vec<TemplateExpression>) {
+    let mut result: Vec<TemplateExpression> = Vec::with_capacity(body.len());
+    for mut e in body.drain(..) {
+        if let TemplateExpression::Raw { text, pos } = e {
+            e = TemplateExpression::Text { text, pos };
+        }
+        match (&e, result.last_mut()) {
+            (TemplateExpression::Comment, _) => continue,
+            (TemplateExpression::Text { text, .. }, _) if text.is_empty() => {
+                continue
+            }
+            (
+                TemplateExpression::Text { text, .. },
+                Some(TemplateExpression::Text { text: prev, .. }),
+            ) => {
+                prev.push_str(text);
+                continue;
+            }
+            _ => {}
+        }
+        for body in e.bodies_mut() {
+            coalesce_text(body);
+        }
+        result.push(e);
+    }
+    *body = result;
+}
+
+pub fn override_blocks(body: &mut [TemplateExpression], names: &[&str]) {
+    for e in body {
+        e.override_blocks(names);
+    }
+}
+
+pub fn block_names<'a>(
+    body: &'a [TemplateExpression],
+    names: &mut Vec<&'a str>,
+) {
+    for e in body {
+        e.block_names(names);
+    }
+}
+
+pub fn locate(body: &mut [TemplateExpression], path: &Rc<str>, buf: &[u8]) {
+    for e in body {
+        e.locate(path, buf);
+    }
+}
+
+/// Change the name of each template called in `body` by `rebase`.
+pub fn rebase_calls(
+    body: &mut [TemplateExpression],
+    rebase: &impl Fn(&str) -> String,
+) {
+    for e in body {
+        if let TemplateExpression::CallTemplate { name, .. } = e {
+            *name = rebase(name);
+        }
+        for b in e.bodies_mut() {
+            rebase_calls(b, rebase);
+        }
+    }
+}
+
+/// Clear the positions in `body`, to compare parsed templates in tests.
+#[cfg(test)]
+pub fn clear_pos(body: &mut [TemplateExpression]) {
+    for e in body {
+        if let Some(pos) = e.pos_mut() {
+            *pos = Pos::default();
+        }
+        for b in e.bodies_mut() {
+            clear_pos(b);
+        }
+    }
+}
+
+pub fn size_hint(body: &[TemplateExpression]) -> usize {
+    body.iter().map(TemplateExpression::size_hint).sum()
+}
+
+/// Order the arguments of a call to the template `name` by the
+/// names of its formal arguments, `params`.
+///
+/// A trailing block is the last argument, and any argument that is
+/// not given gets its default value.
+fn place_arguments(
+    name: &str,
+    params: &[FormalArgument],
+    args: Vec<TemplateArgument>,
+    named: Vec<(String, TemplateArgument)>,
+    block: Option<Vec<TemplateExpression>>,
+) -> Result<Vec<TemplateArgument>, String> {
+    if args.len() > params.len() {
+        return Err(format!(
+            "{name} takes {} arguments, but {} were given",
+            params.len(),
+            args.len() + named.len() + usize::from(block.is_some()),
+        ));
+    }
+    let mut slots = params.iter().map(|_| None).collect::<Vec<_>>();
+    for (slot, arg) in slots.iter_mut().zip(args) {
+        *slot = Some(arg);
+    }
+    for (arg_name, arg) in named {
+        let i = params
+            .iter()
+            .position(|p| p.name == arg_name)
+            .ok_or_else(|| format!("{name} has no argument {arg_name}"))?;
+        if slots[i].replace(arg).is_some() {
+            return Err(format!("Argument {arg_name} to {name} given twice"));
+        }
+    }
+    if let Some(block) = block {
+        match slots.last_mut() {
+            Some(slot @ None) => *slot = Some(TemplateArgument::Body(block)),
+            _ => {
+                return Err(format!(
+                    "No argument left for the trailing block to {name}"
+                ))
+            }
+        }
+    }
+    slots
+        .into_iter()
+        .zip(params)
+        .map(|(arg, p)| {
+            arg.or_else(|| p.default.clone().map(TemplateArgument::Rust))
+                .ok_or_else(|| {
+                    format!("Missing argument {} to {name}", p.name)
+                })
+        })
+        .collect()
+}
+
+/// Write the body of an extended template.
+///
+/// The overriding blocks are written as closures before the
+/// arguments of the parent are bound, so the blocks see the
+/// arguments of the extending template, even if the parent has
+/// arguments with the same names.
+fn write_parent(
+    out: &mut impl Write,
+    blocks: &[(String, Vec<TemplateExpression>)],
+    args: &[(FormalArgument, TemplateArgument)],
+    body: &[TemplateExpression],
+    flavor: Flavor,
+) -> fmt::Result {
+    for (name, body) in blocks {
+        write_closure(out, &format!("_ructe_block_{name}"), body, flavor)?;
+    }
+    out.write_str("{\n")?;
+    for (FormalArgument { name, ty, .. }, value) in args {
+        match value {
+            TemplateArgument::Rust(expr) if expr == name => {}
+            TemplateArgument::Rust(expr) if ty == "Content" => {
+                writeln!(out, "let {name} = {expr};")?;
+            }
+            TemplateArgument::Rust(expr) => {
+                writeln!(out, "let {name}: {ty} = {expr};")?;
+            }
+            TemplateArgument::Body(body) => {
+                write_closure(out, name, body, flavor)?;
+            }
+        }
+    }
+    if let Flavor::Async(content) = flavor {
+        let content = content
+            .iter()
+            .cloned()
+            .chain(
+                blocks
+                    .iter()
+                    .map(|(name, _)| format!("_ructe_block_{name}")),
+            )
+            .chain(
+                args.iter()
+                    .filter(|(arg, _)| arg.ty == "Content")
+                    .map(|(arg, _)| arg.name.clone()),
+            )
+            .collect::<Vec<_>>();
+        write_body(out, body, Flavor::Async(&content))?;
+    } else {
+        write_body(out, body, flavor)?;
+    }
+    out.write_str("}\n")
+}
+
+/// Write a local closure for a block of an extended template.
+fn write_closure(
+    out: &mut impl Write,
+    name: &str,
+    body: &[TemplateExpression],
+    flavor: Flavor,
+) -> fmt::Result {
+    match flavor {
+        Flavor::Sync | Flavor::Fmt => writeln!(
+            out,
+            "let {name} = |_ructe_out_: &mut W| -> {} {{",
+            flavor.result(),
+        )?,
+        Flavor::Async(_) => writeln!(
+            out,
+            "let {name} = |_ructe_out_: W| async {{\n\
+             let mut _ructe_out_ = _ructe_out_;"
+        )?,
+    }
+    write_body(out, body, flavor)?;
+    match flavor {
+        Flavor::Sync | Flavor::Fmt => out.write_str("Ok(())\n};\n"),
+        Flavor::Async(_) => {
+            out.write_str("Ok::<_, io::Error>(_ructe_out_)\n};\n")
+        }
+    }
+}
+
+/// Write a call to another template.
+///
+/// Any `named` arguments should already be put in place by
+/// [`TemplateExpression::resolve_calls`].
+///
+/// An async call gives the output to the called template or content
+/// closure, and gets it back when done.
+fn write_call(
+    out: &mut impl Write,
+    name: &str,
+    args: &[TemplateArgument],
+    named: &[(String, TemplateArgument)],
+    block: Option<&[TemplateExpression]>,
+    flavor: Flavor,
+) -> fmt::Result {
+    match flavor {
+        Flavor::Sync | Flavor::Fmt => {
+            write!(out, "{name}({}", flavor.out())?;
+        }
+        Flavor::Async(content) if content.iter().any(|c| c == name) => {
+            write!(out, "_ructe_out_ = {name}(_ructe_out_")?;
+        }
+        Flavor::Async(_) => {
+            write!(out, "_ructe_out_ = {name}_async(_ructe_out_")?;
+        }
+    }
+    for arg in args.iter().chain(named.iter().map(|(_, arg)| arg)) {
+        out.write_str(", ")?;
+        arg.write(out, flavor)?;
+    }
+    if let Some(block) = block {
+        out.write_str(", ")?;
+        write_content(out, block, flavor)?;
+    }
+    writeln!(out, "){}?;", flavor.wait())
+}
+
+/// Write some literal text.
+fn write_text(
+    out: &mut impl Write,
+    text: &str,
+    flavor: Flavor,
+) -> fmt::Result {
+    let wait = flavor.wait();
+    if text.is_empty() {
+        Ok(())
+    } else if let Flavor::Fmt = flavor {
+        writeln!(out, "_ructe_out_.write_str({text:?})?;")
+    } else if text.is_ascii() {
+        writeln!(out, "_ructe_out_.write_all(b{text:?}){wait}?;")
+    } else {
+        writeln!(out, "_ructe_out_.write_all({text:?}.as_bytes()){wait}?;")
+    }
+}
+
+/// Write the value of `expr` with a format `spec`.
+fn write_formatted(
+    out: &mut impl Write,
+    expr: &str,
+    spec: &str,
+    flavor: Flavor,
+) -> fmt::Result {
+    let args = format_arguments(expr, spec);
+    match flavor {
+        Flavor::Async(_) => {
+            write_async_html(out, &format!("format_args!({args})"))
+        }
+        _ => writeln!(
+            out,
+            "write!(_utils::ToHtmlEscapingWriter({}), {args})?;",
+            flavor.out(),
         ),
-        |(name, expr, body)| TemplateExpression::ForLoop { name, expr, body },
+    }
+}
+
+/// Write the value of `expr` as html.
+fn write_html(
+    out: &mut impl Write,
+    expr: &str,
+    flavor: Flavor,
+) -> fmt::Result {
+    match flavor {
+        Flavor::Async(_) => write_async_html(out, expr),
+        _ => writeln!(out, "{expr}.to_html({})?;", flavor.out()),
+    }
+}
+
+/// Write the value of `expr` as html to an async output.
+///
+/// The value is written to a buffer first, since a `ToHtml`
+/// implementation can only write synchronously.
+fn write_async_html(out: &mut impl Write, expr: &str) -> fmt::Result {
+    writeln!(
+        out,
+        "{{\nlet _ructe_html_ = {expr}.to_buffer()?;\n\
+         _ructe_out_.write_all(_ructe_html_.as_ref()).await?;\n}}"
     )
-    .parse(input)
 }
 
-fn for_variable(input: &[u8]) -> PResult<'_, String> {
-    delimited(
-        spacelike,
-        context(
-            "Expected loop variable name or destructuring tuple",
+/// The arguments to `format_args!` for `expr` with a format `spec`.
+fn format_arguments(expr: &str, spec: &str) -> String {
+    format!("{:?}, {expr}", format!("{{:{spec}}}"))
+}
+
+/// Write a `@match` block.
+fn write_match(
+    out: &mut impl Write,
+    expr: &str,
+    arms: &[MatchArm],
+    flavor: Flavor,
+) -> fmt::Result {
+    write!(out, "match {expr} {{")?;
+    for MatchArm {
+        pattern,
+        guard,
+        body,
+    } in arms
+    {
+        write!(out, "\n  {pattern}")?;
+        if let Some(guard) = guard {
+            write!(out, " if {guard}")?;
+        }
+        writeln!(out, " => {{")?;
+        write_body(out, body, flavor)?;
+        write!(out, "}}")?;
+    }
+    writeln!(out, "\n}}")
+}
+
+/// Write a `@for` loop.
+///
+/// A loop with loop info or a separator keeps count of the items that
+/// have written any output, so that an item that is skipped (by
+/// `@continue` before any output) does not count.
+/// That count is the index in the loop info, and the separator is
+/// written just before the first output of each item after the
+/// first counted one.
+/// A loop with an `else` body keeps track of whether it had any
+/// items at all.
+#[allow(clippy::too_many_arguments)]
+fn write_for(
+    out: &mut impl Write,
+    label: Option<&str>,
+    name: &str,
+    expr: &str,
+    info: Option<&str>,
+    body: &[TemplateExpression],
+    sep: Option<&[TemplateExpression]>,
+    else_body: Option<&[TemplateExpression]>,
+    flavor: Flavor,
+) -> fmt::Result {
+    if info.is_none() && sep.is_none() && else_body.is_none() {
+        let head = format!("for {name} in {expr}");
+        return write_loop(out, label, &head, body, flavor);
+    }
+    // Nested loops need other names for their counts, since the checks
+    // of this loop may be inside them.
+    let depth = counted_depth(body);
+    let count = format!("_ructe_count{depth}_");
+    let fresh = format!("_ructe_fresh{depth}_");
+    out.write_str("{\n")?;
+    if else_body.is_some() {
+        out.write_str("let mut _ructe_empty_ = true;\n")?;
+    }
+    let track = info.is_some() || sep.is_some();
+    if track {
+        writeln!(out, "#[allow(unused_mut)] let mut {count}: usize = 0;")?;
+    }
+    if let Some(info) = info {
+        writeln!(
+            out,
+            "let mut _ructe_iter_ = \
+             IntoIterator::into_iter({expr}).peekable();"
+        )?;
+        write_label(out, label)?;
+        writeln!(
+            out,
+            "while let Some({name}) = _ructe_iter_.next() {{\n\
+             let {info} = _utils::LoopInfo {{\n\
+             index: {count},\n\
+             first: {count} == 0,\n\
+             last: _ructe_iter_.peek().is_none(),\n\
+             }};"
+        )?;
+    } else {
+        write_label(out, label)?;
+        writeln!(out, "for {name} in {expr} {{")?;
+    }
+    if else_body.is_some() {
+        out.write_str("_ructe_empty_ = false;\n")?;
+    }
+    if track {
+        writeln!(out, "#[allow(unused_mut)] let mut {fresh} = true;")?;
+        let mut first_output = vec![TemplateExpression::Code {
+            code: format!("{fresh} = false;"),
+            pos: Pos::default(),
+        }];
+        if let Some(sep) = sep {
+            first_output.push(TemplateExpression::IfBlock {
+                expr: format!("{count} > 0"),
+                body: sep.to_vec(),
+                else_body: None,
+                pos: Pos::default(),
+            });
+        }
+        first_output.push(TemplateExpression::Code {
+            code: format!("{count} += 1;"),
+            pos: Pos::default(),
+        });
+        let check = [TemplateExpression::IfBlock {
+            expr: fresh,
+            body: first_output,
+            else_body: None,
+            pos: Pos::default(),
+        }];
+        let mut body = body.to_vec();
+        before_output(&mut body, &check);
+        write_body(out, &body, flavor)?;
+    } else {
+        write_body(out, body, flavor)?;
+    }
+    out.write_str("}\n")?;
+    if let Some(else_body) = else_body {
+        out.write_str("if _ructe_empty_ {\n")?;
+        write_body(out, else_body, flavor)?;
+        out.write_str("}\n")?;
+    }
+    out.write_str("}\n")
+}
+
+/// The number of nested `@for` loops in `body` that keep track of
+/// their output.
+fn counted_depth(body: &[TemplateExpression]) -> usize {
+    body.iter()
+        .map(|e| {
+            let inner = e.bodies().into_iter().map(counted_depth).max();
+            match e {
+                TemplateExpression::ForLoop { info, sep, .. }
+                    if info.is_some() || sep.is_some() =>
+                {
+                    inner.unwrap_or(0) + 1
+                }
+                _ => inner.unwrap_or(0),
+            }
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+/// Put a copy of `check` before each expression in `body` that may be
+/// the first one to write output, and return true if `body` surely
+/// writes output.
+///
+/// The bodies of branches and loops are handled as well, but not
+/// content given to another template.
+fn before_output(
+    body: &mut Vec<TemplateExpression>,
+    check: &[TemplateExpression],
+) -> bool {
+    for i in 0..body.len() {
+        match &mut body[i] {
+            TemplateExpression::Comment
+            | TemplateExpression::Code { .. }
+            | TemplateExpression::Break { .. }
+            | TemplateExpression::Continue { .. } => {}
+            TemplateExpression::Text { text, .. }
+            | TemplateExpression::Raw { text, .. }
+                if text.is_empty() => {}
+            TemplateExpression::Block { body, .. } => {
+                if before_output(body, check) {
+                    return true;
+                }
+            }
+            e @ (TemplateExpression::IfBlock { .. }
+            | TemplateExpression::MatchBlock { .. }
+            | TemplateExpression::ForLoop { .. }
+            | TemplateExpression::WhileLoop { .. }) => {
+                for body in e.bodies_mut() {
+                    before_output(body, check);
+                }
+            }
+            _ => {
+                body.splice(i..i, check.iter().cloned());
+                return true;
+            }
+        }
+    }
+    false
+}
+
+/// Write the `label` of a loop, if any.
+fn write_label(out: &mut impl Write, label: Option<&str>) -> fmt::Result {
+    match label {
+        Some(label) => write!(out, "'{label}: "),
+        None => Ok(()),
+    }
+}
+
+/// Write an `@if` block, with an optional `else` block.
+fn write_if(
+    out: &mut impl Write,
+    expr: &str,
+    body: &[TemplateExpression],
+    else_body: Option<&[TemplateExpression]>,
+    flavor: Flavor,
+) -> fmt::Result {
+    writeln!(out, "if {expr} {{")?;
+    write_body(out, body, flavor)?;
+    out.write_str("}")?;
+    match else_body {
+        Some([e @ TemplateExpression::IfBlock { .. }]) => {
+            out.write_str(" else\n")?;
+            e.write_code(out, flavor)
+        }
+        Some(body) => {
+            out.write_str(" else {\n")?;
+            write_body(out, body, flavor)?;
+            out.write_str("}\n")
+        }
+        None => out.write_char('\n'),
+    }
+}
+
+/// Write a loop with a `head` such as `while x`, and an optional
+/// `label`.
+fn write_loop(
+    out: &mut impl Write,
+    label: Option<&str>,
+    head: &str,
+    body: &[TemplateExpression],
+    flavor: Flavor,
+) -> fmt::Result {
+    write_label(out, label)?;
+    writeln!(out, "{head} {{")?;
+    write_body(out, body, flavor)?;
+    out.write_str("}\n")
+}
+
+/// Write a `break` or `continue` `keyword`, to a `label` if any.
+fn write_jump(
+    out: &mut impl Write,
+    keyword: &str,
+    label: Option<&str>,
+) -> fmt::Result {
+    match label {
+        Some(label) => writeln!(out, "{keyword} '{label};"),
+        None => writeln!(out, "{keyword};"),
+    }
+}
+
+fn write_body(
+    out: &mut impl Write,
+    body: &[TemplateExpression],
+    flavor: Flavor,
+) -> fmt::Result {
+    for b in body {
+        b.write_code(out, flavor)?;
+    }
+    Ok(())
+}
+
+pub fn template_expression(input: &[u8]) -> PResult<'_, TemplateExpression> {
+    let (rest, mut expression) = expression_here(input)?;
+    if let Some(pos) = expression.pos_mut() {
+        *pos = Pos::of(input);
+    }
+    Ok((rest, expression))
+}
+
+/// A template expression, without its position.
+fn expression_here(input: &[u8]) -> PResult<'_, TemplateExpression> {
+    match opt(preceded(
+        char('@'),
+        alt((
+            tag("*"),
+            tag("~"),
+            tag("?"),
+            tag(":"),
+            tag("@"),
+            // A char literal is an expression, not a loop label.
+            terminated(tag("'"), peek((rust_name, char(':')))),
+            tag("{{"),
+            tag("{"),
+            tag("}"),
+            tag("("),
+            keyword,
+            value(&b""[..], tag("")),
+        )),
+    ))
+    .parse(input)?
+    {
+        (i, Some(b":")) => call_template(i),
+        (i, Some(b"@")) => {
+            Ok((i, TemplateExpression::text_at("@", Pos::of(input))))
+        }
+        (i, Some(b"{{")) => code_block(i),
+        (i, Some(b"{")) => {
+            Ok((i, TemplateExpression::text_at("{", Pos::of(input))))
+        }
+        (i, Some(b"}")) => {
+            Ok((i, TemplateExpression::text_at("}", Pos::of(input))))
+        }
+        (i, Some(b"*")) => {
+            map(comment_tail, |()| TemplateExpression::Comment).parse(i)
+        }
+        (i, Some(b"?")) => attribute(i),
+        (i, Some(b"~")) => {
+            map(multispace0, |_| TemplateExpression::Comment).parse(i)
+        }
+        (i, Some(b"if")) => if2(i),
+        (i, Some(b"for")) => for2(i),
+        (i, Some(b"while")) => while2(i),
+        (i, Some(b"match")) => match2(i),
+        (i, Some(b"let")) => let2(i),
+        (i, Some(b"block")) => block2(i),
+        (i, Some(b"include_raw")) => include(i, false),
+        (i, Some(b"include_escaped")) => include(i, true),
+        (i, Some(b"raw")) => raw_block(i),
+        (i, Some(b"'")) => labeled_loop(i),
+        (i, Some(b"break")) => {
+            map(loop_label, |label| TemplateExpression::Break {
+                label,
+                pos: Pos::default(),
+            })
+            .parse(i)
+        }
+        (i, Some(b"continue")) => {
+            map(loop_label, |label| TemplateExpression::Continue {
+                label,
+                pos: Pos::default(),
+            })
+            .parse(i)
+        }
+        (i, Some(b"(")) => paren_expression(i),
+        (i, Some(b"")) => {
+            map(expression, |expr| TemplateExpression::Expression {
+                expr: expr.to_string(),
+                pos: Pos::default(),
+            })
+            .parse(i)
+        }
+        (_i, Some(_)) => unreachable!(),
+        (input, None) => {
+            let (i, text) =
+                map_res(is_not("@{}"), input_to_str).parse(input)?;
+            let text = if i.starts_with(b"@~") {
+                text.trim_end()
+            } else {
+                text
+            };
+            Ok((i, TemplateExpression::text_at(text, Pos::of(input))))
+        }
+    }
+}
+
+/// The keyword of a directive such as `@if` or `@include_raw(...)`.
+fn keyword(input: &[u8]) -> PResult<'_, &[u8]> {
+    alt((
+        terminated(
+            alt((tag("include_raw"), tag("include_escaped"))),
+            peek(char('(')),
+        ),
+        terminated(tag("raw"), peek(char('#'))),
+        terminated(
+            alt((tag("break"), tag("continue"))),
+            not(satisfy(|c: char| c.is_alphanumeric() || c == '_')),
+        ),
+        terminated(
             alt((
-                map(
-                    map_res(
-                        recognize(preceded(rust_name, opt(expr_in_braces))),
-                        input_to_str,
-                    ),
-                    String::from,
-                ),
-                map(
-                    pair(
-                        opt(char('&')),
-                        delimited(char('('), comma_expressions, char(')')),
-                    ),
-                    |(pre, args)| {
-                        format!("{}({})", pre.map_or("", |_| "&"), args)
-                    },
-                ),
+                tag("if"),
+                tag("for"),
+                tag("while"),
+                tag("match"),
+                tag("let"),
             )),
+            multispace1,
         ),
-        spacelike,
-    )
+        // A variable may be named block as well.
+        terminated(
+            tag("block"),
+            peek((multispace1, spacelike, rust_name, spacelike, char('{'))),
+        ),
+    ))
     .parse(input)
 }
 
-fn match2(input: &[u8]) -> PResult<'_, TemplateExpression> {
-    context(
-        "Error in match expression:",
+/// An expression in parenthesis, optionally with a format spec.
+///
+/// A single `:` outside of any brackets starts a format spec.
+fn paren_expression(input: &[u8]) -> PResult<'_, TemplateExpression> {
+    alt((
         map(
-            (
-                delimited(spacelike, expression, spacelike),
-                preceded(
-                    char('{'),
-                    map(
-                        many_till(
-                            context(
-                                "Error in match arm starting here:",
-                                pair(
-                                    delimited(
-                                        spacelike,
-                                        map(expression, String::from),
-                                        spacelike,
-                                    ),
-                                    preceded(
-                                        terminated(tag("=>"), spacelike),
-                                        template_block,
-                                    ),
-                                ),
-                            ),
-                            preceded(spacelike, char('}')),
-                        ),
-                        |(arms, _end)| arms,
-                    ),
-                ),
+            terminated(
+                separated_pair(expr_before_colon, char(':'), format_spec),
+                cut(context("Invalid format spec", char(')'))),
             ),
-            |(expr, arms)| TemplateExpression::MatchBlock {
-                expr: expr.to_string(),
-                arms,
+            |(expr, spec)| TemplateExpression::Formatted {
+                expr: expr.trim().into(),
+                spec: spec.into(),
+                pos: Pos::default(),
             },
         ),
+        map(terminated(expr_inside_parens, tag(")")), |expr| {
+            TemplateExpression::Expression {
+                expr: format!("({expr})"),
+                pos: Pos::default(),
+            }
+        }),
+    ))
+    .parse(input)
+}
+
+/// A format spec, as in `{:spec}` in the [`format!`] macro.
+///
+/// Width and precision can be given as numbers or as `name$`, but not
+/// as `*`, since the value is the only argument.
+/// Every part is optional, so this parser only stops where the spec
+/// stops being valid.
+fn format_spec(input: &[u8]) -> PResult<'_, &str> {
+    let count = || alt((digit1, recognize(terminated(rust_name, char('$')))));
+    map_res(
+        recognize((
+            opt(alt((
+                recognize(pair(none_of("{}"), one_of("<^>"))),
+                recognize(one_of("<^>")),
+            ))),
+            opt(one_of("+-")),
+            opt(char('#')),
+            opt(char('0')),
+            opt(count()),
+            opt(pair(char('.'), count())),
+            opt(alt((
+                tag("x?"),
+                tag("X?"),
+                tag("?"),
+                recognize(one_of("xXobeEp")),
+            ))),
+        )),
+        input_to_str,
     )
     .parse(input)
 }
 
-fn template_block(input: &[u8]) -> PResult<'_, Vec<TemplateExpression>> {
-    preceded(
-        char('{'),
+fn include(input: &[u8], escaped: bool) -> PResult<'_, TemplateExpression> {
+    context(
+        "Error in include:",
         map(
-            many_till(
-                context(
-                    "Error in expression starting here:",
-                    template_expression,
+            cut(delimited(
+                tag("("),
+                delimited(
+                    context("Expected quoted file name", char('"')),
+                    map_res(is_not("\""), input_to_str),
+                    char('"'),
                 ),
-                char('}'),
-            ),
-            |(block, _end)| block,
+                context("Expected ')'", tag(")")),
+            )),
+            |path| TemplateExpression::Include {
+                path: path.into(),
+                escaped,
+                pos: Pos::default(),
+            },
         ),
     )
     .parse(input)
 }
 
-fn template_argument(input: &[u8]) -> PResult<'_, TemplateArgument> {
-    alt((
-        map(
-            delimited(
-                char('{'),
-                many0(template_expression),
-                terminated(char('}'), spacelike),
+/// An optional attribute, `@?name=value`.
+///
+/// The value is an expression or a list of class names.
+fn attribute(input: &[u8]) -> PResult<'_, TemplateExpression> {
+    context(
+        "Error in attribute:",
+        cut(map(
+            separated_pair(
+                map_res(
+                    take_while1(|c: u8| {
+                        c.is_ascii_alphanumeric() || matches!(c, b'-' | b'_')
+                    }),
+                    input_to_str,
+                ),
+                char('='),
+                alt((class_list, map(expression, String::from))),
             ),
-            TemplateArgument::Body,
+            |(name, value)| TemplateExpression::Attribute {
+                name: name.to_string(),
+                value,
+                pos: Pos::default(),
+            },
+        )),
+    )
+    .parse(input)
+}
+
+/// A list of class names, `["a", "b" if x]`, as a `ClassList`.
+fn class_list(input: &[u8]) -> PResult<'_, String> {
+    map(
+        delimited(
+            terminated(char('['), multispace0),
+            separated_list0(
+                delimited(multispace0, char(','), multispace0),
+                pair(
+                    expression,
+                    opt(preceded(
+                        delimited(multispace1, tag("if"), multispace1),
+                        logic_expression,
+                    )),
+                ),
+            ),
+            (multispace0, opt(char(',')), multispace0, char(']')),
         ),
-        map(map(expression, String::from), TemplateArgument::Rust),
-    ))
+        |classes| {
+            let classes = classes
+                .iter()
+                .map(|(class, cond)| {
+                    format!("(&{class}, {})", cond.unwrap_or("true"))
+                })
+                .collect::<Vec<_>>();
+            format!("_utils::ClassList(&[{}])", classes.join(", "))
+        },
+    )
     .parse(input)
 }
 
-fn cond_expression(input: &[u8]) -> PResult<'_, String> {
-    match opt(tag("let")).parse(input)? {
-        (i, Some(b"let")) => map(
+/// A `@raw#{ ... }#` block, written as is.
+///
+/// Any number of `#` can be used, as long as the end marker has as
+/// many as the start.
+fn raw_block(input: &[u8]) -> PResult<'_, TemplateExpression> {
+    let (i, hashes) = terminated(is_a("#"), char('{')).parse(input)?;
+    let end = || pair(char('}'), tag(hashes));
+    context(
+        "Expected end of raw block",
+        cut(map(
+            terminated(
+                map_res(
+                    recognize(many_till(take(1usize), peek(end()))),
+                    input_to_str,
+                ),
+                end(),
+            ),
+            |text| TemplateExpression::Raw {
+                text: text.to_string(),
+                pos: Pos::of(i),
+            },
+        )),
+    )
+    .parse(i)
+}
+
+/// A loop with a label, `@'name: for ...` or `@'name: while ...`.
+fn labeled_loop(input: &[u8]) -> PResult<'_, TemplateExpression> {
+    let (i, name) =
+        terminated(rust_name, (char(':'), spacelike)).parse(input)?;
+    context(
+        "Expected for or while loop after label",
+        cut(map(
+            alt((
+                preceded(tag("for "), for2),
+                preceded(tag("while "), while2),
+            )),
+            |mut e| {
+                if let TemplateExpression::ForLoop { label, .. }
+                | TemplateExpression::WhileLoop { label, .. } = &mut e
+                {
+                    *label = Some(name.into());
+                }
+                e
+            },
+        )),
+    )
+    .parse(i)
+}
+
+/// The label of a `@break` or `@continue`, if any.
+fn loop_label(input: &[u8]) -> PResult<'_, Option<String>> {
+    opt(map(preceded(tag(" '"), rust_name), String::from)).parse(input)
+}
+
+fn if2(input: &[u8]) -> PResult<'_, TemplateExpression> {
+    context(
+        "Error in conditional expression:",
+        map(
+            (
+                delimited(spacelike, cond_expression, spacelike),
+                template_block,
+                opt(preceded(
+                    delimited(spacelike, tag("else"), spacelike),
+                    alt((
+                        preceded(tag("if"), map(if2, |e| vec![e])),
+                        template_block,
+                    )),
+                )),
+            ),
+            |(expr, body, else_body)| TemplateExpression::IfBlock {
+                expr,
+                body,
+                else_body,
+                pos: Pos::default(),
+            },
+        ),
+    )
+    .parse(input)
+}
+
+fn for2(input: &[u8]) -> PResult<'_, TemplateExpression> {
+    map(
+        (
+            for_variable,
+            delimited(
+                terminated(context("Expected \"in\"", tag("in")), spacelike),
+                context("Expected iterable expression", loop_expression),
+                spacelike,
+            ),
+            opt(delimited(
+                terminated(tag("with "), spacelike),
+                context("Expected loop info variable name", rust_name),
+                spacelike,
+            )),
+            context("Error in loop block:", template_block),
+            opt(preceded(
+                delimited(spacelike, tag("@sep"), spacelike),
+                context("Error in loop separator block:", template_block),
+            )),
+            opt(preceded(
+                delimited(spacelike, tag("else"), spacelike),
+                context("Error in loop else block:", template_block),
+            )),
+        ),
+        |(name, expr, info, body, sep, else_body)| {
+            TemplateExpression::ForLoop {
+                label: None,
+                name,
+                expr,
+                info: info.map(String::from),
+                body,
+                sep,
+                else_body,
+                pos: Pos::default(),
+            }
+        },
+    )
+    .parse(input)
+}
+
+fn while2(input: &[u8]) -> PResult<'_, TemplateExpression> {
+    context(
+        "Error in while loop:",
+        map(
             pair(
-                preceded(
-                    spacelike,
-                    context(
-                        "Expected LHS expression in let binding",
-                        expression,
+                delimited(spacelike, cond_expression, spacelike),
+                template_block,
+            ),
+            |(expr, body)| TemplateExpression::WhileLoop {
+                label: None,
+                expr,
+                body,
+                pos: Pos::default(),
+            },
+        ),
+    )
+    .parse(input)
+}
+
+fn for_variable(input: &[u8]) -> PResult<'_, String> {
+    delimited(
+        spacelike,
+        context(
+            "Expected loop variable name or destructuring tuple",
+            alt((
+                map(
+                    map_res(
+                        recognize(preceded(rust_name, opt(expr_in_braces))),
+                        input_to_str,
+                    ),
+                    String::from,
+                ),
+                map(
+                    pair(
+                        opt(char('&')),
+                        delimited(char('('), comma_expressions, char(')')),
                     ),
+                    |(pre, args)| {
+                        format!("{}({})", pre.map_or("", |_| "&"), args)
+                    },
                 ),
+            )),
+        ),
+        spacelike,
+    )
+    .parse(input)
+}
+
+fn match2(input: &[u8]) -> PResult<'_, TemplateExpression> {
+    context(
+        "Error in match expression:",
+        map(
+            (
+                delimited(spacelike, expression, spacelike),
                 preceded(
-                    delimited(spacelike, char('='), spacelike),
-                    context(
-                        "Expected RHS expression in let binding",
-                        expression,
+                    char('{'),
+                    map(
+                        many_till(
+                            context(
+                                "Error in match arm starting here:",
+                                match_arm,
+                            ),
+                            preceded(spacelike, char('}')),
+                        ),
+                        |(arms, _end)| arms,
                     ),
                 ),
             ),
-            |(lhs, rhs)| format!("let {lhs} = {rhs}"),
-        )
-        .parse(i),
-        (_i, Some(_)) => unreachable!(),
-        (i, None) => map(
-            context("Expected expression", logic_expression),
-            String::from,
-        )
-        .parse(i),
-    }
+            |(expr, arms)| TemplateExpression::MatchBlock {
+                expr: expr.to_string(),
+                arms,
+                pos: Pos::default(),
+            },
+        ),
+    )
+    .parse(input)
 }
 
-fn loop_expression(input: &[u8]) -> PResult<'_, String> {
+fn match_arm(input: &[u8]) -> PResult<'_, MatchArm> {
     map(
-        map_res(
-            recognize(terminated(
-                expression,
-                opt(preceded(
-                    terminated(tag(".."), opt(char('='))),
-                    expression,
-                )),
+        (
+            delimited(spacelike, map(pattern, String::from), spacelike),
+            opt(delimited(
+                terminated(
+                    tag("if"),
+                    not(satisfy(|c| c.is_alphanumeric() || c == '_')),
+                ),
+                delimited(
+                    spacelike,
+                    context("Expected guard expression", logic_expression),
+                    spacelike,
+                ),
+                spacelike,
             )),
-            input_to_str,
+            preceded(
+                terminated(context("Expected \"=>\"", tag("=>")), spacelike),
+                template_block,
+            ),
         ),
-        String::from,
+        |(pattern, guard, body)| MatchArm {
+            pattern,
+            guard: guard.map(String::from),
+            body,
+        },
     )
     .parse(input)
 }
 
-fn logic_expression(input: &[u8]) -> PResult<'_, &str> {
-    map_res(
-        recognize((
-            opt(terminated(char('!'), spacelike)),
-            expression,
-            opt(pair(
-                rel_operator,
-                context("Expected expression", logic_expression),
-            )),
-        )),
-        input_to_str,
+fn let2(input: &[u8]) -> PResult<'_, TemplateExpression> {
+    context(
+        "Error in let binding:",
+        map(
+            terminated(
+                map_res(
+                    recognize((
+                        preceded(
+                            spacelike,
+                            context(
+                                "Expected pattern in let binding",
+                                preceded(
+                                    opt(terminated(tag("mut"), multispace1)),
+                                    expression,
+                                ),
+                            ),
+                        ),
+                        opt(preceded(
+                            delimited(spacelike, char(':'), spacelike),
+                            context(
+                                "Expected type in let binding",
+                                type_expression,
+                            ),
+                        )),
+                        preceded(
+                            delimited(spacelike, char('='), spacelike),
+                            context(
+                                "Expected RHS expression in let binding",
+                                expr_before_semicolon,
+                            ),
+                        ),
+                    )),
+                    input_to_str,
+                ),
+                char(';'),
+            ),
+            |binding| TemplateExpression::Code {
+                code: format!("let {};", binding.trim()),
+                pos: Pos::default(),
+            },
+        ),
     )
     .parse(input)
 }
 
-fn rel_operator(input: &[u8]) -> PResult<'_, &str> {
-    map_res(
-        delimited(
-            spacelike,
-            context(
-                "Expected relational operator",
-                alt((
-                    tag("!="),
-                    tag("&&"),
-                    tag("<="),
-                    tag("<"),
-                    tag("=="),
-                    tag(">="),
-                    tag(">"),
-                    tag("||"),
-                )),
+/// The name and default body of a `@block`, after the keyword.
+pub fn block2(input: &[u8]) -> PResult<'_, TemplateExpression> {
+    context(
+        "Error in block:",
+        map(
+            pair(
+                delimited(
+                    spacelike,
+                    context("Expected block name", rust_name),
+                    spacelike,
+                ),
+                template_block,
             ),
-            spacelike,
+            |(name, body)| TemplateExpression::Block {
+                name: name.to_string(),
+                body,
+                pos: Pos::default(),
+            },
         ),
-        input_to_str,
     )
     .parse(input)
 }
 
-#[cfg(test)]
-mod test {
-    use super::super::parseresult::show_errors;
-    use super::*;
+fn code_block(input: &[u8]) -> PResult<'_, TemplateExpression> {
+    context(
+        "Error in code block:",
+        map(terminated(expr_inside_parens, tag("}}")), |code| {
+            TemplateExpression::Code {
+                code: code.trim().to_string(),
+                pos: Pos::default(),
+            }
+        }),
+    )
+    .parse(input)
+}
+
+fn call_template(input: &[u8]) -> PResult<'_, TemplateExpression> {
+    map(
+        (
+            map_res(
+                recognize(separated_list1(tag("::"), rust_name)),
+                input_to_str,
+            ),
+            delimited(
+                char('('),
+                call_arguments,
+                context("expected ',' or ')'.", char(')')),
+            ),
+            opt(preceded(
+                pair(spacelike, peek(char('{'))),
+                cut(context("Error in trailing block:", template_block)),
+            )),
+        ),
+        |(name, (args, named), block)| TemplateExpression::CallTemplate {
+            name: name.to_string(),
+            args,
+            named,
+            block,
+            pos: Pos::default(),
+        },
+    )
+    .parse(input)
+}
+
+/// Positional arguments followed by `name = value` arguments.
+#[allow(clippy::type_complexity)]
+fn call_arguments(
+    input: &[u8],
+) -> PResult<'_, (Vec<TemplateArgument>, Vec<(String, TemplateArgument)>)> {
+    let comma = || terminated(tag(","), spacelike);
+    let named = || {
+        separated_list1(
+            comma(),
+            pair(
+                map(terminated(rust_name, argument_name_end), String::from),
+                template_argument,
+            ),
+        )
+    };
+    alt((
+        map(named(), |named| (vec![], named)),
+        pair(
+            separated_list0(
+                comma(),
+                preceded(
+                    not(pair(rust_name, argument_name_end)),
+                    template_argument,
+                ),
+            ),
+            map(opt(preceded(comma(), named())), Option::unwrap_or_default),
+        ),
+    ))
+    .parse(input)
+}
+
+fn argument_name_end(input: &[u8]) -> PResult<'_, ()> {
+    value((), (spacelike, char('='), not(char('=')), spacelike)).parse(input)
+}
+
+pub fn template_block(input: &[u8]) -> PResult<'_, Vec<TemplateExpression>> {
+    preceded(
+        char('{'),
+        map(
+            many_till(
+                context(
+                    "Error in expression starting here:",
+                    template_expression,
+                ),
+                char('}'),
+            ),
+            |(block, _end)| block,
+        ),
+    )
+    .parse(input)
+}
+
+pub fn template_argument(input: &[u8]) -> PResult<'_, TemplateArgument> {
+    alt((
+        map(
+            delimited(
+                char('{'),
+                many0(template_expression),
+                terminated(char('}'), spacelike),
+            ),
+            TemplateArgument::Body,
+        ),
+        map(map(expression, String::from), TemplateArgument::Rust),
+    ))
+    .parse(input)
+}
+
+fn cond_expression(input: &[u8]) -> PResult<'_, String> {
+    match opt(tag("let")).parse(input)? {
+        (i, Some(b"let")) => map(
+            pair(
+                preceded(
+                    spacelike,
+                    context(
+                        "Expected LHS expression in let binding",
+                        expression,
+                    ),
+                ),
+                preceded(
+                    delimited(spacelike, char('='), spacelike),
+                    context(
+                        "Expected RHS expression in let binding",
+                        expression,
+                    ),
+                ),
+            ),
+            |(lhs, rhs)| format!("let {lhs} = {rhs}"),
+        )
+        .parse(i),
+        (_i, Some(_)) => unreachable!(),
+        (i, None) => map(
+            context("Expected expression", logic_expression),
+            String::from,
+        )
+        .parse(i),
+    }
+}
+
+fn loop_expression(input: &[u8]) -> PResult<'_, String> {
+    map(
+        map_res(
+            recognize(terminated(
+                expression,
+                opt(preceded(
+                    terminated(tag(".."), opt(char('='))),
+                    expression,
+                )),
+            )),
+            input_to_str,
+        ),
+        String::from,
+    )
+    .parse(input)
+}
+
+pub fn logic_expression(input: &[u8]) -> PResult<'_, &str> {
+    map_res(
+        recognize(pair(unary_expression, many0(binary_operation))),
+        input_to_str,
+    )
+    .parse(input)
+}
+
+/// An operand, with any prefix operators and `as` casts.
+fn unary_expression(input: &[u8]) -> PResult<'_, ()> {
+    value(
+        (),
+        (
+            many0(terminated(
+                alt((
+                    tag("!"),
+                    tag("-"),
+                    tag("*"),
+                    terminated(tag("&"), opt(tag("mut "))),
+                )),
+                spacelike,
+            )),
+            expression,
+            many0(preceded(
+                delimited(
+                    spacelike,
+                    terminated(
+                        tag("as"),
+                        not(satisfy(|c| c.is_alphanumeric() || c == '_')),
+                    ),
+                    spacelike,
+                ),
+                context("Expected type to cast to", type_expression),
+            )),
+        ),
+    )
+    .parse(input)
+}
+
+/// A binary operator and its right hand side operand.
+///
+/// When an operator is found, the operand is required, so that an
+/// error can point at the operator.
+fn binary_operation(input: &[u8]) -> PResult<'_, ()> {
+    preceded(
+        spacelike,
+        context(
+            "Expected expression after operator",
+            value(
+                (),
+                pair(
+                    binary_operator,
+                    cut(preceded(spacelike, unary_expression)),
+                ),
+            ),
+        ),
+    )
+    .parse(input)
+}
+
+fn binary_operator(input: &[u8]) -> PResult<'_, &[u8]> {
+    alt((
+        alt((
+            tag("||"),
+            tag("&&"),
+            tag("=="),
+            tag("!="),
+            tag("<="),
+            tag(">="),
+            tag("<<"),
+            tag(">>"),
+            tag("..="),
+            tag(".."),
+        )),
+        alt((
+            tag("<"),
+            tag(">"),
+            tag("+"),
+            tag("-"),
+            tag("*"),
+            tag("/"),
+            tag("%"),
+            tag("^"),
+            tag("&"),
+            tag("|"),
+        )),
+    ))
+    .parse(input)
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::parseresult::show_errors;
+    use super::*;
+
+    /// Parse a template expression, without positions.
+    fn template_expression(input: &[u8]) -> PResult<'_, TemplateExpression> {
+        let (rest, mut expr) = super::template_expression(input)?;
+        clear_pos(std::slice::from_mut(&mut expr));
+        Ok((rest, expr))
+    }
+
+    #[test]
+    fn for_variable_simple() {
+        assert_eq!(
+            for_variable(b"foo").unwrap(),
+            (&b""[..], "foo".to_string())
+        )
+    }
+
+    #[test]
+    fn for_variable_tuple() {
+        assert_eq!(
+            for_variable(b"(foo, bar)").unwrap(),
+            (&b""[..], "(foo, bar)".to_string())
+        )
+    }
+
+    #[test]
+    fn for_variable_tuple_ref() {
+        assert_eq!(
+            for_variable(b"&(foo, bar)").unwrap(),
+            (&b""[..], "&(foo, bar)".to_string())
+        )
+    }
+
+    #[test]
+    fn for_variable_struct() {
+        assert_eq!(
+            for_variable(b"MyStruct{foo, bar}").unwrap(),
+            (&b""[..], "MyStruct{foo, bar}".to_string())
+        )
+    }
+
+    #[test]
+    fn call_simple() {
+        assert_eq!(
+            template_expression(b"@foo()"),
+            Ok((
+                &b""[..],
+                TemplateExpression::Expression {
+                    expr: "foo()".to_string(),
+                    pos: Pos::default(),
+                },
+            ))
+        )
+    }
+
+    #[test]
+    fn call_template_named_and_trailing() {
+        assert_eq!(
+            template_expression(b"@:a::b_html(x, y = {Y}) {Z}"),
+            Ok((
+                &b""[..],
+                TemplateExpression::CallTemplate {
+                    name: "a::b_html".to_string(),
+                    args: vec![TemplateArgument::Rust("x".to_string())],
+                    named: vec![(
+                        "y".to_string(),
+                        TemplateArgument::Body(vec![
+                            TemplateExpression::text("Y")
+                        ]),
+                    )],
+                    block: Some(vec![TemplateExpression::text("Z")]),
+                    pos: Pos::default(),
+                },
+            ))
+        );
+    }
+
+    #[test]
+    fn call_template_resolve_named() {
+        let params =
+            [("a", None), ("b", Some("0")), ("c", None), ("d", None)].map(
+                |(name, default)| FormalArgument {
+                    doc: Vec::new(),
+                    name: name.into(),
+                    ty: "u8".into(),
+                    default: default.map(String::from),
+                    pos: Pos::default(),
+                },
+            );
+        let mut found = |name: &str| {
+            assert_eq!(name, "t_html");
+            Some(params.to_vec())
+        };
+        let (_, mut call) =
+            template_expression(b"@:t_html(c = 3) {B}").unwrap();
+        assert_eq!(
+            call.resolve_calls(&mut found),
+            Err("Missing argument a to t_html".to_string()),
+        );
+        let (_, mut call) =
+            template_expression(b"@:t_html(1, c = 3) {B}").unwrap();
+        assert_eq!(call.resolve_calls(&mut found), Ok(()));
+        let mut code = String::new();
+        call.write_code(&mut code, Flavor::Sync).unwrap();
+        assert_eq!(
+            code,
+            "t_html(_ructe_out_.by_ref(), 1, 0, 3, \
+             #[allow(clippy::used_underscore_binding)] \
+             |mut _ructe_out_| {\n\
+             _ructe_out_.write_all(b\"B\")?;\n\
+             Ok(())\n}\n)?;\n",
+        );
+    }
+
+    #[test]
+    fn call_template_positional_after_named() {
+        assert_eq!(
+            expression_error(b"@:t_html(a = 1, 2)"),
+            ":   1:@:t_html(a = 1, 2)\n\
+             :                   ^ expected ',' or ')'.\n\
+             :   1:@:t_html(a = 1, 2)\n\
+             :                   ^ Expected ')'\n"
+        );
+    }
+
+    /// Check that issue #53 stays fixed.
+    #[test]
+    fn call_empty_str() {
+        assert_eq!(
+            template_expression(b"@foo(\"\")"),
+            Ok((
+                &b""[..],
+                TemplateExpression::Expression {
+                    expr: "foo(\"\")".to_string(),
+                    pos: Pos::default(),
+                },
+            ))
+        )
+    }
+
+    #[test]
+    fn if_boolean_var() {
+        assert_eq!(
+            template_expression(b"@if cond { something }"),
+            Ok((
+                &b""[..],
+                TemplateExpression::IfBlock {
+                    expr: "cond".to_string(),
+                    body: vec![TemplateExpression::text(" something ")],
+                    else_body: None,
+                    pos: Pos::default(),
+                }
+            ))
+        )
+    }
+
+    #[test]
+    fn if_let() {
+        assert_eq!(
+            template_expression(b"@if let Some(x) = x { something }"),
+            Ok((
+                &b""[..],
+                TemplateExpression::IfBlock {
+                    expr: "let Some(x) = x".to_string(),
+                    body: vec![TemplateExpression::text(" something ")],
+                    else_body: None,
+                    pos: Pos::default(),
+                }
+            ))
+        )
+    }
+
+    #[test]
+    fn if_let_2() {
+        assert_eq!(
+            template_expression(b"@if let Some((x, y)) = x { something }"),
+            Ok((
+                &b""[..],
+                TemplateExpression::IfBlock {
+                    expr: "let Some((x, y)) = x".to_string(),
+                    body: vec![TemplateExpression::text(" something ")],
+                    else_body: None,
+                    pos: Pos::default(),
+                }
+            ))
+        )
+    }
+
+    #[test]
+    fn if_let_3() {
+        assert_eq!(
+            template_expression(
+                b"@if let Some(p) = Uri::borrow_from(&state) { something }"
+            ),
+            Ok((
+                &b""[..],
+                TemplateExpression::IfBlock {
+                    expr: "let Some(p) = Uri::borrow_from(&state)"
+                        .to_string(),
+                    body: vec![TemplateExpression::text(" something ")],
+                    else_body: None,
+                    pos: Pos::default(),
+                }
+            ))
+        )
+    }
+
+    #[test]
+    fn if_let_struct() {
+        assert_eq!(
+            template_expression(
+                b"@if let Struct{x, y} = variable { something }"
+            ),
+            Ok((
+                &b""[..],
+                TemplateExpression::IfBlock {
+                    expr: "let Struct{x, y} = variable".to_string(),
+                    body: vec![TemplateExpression::text(" something ")],
+                    else_body: None,
+                    pos: Pos::default(),
+                }
+            ))
+        )
+    }
+
+    #[test]
+    fn if_compare() {
+        assert_eq!(
+            template_expression(b"@if x == 17 { something }"),
+            Ok((
+                &b""[..],
+                TemplateExpression::IfBlock {
+                    expr: "x == 17".to_string(),
+                    body: vec![TemplateExpression::text(" something ")],
+                    else_body: None,
+                    pos: Pos::default(),
+                }
+            ))
+        )
+    }
+
+    /// Check that issue #53 stays fixed.
+    #[test]
+    fn if_compare_empty_string() {
+        // Note that x.is_empty() would be better in real code, but this and
+        // other uses of empty strings in conditionals should be ok.
+        assert_eq!(
+            template_expression(b"@if x == \"\" { something }"),
+            Ok((
+                &b""[..],
+                TemplateExpression::IfBlock {
+                    expr: "x == \"\"".to_string(),
+                    body: vec![TemplateExpression::text(" something ")],
+                    else_body: None,
+                    pos: Pos::default(),
+                }
+            ))
+        )
+    }
+
+    #[test]
+    fn if_complex_logig() {
+        assert_eq!(
+            template_expression(b"@if x == 17 || y && z() { something }"),
+            Ok((
+                &b""[..],
+                TemplateExpression::IfBlock {
+                    expr: "x == 17 || y && z()".to_string(),
+                    body: vec![TemplateExpression::text(" something ")],
+                    else_body: None,
+                    pos: Pos::default(),
+                }
+            ))
+        )
+    }
+    #[test]
+    fn if_arithmetic() {
+        check_condition("count % 2 == 0");
+        check_condition("a + b > limit");
+        check_condition("a+b*c-d/e>=f");
+        check_condition("x as u32 > 3");
+        check_condition("x as i64 as f64 <= 2.5");
+        check_condition("-x < y");
+        check_condition("!(a && b)");
+        check_condition("! a.is_empty()");
+        check_condition("flags & MASK != 0");
+        check_condition("a << 2 | b ^ c >= *d");
+        check_condition("(0..len).contains(&i)");
+    }
+
+    #[test]
+    fn if_cast_variable_named_ask() {
+        check_condition("ask && asap");
+    }
+
+    fn check_condition(cond: &str) {
+        let input = format!("@if {cond} {{ something }}");
+        assert_eq!(
+            template_expression(input.as_bytes()),
+            Ok((
+                &b""[..],
+                TemplateExpression::IfBlock {
+                    expr: cond.to_string(),
+                    body: vec![TemplateExpression::text(" something ")],
+                    else_body: None,
+                    pos: Pos::default(),
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn if_missing_operand() {
+        assert_eq!(
+            expression_error(b"@if a + { oops }"),
+            ":   1:@if a + { oops }\n\
+             :         ^ Error in conditional expression:\n\
+             :   1:@if a + { oops }\n\
+             :         ^ Expected expression\n\
+             :   1:@if a + { oops }\n\
+             :           ^ Expected expression after operator\n\
+             :   1:@if a + { oops }\n\
+             :             ^ Expected rust expression\n"
+        );
+    }
+
+    #[test]
+    fn if_bad_operator() {
+        assert_eq!(
+            expression_error(b"@if a === b { oops }"),
+            ":   1:@if a === b { oops }\n\
+             :         ^ Error in conditional expression:\n\
+             :   1:@if a === b { oops }\n\
+             :         ^ Expected expression\n\
+             :   1:@if a === b { oops }\n\
+             :           ^ Expected expression after operator\n\
+             :   1:@if a === b { oops }\n\
+             :             ^ Expected rust expression\n"
+        );
+    }
+
+    #[test]
+    fn if_missing_conditional() {
+        assert_eq!(
+            expression_error(b"@if { oops }"),
+            ":   1:@if { oops }\n\
+             :         ^ Error in conditional expression:\n\
+             :   1:@if { oops }\n\
+             :         ^ Expected expression\n\
+             :   1:@if { oops }\n\
+             :         ^ Expected rust expression\n"
+        )
+    }
+
+    #[test]
+    fn if_bad_let() {
+        assert_eq!(
+            expression_error(b"@if let foo { oops }"),
+            ":   1:@if let foo { oops }\n\
+             :         ^ Error in conditional expression:\n\
+             :   1:@if let foo { oops }\n\
+             :                 ^ Expected \'=\'\n"
+        )
+    }
+
+    #[test]
+    fn for_in_struct() {
+        assert_eq!(
+            template_expression(
+                b"@for Struct{x, y} in structs { something }"
+            ),
+            Ok((
+                &b""[..],
+                TemplateExpression::ForLoop {
+                    label: None,
+                    name: "Struct{x, y}".to_string(),
+                    expr: "structs".to_string(),
+                    info: None,
+                    body: vec![TemplateExpression::text(" something ")],
+                    sep: None,
+                    else_body: None,
+                    pos: Pos::default(),
+                }
+            ))
+        )
+    }
+
+    #[test]
+    fn for_with_info_sep_else() {
+        assert_eq!(
+            template_expression(
+                b"@for x in xs with info { @x } @sep {, } else { none }"
+            ),
+            Ok((
+                &b""[..],
+                TemplateExpression::ForLoop {
+                    label: None,
+                    name: "x".to_string(),
+                    expr: "xs".to_string(),
+                    info: Some("info".to_string()),
+                    body: vec![
+                        TemplateExpression::text(" "),
+                        TemplateExpression::Expression {
+                            expr: "x".to_string(),
+                            pos: Pos::default(),
+                        },
+                        TemplateExpression::text(" "),
+                    ],
+                    sep: Some(vec![TemplateExpression::text(", ")]),
+                    else_body: Some(vec![TemplateExpression::text(" none ")]),
+                    pos: Pos::default(),
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn for_else_without_sep() {
+        assert_eq!(
+            template_expression(b"@for x in xs {x}\nelse {-} after"),
+            Ok((
+                &b" after"[..],
+                TemplateExpression::ForLoop {
+                    label: None,
+                    name: "x".to_string(),
+                    expr: "xs".to_string(),
+                    info: None,
+                    body: vec![TemplateExpression::text("x")],
+                    sep: None,
+                    else_body: Some(vec![TemplateExpression::text("-")]),
+                    pos: Pos::default(),
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn for_followed_by_expression() {
+        assert_eq!(
+            template_expression(b"@for x in xs {x} @separator"),
+            Ok((
+                &b" @separator"[..],
+                TemplateExpression::ForLoop {
+                    label: None,
+                    name: "x".to_string(),
+                    expr: "xs".to_string(),
+                    info: None,
+                    body: vec![TemplateExpression::text("x")],
+                    sep: None,
+                    else_body: None,
+                    pos: Pos::default(),
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn while_condition() {
+        assert_eq!(
+            template_expression(b"@while n < 10 { something }"),
+            Ok((
+                &b""[..],
+                TemplateExpression::WhileLoop {
+                    label: None,
+                    expr: "n < 10".to_string(),
+                    body: vec![TemplateExpression::text(" something ")],
+                    pos: Pos::default(),
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn while_let() {
+        assert_eq!(
+            template_expression(
+                b"@while let Some(x) = it.next() { something }"
+            ),
+            Ok((
+                &b""[..],
+                TemplateExpression::WhileLoop {
+                    label: None,
+                    expr: "let Some(x) = it.next()".to_string(),
+                    body: vec![TemplateExpression::text(" something ")],
+                    pos: Pos::default(),
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn labeled_loop_with_break() {
+        assert_eq!(
+            template_expression(
+                b"@'rows: while go() { @for x in xs { @continue 'rows } }"
+            ),
+            Ok((
+                &b""[..],
+                TemplateExpression::WhileLoop {
+                    label: Some("rows".to_string()),
+                    expr: "go()".to_string(),
+                    body: vec![
+                        TemplateExpression::text(" "),
+                        TemplateExpression::ForLoop {
+                            label: None,
+                            name: "x".to_string(),
+                            expr: "xs".to_string(),
+                            info: None,
+                            body: vec![
+                                TemplateExpression::text(" "),
+                                TemplateExpression::Continue {
+                                    label: Some("rows".to_string()),
+                                    pos: Pos::default(),
+                                },
+                                TemplateExpression::text(" "),
+                            ],
+                            sep: None,
+                            else_body: None,
+                            pos: Pos::default(),
+                        },
+                        TemplateExpression::text(" "),
+                    ],
+                    pos: Pos::default(),
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn break_followed_by_text() {
+        assert_eq!(
+            template_expression(b"@break done"),
+            Ok((
+                &b" done"[..],
+                TemplateExpression::Break {
+                    label: None,
+                    pos: Pos::default(),
+                }
+            ))
+        );
+        assert_eq!(
+            template_expression(b"@breakfast"),
+            Ok((
+                &b""[..],
+                TemplateExpression::Expression {
+                    expr: "breakfast".to_string(),
+                    pos: Pos::default(),
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn char_literal_is_not_a_label() {
+        assert_eq!(
+            template_expression(b"@'x'.to_uppercase() "),
+            Ok((
+                &b" "[..],
+                TemplateExpression::Expression {
+                    expr: "'x'.to_uppercase()".into(),
+                    pos: Pos::default(),
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn variable_named_block() {
+        assert_eq!(
+            template_expression(b"@block is here"),
+            Ok((
+                &b" is here"[..],
+                TemplateExpression::Expression {
+                    expr: "block".into(),
+                    pos: Pos::default(),
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn error_in_labeled_loop() {
+        assert_eq!(
+            expression_error(b"@'a: if x {}"),
+            ":   1:@'a: if x {}\n\
+             :          ^ Expected for or while loop after label\n"
+        );
+    }
+
+    #[test]
+    fn break_outside_loop() {
+        let check = |src: &[u8]| {
+            let (_, body) = many0(template_expression).parse(src).unwrap();
+            check_loops(&body, &[])
+        };
+        assert_eq!(check(b"@for x in xs {@if x {@break}}"), Ok(()));
+        assert_eq!(
+            check(b"@for x in xs {} else {@break}"),
+            Err("@break outside of a loop".into()),
+        );
+        assert_eq!(
+            check(b"@for x in xs {@:t({@continue})}"),
+            Err("@continue outside of a loop".into()),
+        );
+        assert_eq!(
+            check(b"@'a: for x in xs {@while y {@break 'b}}"),
+            Err("@break 'b outside of a loop labeled 'b".into()),
+        );
+    }
 
     #[test]
-    fn for_variable_simple() {
+    fn block_with_default() {
         assert_eq!(
-            for_variable(b"foo").unwrap(),
-            (&b""[..], "foo".to_string())
-        )
+            template_expression(b"@block title {<h1>@name</h1>}"),
+            Ok((
+                &b""[..],
+                TemplateExpression::Block {
+                    name: "title".to_string(),
+                    body: vec![
+                        TemplateExpression::text("<h1>"),
+                        TemplateExpression::Expression {
+                            expr: "name".to_string(),
+                            pos: Pos::default(),
+                        },
+                        TemplateExpression::text("</h1>"),
+                    ],
+                    pos: Pos::default(),
+                }
+            ))
+        );
     }
 
     #[test]
-    fn for_variable_tuple() {
+    fn async_call_and_content() {
+        let (_, e) =
+            template_expression(b"@:page_html(x, {<p>@y</p>@:body()})")
+                .unwrap();
+        let content = ["body".to_string()];
+        let mut code = String::new();
+        e.write_code(&mut code, Flavor::Async(&content)).unwrap();
         assert_eq!(
-            for_variable(b"(foo, bar)").unwrap(),
-            (&b""[..], "(foo, bar)".to_string())
-        )
+            code,
+            "_ructe_out_ = page_html_async(_ructe_out_, x, \
+             #[allow(clippy::used_underscore_binding)] \
+             |_ructe_out_| Box::pin(async {\n\
+             let mut _ructe_out_ = _ructe_out_;\n\
+             _ructe_out_.write_all(b\"<p>\").await?;\n\
+             {\nlet _ructe_html_ = y.to_buffer()?;\n\
+             _ructe_out_.write_all(_ructe_html_.as_ref()).await?;\n}\n\
+             _ructe_out_.write_all(b\"</p>\").await?;\n\
+             _ructe_out_ = body(_ructe_out_).await?;\n\
+             Ok::<_, io::Error>(_ructe_out_)\n})\n).await?;\n",
+        );
     }
 
     #[test]
-    fn for_variable_tuple_ref() {
+    fn block_overridden_inside_if() {
+        let (_, mut e) =
+            template_expression(b"@if x {@block a {A}@block b {B}}").unwrap();
+        e.override_blocks(&["b"]);
+        let mut names = Vec::new();
+        e.block_names(&mut names);
+        assert_eq!(names, ["a"]);
+        let mut code = String::new();
+        e.write_code(&mut code, Flavor::Sync).unwrap();
         assert_eq!(
-            for_variable(b"&(foo, bar)").unwrap(),
-            (&b""[..], "&(foo, bar)".to_string())
-        )
+            code,
+            "if x {\n\
+             _ructe_out_.write_all(b\"A\")?;\n\
+             _ructe_block_b(_ructe_out_.by_ref())?;\n\
+             }\n",
+        );
     }
 
     #[test]
-    fn for_variable_struct() {
+    fn while_missing_block() {
         assert_eq!(
-            for_variable(b"MyStruct{foo, bar}").unwrap(),
-            (&b""[..], "MyStruct{foo, bar}".to_string())
-        )
+            expression_error(b"@while let Some(x) = y"),
+            ":   1:@while let Some(x) = y\n\
+             :            ^ Error in while loop:\n\
+             :   1:@while let Some(x) = y\n\
+             :                           ^ Expected '{'\n"
+        );
     }
 
     #[test]
-    fn call_simple() {
+    fn match_guards_and_alternatives() {
         assert_eq!(
-            template_expression(b"@foo()"),
+            template_expression(
+                b"@match n {\n  \
+                  Some(n) if n > 10 => {many}\n  \
+                  Some(1..=9) | Some(0) => {few}\n  \
+                  None => {none}\n\
+                  }"
+            ),
             Ok((
                 &b""[..],
-                TemplateExpression::Expression {
-                    expr: "foo()".to_string(),
-                },
+                TemplateExpression::MatchBlock {
+                    expr: "n".to_string(),
+                    arms: vec![
+                        MatchArm {
+                            pattern: "Some(n)".to_string(),
+                            guard: Some("n > 10".to_string()),
+                            body: vec![TemplateExpression::text("many")],
+                        },
+                        MatchArm {
+                            pattern: "Some(1..=9) | Some(0)".to_string(),
+                            guard: None,
+                            body: vec![TemplateExpression::text("few")],
+                        },
+                        MatchArm {
+                            pattern: "None".to_string(),
+                            guard: None,
+                            body: vec![TemplateExpression::text("none")],
+                        },
+                    ],
+                    pos: Pos::default(),
+                }
             ))
-        )
+        );
     }
 
-    /// Check that issue #53 stays fixed.
     #[test]
-    fn call_empty_str() {
+    fn match_pattern_named_if() {
         assert_eq!(
-            template_expression(b"@foo(\"\")"),
+            template_expression(b"@match x { iffy => {y} }"),
             Ok((
                 &b""[..],
-                TemplateExpression::Expression {
-                    expr: "foo(\"\")".to_string(),
-                },
+                TemplateExpression::MatchBlock {
+                    expr: "x".to_string(),
+                    arms: vec![MatchArm {
+                        pattern: "iffy".to_string(),
+                        guard: None,
+                        body: vec![TemplateExpression::text("y")],
+                    }],
+                    pos: Pos::default(),
+                }
             ))
+        );
+    }
+
+    #[test]
+    fn match_missing_arrow() {
+        assert_eq!(
+            expression_error(b"@match x { A | B -> {y} }"),
+            ":   1:@match x { A | B -> {y} }\n\
+             :            ^ Error in match expression:\n\
+             :   1:@match x { A | B -> {y} }\n\
+             :               ^ Error in match arm starting here:\n\
+             :   1:@match x { A | B -> {y} }\n\
+             :                      ^ Expected \"=>\"\n"
+        );
+    }
+
+    #[test]
+    fn for_missing_in() {
+        // TODO The second part of this message isn't really helpful.
+        assert_eq!(
+            expression_error(b"@for what ever { hello }"),
+            ":   1:@for what ever { hello }\n\
+             :               ^ Expected \"in\"\n"
         )
     }
 
     #[test]
-    fn if_boolean_var() {
+    fn let_binding() {
         assert_eq!(
-            template_expression(b"@if cond { something }"),
+            template_expression(b"@let name = user.profile().name();"),
             Ok((
                 &b""[..],
-                TemplateExpression::IfBlock {
-                    expr: "cond".to_string(),
-                    body: vec![TemplateExpression::text(" something ")],
-                    else_body: None,
+                TemplateExpression::Code {
+                    code: "let name = user.profile().name();".to_string(),
+                    pos: Pos::default(),
                 }
             ))
-        )
+        );
     }
 
     #[test]
-    fn if_let() {
+    fn let_binding_typed_mut() {
         assert_eq!(
-            template_expression(b"@if let Some(x) = x { something }"),
+            template_expression(b"@let mut n: usize = items.len() + 1;"),
             Ok((
                 &b""[..],
-                TemplateExpression::IfBlock {
-                    expr: "let Some(x) = x".to_string(),
-                    body: vec![TemplateExpression::text(" something ")],
-                    else_body: None,
+                TemplateExpression::Code {
+                    code: "let mut n: usize = items.len() + 1;".to_string(),
+                    pos: Pos::default(),
                 }
             ))
-        )
+        );
     }
 
     #[test]
-    fn if_let_2() {
+    fn let_binding_any_whitespace() {
         assert_eq!(
-            template_expression(b"@if let Some((x, y)) = x { something }"),
+            template_expression(b"@let\tmut\nn = 1;"),
             Ok((
                 &b""[..],
-                TemplateExpression::IfBlock {
-                    expr: "let Some((x, y)) = x".to_string(),
-                    body: vec![TemplateExpression::text(" something ")],
-                    else_body: None,
+                TemplateExpression::Code {
+                    code: "let mut\nn = 1;".to_string(),
+                    pos: Pos::default(),
                 }
             ))
-        )
+        );
     }
 
     #[test]
-    fn if_let_3() {
+    fn let_binding_destructure() {
         assert_eq!(
-            template_expression(
-                b"@if let Some(p) = Uri::borrow_from(&state) { something }"
-            ),
+            template_expression(b"@let (a, b) = (f(\";\"), [1; 2]);"),
             Ok((
                 &b""[..],
-                TemplateExpression::IfBlock {
-                    expr: "let Some(p) = Uri::borrow_from(&state)"
-                        .to_string(),
-                    body: vec![TemplateExpression::text(" something ")],
-                    else_body: None,
+                TemplateExpression::Code {
+                    code: "let (a, b) = (f(\";\"), [1; 2]);".to_string(),
+                    pos: Pos::default(),
                 }
             ))
-        )
+        );
     }
 
     #[test]
-    fn if_let_struct() {
+    fn let_missing_semicolon() {
+        assert_eq!(
+            expression_error(b"@let x = 17 <p>@x</p>"),
+            ":   1:@let x = 17 <p>@x</p>\n\
+             :          ^ Error in let binding:\n\
+             :   1:@let x = 17 <p>@x</p>\n\
+             :                    ^ Expected ';'\n"
+        );
+    }
+
+    #[test]
+    fn code_block() {
         assert_eq!(
             template_expression(
-                b"@if let Struct{x, y} = variable { something }"
+                b"@{{ let a = 1;\n    let b = { a + 1 }; }} after"
             ),
             Ok((
-                &b""[..],
-                TemplateExpression::IfBlock {
-                    expr: "let Struct{x, y} = variable".to_string(),
-                    body: vec![TemplateExpression::text(" something ")],
-                    else_body: None,
+                &b" after"[..],
+                TemplateExpression::Code {
+                    code: "let a = 1;\n    let b = { a + 1 };".to_string(),
+                    pos: Pos::default(),
                 }
             ))
-        )
+        );
     }
 
     #[test]
-    fn if_compare() {
+    fn escaped_brace_is_not_code() {
         assert_eq!(
-            template_expression(b"@if x == 17 { something }"),
+            template_expression(b"@{ text"),
+            Ok((&b" text"[..], TemplateExpression::text("{")))
+        );
+    }
+
+    #[test]
+    fn escaped_text_position() {
+        let src = b"{<p>\n@@ and @}}";
+        let (_, body) = template_block(src).unwrap();
+        let lines = body
+            .iter()
+            .filter_map(|e| Some(e.pos()?.line_col(&src[..])))
+            .collect::<Vec<_>>();
+        assert_eq!(lines, [(1, 2), (2, 1), (2, 3), (2, 8)]);
+    }
+
+    #[test]
+    fn include_file() {
+        assert_eq!(
+            template_expression(b"@include_escaped(\"icons/x.svg\")!"),
+            Ok((
+                &b"!"[..],
+                TemplateExpression::Include {
+                    path: "icons/x.svg".to_string(),
+                    escaped: true,
+                    pos: Pos::default(),
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn include_unquoted() {
+        assert_eq!(
+            expression_error(b"@include_raw(name)"),
+            ":   1:@include_raw(name)\n\
+             :                 ^ Error in include:\n\
+             :   1:@include_raw(name)\n\
+             :                  ^ Expected quoted file name\n\
+             :   1:@include_raw(name)\n\
+             :                  ^ Expected '\"'\n"
+        );
+    }
+
+    #[test]
+    fn include_raw_as_name() {
+        assert_eq!(
+            template_expression(b"@include_raw.len()"),
             Ok((
                 &b""[..],
-                TemplateExpression::IfBlock {
-                    expr: "x == 17".to_string(),
-                    body: vec![TemplateExpression::text(" something ")],
-                    else_body: None,
+                TemplateExpression::Expression {
+                    expr: "include_raw.len()".to_string(),
+                    pos: Pos::default(),
                 }
             ))
-        )
+        );
     }
 
-    /// Check that issue #53 stays fixed.
     #[test]
-    fn if_compare_empty_string() {
-        // Note that x.is_empty() would be better in real code, but this and
-        // other uses of empty strings in conditionals should be ok.
+    fn trim_marker() {
         assert_eq!(
-            template_expression(b"@if x == \"\" { something }"),
+            template_expression(b"a \n  @~ \n b"),
+            Ok((&b"@~ \n b"[..], TemplateExpression::text("a"))),
+        );
+        assert_eq!(
+            template_expression(b"@~ \n b"),
+            Ok((&b"b"[..], TemplateExpression::Comment)),
+        );
+    }
+
+    #[test]
+    fn collapse_text_between_tags() {
+        let mut raw = None;
+        let mut context = HtmlContext::default();
+        assert_eq!(
+            collapse_text(
+                "<ul>\n  <li>a  b</li>\n  <li>",
+                None,
+                None,
+                &mut raw,
+                &mut context
+            ),
+            "<ul><li>a b</li><li>",
+        );
+        assert_eq!(raw, None);
+        assert_eq!(
+            collapse_text(
+                "\n  Hello,\n  ",
+                None,
+                None,
+                &mut raw,
+                &mut context
+            ),
+            " Hello, ",
+        );
+    }
+
+    #[test]
+    fn collapse_text_keeps_pre() {
+        let mut raw = None;
+        let mut context = HtmlContext::default();
+        assert_eq!(
+            collapse_text(
+                "<div>\n  <PRE class=x>\n a\n",
+                None,
+                None,
+                &mut raw,
+                &mut context
+            ),
+            "<div><PRE class=x>\n a\n",
+        );
+        assert_eq!(raw, Some("pre"));
+        assert_eq!(
+            collapse_text(
+                "  b\n</pre>\n  <pretty> x",
+                None,
+                None,
+                &mut raw,
+                &mut context
+            ),
+            "  b\n</pre><pretty> x",
+        );
+        assert_eq!(raw, None);
+    }
+
+    #[test]
+    fn collapse_keeps_attribute_values() {
+        let mut raw = None;
+        let mut context = HtmlContext::default();
+        assert_eq!(
+            collapse_text(
+                "<p  title=\"a    b\n c\"  class='x  ",
+                None,
+                None,
+                &mut raw,
+                &mut context
+            ),
+            "<p title=\"a    b\n c\" class='x  ",
+        );
+        assert_eq!(
+            collapse_text(
+                "  y'>\n  a    b",
+                None,
+                None,
+                &mut raw,
+                &mut context
+            ),
+            "  y'> a b",
+        );
+    }
+
+    #[test]
+    fn collapse_text_next_to_expressions() {
+        let (_, mut body) =
+            template_block(b"{@first\n@last </b>\n@name\n  <i>}").unwrap();
+        collapse_whitespace(
+            &mut body,
+            &mut None,
+            &mut HtmlContext::default(),
+        );
+        coalesce_text(&mut body);
+        clear_pos(&mut body);
+        assert_eq!(
+            body,
+            [
+                TemplateExpression::Expression {
+                    expr: "first".into(),
+                    pos: Pos::default(),
+                },
+                TemplateExpression::text(" "),
+                TemplateExpression::Expression {
+                    expr: "last".into(),
+                    pos: Pos::default(),
+                },
+                TemplateExpression::text(" </b> "),
+                TemplateExpression::Expression {
+                    expr: "name".into(),
+                    pos: Pos::default(),
+                },
+                TemplateExpression::text(" <i>"),
+            ],
+        );
+    }
+
+    #[test]
+    fn collapse_text_next_to_blocks() {
+        let (_, mut body) =
+            template_block(b"{</b>\n@if x {\n  yes }\n<ul>\n@for x in y {\n  <li>@x</li>\n}\n</ul>}")
+                .unwrap();
+        collapse_whitespace(
+            &mut body,
+            &mut None,
+            &mut HtmlContext::default(),
+        );
+        let text = body
+            .iter()
+            .filter_map(|e| match e {
+                TemplateExpression::Text { text, .. } => Some(text.as_str()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(text, ["</b> ", " <ul>", "</ul>"]);
+    }
+
+    #[test]
+    fn collapse_keeps_raw_block() {
+        let (_, mut body) =
+            template_block(b"{<pre>\n</pre>\n@raw#{  a\n  b  }#\n<p>}")
+                .unwrap();
+        collapse_whitespace(
+            &mut body,
+            &mut None,
+            &mut HtmlContext::default(),
+        );
+        coalesce_text(&mut body);
+        clear_pos(&mut body);
+        assert_eq!(
+            body,
+            [TemplateExpression::text("<pre>\n</pre>   a\n  b   <p>")],
+        );
+    }
+
+    #[test]
+    fn raw_block() {
+        assert_eq!(
+            template_expression(b"@raw#{ if (a) { b@c; }}#\n"),
+            Ok((
+                &b"\n"[..],
+                TemplateExpression::Raw {
+                    text: " if (a) { b@c; }".into(),
+                    pos: Pos::default(),
+                }
+            )),
+        );
+        assert_eq!(
+            template_expression(b"@raw##{ x }# }##"),
             Ok((
                 &b""[..],
-                TemplateExpression::IfBlock {
-                    expr: "x == \"\"".to_string(),
-                    body: vec![TemplateExpression::text(" something ")],
-                    else_body: None,
+                TemplateExpression::Raw {
+                    text: " x }# ".into(),
+                    pos: Pos::default(),
+                }
+            )),
+        );
+    }
+
+    #[test]
+    fn raw_block_unterminated() {
+        assert_eq!(
+            expression_error(b"@raw#{ a } b"),
+            ":   1:@raw#{ a } b\n\
+             :           ^ Expected end of raw block\n",
+        );
+    }
+
+    #[test]
+    fn optional_attribute() {
+        assert_eq!(
+            template_expression(b"@?data-id=item.id()>"),
+            Ok((
+                &b">"[..],
+                TemplateExpression::Attribute {
+                    name: "data-id".into(),
+                    value: "item.id()".into(),
+                    pos: Pos::default(),
                 }
             ))
-        )
+        );
     }
 
     #[test]
-    fn if_complex_logig() {
+    fn class_list_attribute() {
         assert_eq!(
-            template_expression(b"@if x == 17 || y && z() { something }"),
+            template_expression(b"@?class=[\"a\", name if x > 1 ,]>"),
+            Ok((
+                &b">"[..],
+                TemplateExpression::Attribute {
+                    name: "class".into(),
+                    value: "_utils::ClassList(&[(&\"a\", true), \
+                            (&name, x > 1)])"
+                        .into(),
+                    pos: Pos::default(),
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn attribute_without_value() {
+        assert_eq!(
+            expression_error(b"@?checked>"),
+            ":   1:@?checked>\n\
+             :       ^ Error in attribute:\n\
+             :   1:@?checked>\n\
+             :              ^ Expected '='\n"
+        );
+    }
+
+    #[test]
+    fn formatted_expression() {
+        assert_eq!(
+            template_expression(b"@(x.price() * 2.0:>8.2)</td>"),
+            Ok((
+                &b"</td>"[..],
+                TemplateExpression::Formatted {
+                    expr: "x.price() * 2.0".into(),
+                    spec: ">8.2".into(),
+                    pos: Pos::default(),
+                }
+            ))
+        );
+        assert_eq!(
+            template_expression(b"@(std::f64::consts::PI:e)"),
             Ok((
                 &b""[..],
-                TemplateExpression::IfBlock {
-                    expr: "x == 17 || y && z()".to_string(),
-                    body: vec![TemplateExpression::text(" something ")],
-                    else_body: None,
+                TemplateExpression::Formatted {
+                    expr: "std::f64::consts::PI".into(),
+                    spec: "e".into(),
+                    pos: Pos::default(),
                 }
             ))
-        )
+        );
     }
+
     #[test]
-    fn if_missing_conditional() {
+    fn formatted_expression_fill() {
         assert_eq!(
-            expression_error(b"@if { oops }"),
-            ":   1:@if { oops }\n\
-             :         ^ Error in conditional expression:\n\
-             :   1:@if { oops }\n\
-             :         ^ Expected expression\n\
-             :   1:@if { oops }\n\
-             :         ^ Expected rust expression\n"
-        )
+            template_expression(b"@(n:\"^+#010.3)</p>"),
+            Ok((
+                &b"</p>"[..],
+                TemplateExpression::Formatted {
+                    expr: "n".into(),
+                    spec: "\"^+#010.3".into(),
+                    pos: Pos::default(),
+                }
+            ))
+        );
+        assert_eq!(
+            template_expression(b"@(x:>width$.prec$e)"),
+            Ok((
+                &b""[..],
+                TemplateExpression::Formatted {
+                    expr: "x".into(),
+                    spec: ">width$.prec$e".into(),
+                    pos: Pos::default(),
+                }
+            ))
+        );
     }
 
     #[test]
-    fn if_bad_let() {
+    fn error_in_format_spec() {
         assert_eq!(
-            expression_error(b"@if let foo { oops }"),
-            ":   1:@if let foo { oops }\n\
-             :         ^ Error in conditional expression:\n\
-             :   1:@if let foo { oops }\n\
-             :                 ^ Expected \'=\'\n"
-        )
+            expression_error(b"@(x:\")"),
+            ":   1:@(x:\")\n\
+             :         ^ Invalid format spec\n\
+             :   1:@(x:\")\n\
+             :         ^ Expected ')'\n"
+        );
+        assert_eq!(
+            expression_error(b"@(x:.*)"),
+            ":   1:@(x:.*)\n\
+             :         ^ Invalid format spec\n\
+             :   1:@(x:.*)\n\
+             :         ^ Expected ')'\n"
+        );
     }
 
     #[test]
-    fn for_in_struct() {
+    fn paren_expression_with_path() {
         assert_eq!(
-            template_expression(
-                b"@for Struct{x, y} in structs { something }"
-            ),
+            template_expression(b"@(a::b(Foo { x: 1 }))"),
             Ok((
                 &b""[..],
-                TemplateExpression::ForLoop {
-                    name: "Struct{x, y}".to_string(),
-                    expr: "structs".to_string(),
-                    body: vec![TemplateExpression::text(" something ")],
+                TemplateExpression::Expression {
+                    expr: "(a::b(Foo { x: 1 }))".into(),
+                    pos: Pos::default(),
                 }
             ))
-        )
+        );
     }
 
     #[test]
-    fn for_missing_in() {
-        // TODO The second part of this message isn't really helpful.
+    fn formatted_expression_code() {
+        let mut code = String::new();
+        TemplateExpression::Formatted {
+            expr: "n".into(),
+            spec: "03".into(),
+            pos: Pos::default(),
+        }
+        .write_code(&mut code, Flavor::Sync)
+        .unwrap();
         assert_eq!(
-            expression_error(b"@for what ever { hello }"),
-            ":   1:@for what ever { hello }\n\
-             :               ^ Expected \"in\"\n"
-        )
+            code,
+            "write!(_utils::ToHtmlEscapingWriter(_ructe_out_.by_ref()), \
+             \"{:03}\", n)?;\n",
+        );
     }
 
     fn expression_error(input: &[u8]) -> String {
@@ -11,6 +11,10 @@
 mod utils;
 pub use self::utils::*;
 
+#[cfg(test)]
+#[allow(dead_code)]
+mod utils_fmt;
+
 #[cfg(feature = "mime03")]
 use mime::Mime;
 
@@ -81,3 +85,107 @@ fn raw_html() {
     Html("a<b>c</b>").to_html(&mut buf).unwrap();
     assert_eq!(b"a<b>c</b>", &buf[..]);
 }
+
+#[test]
+fn js_string() {
+    let mut buf = Vec::new();
+    JsString("a \"b\"</script>é😀").to_html(&mut buf).unwrap();
+    assert_eq!(
+        r"a \u0022b\u0022\u003C\u002Fscript\u003Eé\uD83D\uDE00".as_bytes(),
+        &buf[..]
+    );
+}
+
+#[test]
+fn css_escaped() {
+    let mut buf = Vec::new();
+    CssEscaped("red;}").to_html(&mut buf).unwrap();
+    assert_eq!(br"red\3B \7D ", &buf[..]);
+}
+
+#[test]
+fn safe_url() {
+    let mut buf = Vec::new();
+    SafeUrl("/a?b=1&c=2").to_html(&mut buf).unwrap();
+    SafeUrl(" ").to_html(&mut buf).unwrap();
+    SafeUrl("HTTPS://x/").to_html(&mut buf).unwrap();
+    SafeUrl(" ").to_html(&mut buf).unwrap();
+    SafeUrl("JavaScript:alert(1)").to_html(&mut buf).unwrap();
+    assert_eq!(b"/a?b=1&amp;c=2 HTTPS://x/ #", &buf[..]);
+}
+
+#[test]
+fn url_encoded() {
+    let mut buf = Vec::new();
+    UrlEncoded("a b&c=ö").to_html(&mut buf).unwrap();
+    assert_eq!(b"a%20b%26c%3D%C3%B6", &buf[..]);
+}
+
+#[test]
+fn attr() {
+    let mut buf = Vec::new();
+    Attr("checked", true).to_html(&mut buf).unwrap();
+    Attr("checked", false).to_html(&mut buf).unwrap();
+    Attr("title", None::<&str>).to_html(&mut buf).unwrap();
+    buf.push(b' ');
+    Attr("title", Some("\"x\"")).to_html(&mut buf).unwrap();
+    assert_eq!(b"checked title=\"&quot;x&quot;\"", &buf[..]);
+}
+
+#[test]
+fn escaped_attr() {
+    let mut buf = Vec::new();
+    let url = Some("javascript:alert(1)");
+    EscapedAttr("href", &url, |v, o| SafeUrl(v).to_html(o))
+        .to_html(&mut buf)
+        .unwrap();
+    assert_eq!(b"href=\"#\"", &buf[..]);
+}
+
+#[test]
+fn class_list() {
+    let mut buf = Vec::new();
+    let classes =
+        ClassList(&[(&"btn", true), (&"big", false), (&"<x>", true)]);
+    Attr("class", &classes).to_html(&mut buf).unwrap();
+    Attr("class", ClassList(&[(&"big", false)]))
+        .to_html(&mut buf)
+        .unwrap();
+    assert_eq!(b"class=\"btn &lt;x&gt;\"", &buf[..]);
+}
+
+#[cfg(test)]
+mod fmt_test {
+    use super::utils_fmt::{Attr, Html, JsString, ToHtml};
+
+    fn to_string(value: &dyn ToHtml) -> String {
+        let mut out = String::new();
+        value.to_html(&mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn encoded() {
+        assert_eq!(to_string(&"a < b\0\n"), "a &lt; b\0\n");
+        assert_eq!(to_string(&"'b"), "&#39;b");
+        assert_eq!(to_string(&"xxxxx>&\"é"), "xxxxx&gt;&amp;&quot;é");
+        assert_eq!(to_string(&""), "");
+    }
+
+    #[test]
+    fn raw_html() {
+        assert_eq!(to_string(&Html("a<b>c</b>")), "a<b>c</b>");
+    }
+
+    #[test]
+    fn js_string() {
+        assert_eq!(to_string(&JsString("a'é😀")), r"a\u0027é\uD83D\uDE00");
+    }
+
+    #[test]
+    fn attr() {
+        assert_eq!(to_string(&Attr("checked", true)), "checked");
+        assert_eq!(to_string(&Attr("title", Some("<"))), "title=\"&lt;\"");
+        assert_eq!(to_string(&Attr("title", None::<&str>)), "");
+    }
+}
@@ -1,6 +1,13 @@
 use std::fmt::Display;
 use std::io::{self, Write};
 
+#[path = "utils_common.rs"]
+mod common;
+pub use self::common::*;
+
+/// The result of writing to a [`Write`].
+type WriteResult = io::Result<()>;
+
 /// This trait should be implemented for any value that can be the
 /// result of an expression in a template.
 ///
@@ -16,6 +23,21 @@ pub trait ToHtml {
     /// Implementors should not return an error for any other reason.
     fn to_html(&self, out: &mut dyn Write) -> io::Result<()>;
 
+    /// Write self to `out` as plain text, not escaped as html.
+    ///
+    /// This is what wrappers such as [`JsString`] escape for another
+    /// context than html.
+    /// The default is the html representation, so a value with
+    /// markup of its own gets escaped as that markup, while any
+    /// `T: Display` writes its `Display` text.
+    ///
+    /// # Errors
+    ///
+    /// This method returns an error if writing to `out` fails.
+    fn to_text(&self, out: &mut dyn Write) -> io::Result<()> {
+        self.to_html(out)
+    }
+
     /// Write the HTML represention of this value to a buffer.
     ///
     /// The HTML representation is decieded by the [`Self::to_html`] method.
@@ -81,26 +103,48 @@ impl PartialEq<&str> for HtmlBuffer {
     }
 }
 
-/// Wrapper object for data that should be outputted as raw html
-/// (objects that may contain markup).
-#[allow(dead_code)]
-pub struct Html<T>(pub T);
+/// Wrapper to format a [`ToHtml`] value with its html representation.
+///
+/// Every type that implements [`Display`] is also [`ToHtml`], escaped
+/// as html text, so a type with an html representation of its own
+/// (such as the struct generated for a template) can not implement
+/// [`Display`].
+/// This wrapper can be used with `format!` and friends instead.
+///
+/// # Examples
+/// ```
+/// # use ructe::templates::{Html, HtmlDisplay};
+/// assert_eq!(format!("{}", HtmlDisplay(&Html("<b>x</b>"))), "<b>x</b>");
+/// ```
+pub struct HtmlDisplay<'a, T: ?Sized>(pub &'a T);
 
-impl<T: Display> ToHtml for Html<T> {
-    #[inline]
-    fn to_html(&self, out: &mut dyn Write) -> io::Result<()> {
-        write!(out, "{}", self.0)
+impl<T: ToHtml + ?Sized> Display for HtmlDisplay<'_, T> {
+    fn fmt(&self, out: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let buf = self.0.to_buffer().map_err(|_| std::fmt::Error)?;
+        let html =
+            std::str::from_utf8(&buf.buf).map_err(|_| std::fmt::Error)?;
+        out.write_str(html)
     }
 }
 
-impl<T: Display> ToHtml for T {
-    #[inline]
-    fn to_html(&self, out: &mut dyn Write) -> io::Result<()> {
-        write!(ToHtmlEscapingWriter(out), "{self}")
-    }
+/// Write `s` to `out`.
+fn write_str(out: &mut dyn Write, s: &str) -> WriteResult {
+    out.write_all(s.as_bytes())
 }
 
-struct ToHtmlEscapingWriter<'a>(&'a mut dyn Write);
+/// The plain text of `value`, see [`ToHtml::to_text`].
+fn text_of<T: ToHtml + ?Sized>(value: &T) -> io::Result<String> {
+    let mut buf = Vec::new();
+    value.to_text(&mut buf)?;
+    Ok(String::from_utf8_lossy(&buf).into_owned())
+}
+
+/// A writer that html-escapes everything written to it.
+///
+/// This is used by the code generated for `@(expr:spec)`, and not
+/// intended to be used directly.
+#[doc(hidden)]
+pub struct ToHtmlEscapingWriter<'a>(pub &'a mut dyn Write);
 
 impl Write for ToHtmlEscapingWriter<'_> {
     #[inline]
@@ -0,0 +1,330 @@
+//! The parts of the `templates` module that are the same for
+//! templates writing to a `std::io::Write` and to a
+//! `core::fmt::Write`.
+//!
+//! This is a submodule of both `utils.rs` and `utils_fmt.rs`, and
+//! uses the `Write` trait, `WriteResult` type and helper functions
+//! of its parent.
+
+use super::{
+    text_of, write_str, ToHtml, ToHtmlEscapingWriter, Write, WriteResult,
+};
+use core::fmt::Display;
+
+/// Information about the current iteration of a `@for` loop.
+///
+/// A loop written as `@for item in items with info { ... }` has a
+/// `LoopInfo` named `info` available in its body.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LoopInfo {
+    /// The zero-based index of the current item, counting only the
+    /// items that have written any output, so items skipped by
+    /// `@continue` before any output are not counted.
+    pub index: usize,
+    /// True for the first counted item, i.e. when `index` is zero.
+    pub first: bool,
+    /// True for the last item of the iterator.
+    pub last: bool,
+}
+
+/// Wrapper object for data that should be outputted as raw html
+/// (objects that may contain markup).
+#[allow(dead_code)]
+pub struct Html<T>(pub T);
+
+impl<T: Display> ToHtml for Html<T> {
+    #[inline]
+    fn to_html(&self, out: &mut dyn Write) -> WriteResult {
+        write!(out, "{}", self.0)
+    }
+}
+
+/// Wrapper for an optional attribute, with its name and value.
+///
+/// The attribute is written as `name="value"` (with the value
+/// escaped), just as `name` for a `true` value, or not at all for a
+/// `false` or `None` value.
+/// This is what `@?name=value` in a template gives.
+///
+/// # Examples
+/// ```ignore
+/// # use ructe::templates::{Attr, ToHtml};
+/// # fn main() -> std::io::Result<()> {
+/// assert_eq!(Attr("checked", true).to_buffer()?, "checked");
+/// assert_eq!(Attr("checked", false).to_buffer()?, "");
+/// assert_eq!(Attr("title", Some("\"x\"")).to_buffer()?, "title=\"&quot;x&quot;\"");
+/// assert_eq!(Attr("title", None::<&str>).to_buffer()?, "");
+/// # Ok(())
+/// # }
+/// ```
+#[allow(dead_code)]
+pub struct Attr<'a, T>(pub &'a str, pub T);
+
+impl<T: AttrValue> ToHtml for Attr<'_, T> {
+    fn to_html(&self, out: &mut dyn Write) -> WriteResult {
+        self.1.write_attr(self.0, out)
+    }
+}
+
+/// Wrapper for an optional attribute, like [`Attr`], with its value
+/// escaped by a function.
+///
+/// This is what `@?name=value` in a template gives with contextual
+/// escaping, for an attribute such as `href` or `onclick` that needs
+/// other escaping than html.
+///
+/// # Examples
+/// ```ignore
+/// # use ructe::templates::{EscapedAttr, SafeUrl, ToHtml};
+/// # fn main() -> std::io::Result<()> {
+/// let url = Some("javascript:alert(1)");
+/// let href = EscapedAttr("href", &url, |v, o| SafeUrl(v).to_html(o));
+/// assert_eq!(href.to_buffer()?, "href=\"#\"");
+/// # Ok(())
+/// # }
+/// ```
+#[allow(dead_code)]
+pub struct EscapedAttr<'a, T>(pub &'a str, pub T, pub EscapeFn);
+
+impl<T: AttrValue> ToHtml for EscapedAttr<'_, T> {
+    fn to_html(&self, out: &mut dyn Write) -> WriteResult {
+        self.1.write_attr_with(self.0, self.2, out)
+    }
+}
+
+/// A function that writes an attribute value to `out`, escaped in
+/// some way.
+pub type EscapeFn = fn(&dyn ToHtml, &mut dyn Write) -> WriteResult;
+
+/// A value for an optional attribute, see [`Attr`].
+pub trait AttrValue {
+    /// Write the attribute `name` with this value to `out`, or
+    /// nothing if the attribute should be omitted.
+    ///
+    /// # Errors
+    ///
+    /// This method returns an error if writing to `out` fails.
+    fn write_attr(&self, name: &str, out: &mut dyn Write) -> WriteResult {
+        self.write_attr_with(name, |value, out| value.to_html(out), out)
+    }
+
+    /// Write the attribute `name` with this value to `out`, with
+    /// the value escaped by `escape`, or nothing if the attribute
+    /// should be omitted.
+    ///
+    /// # Errors
+    ///
+    /// This method returns an error if writing to `out` fails.
+    fn write_attr_with(
+        &self,
+        name: &str,
+        escape: EscapeFn,
+        out: &mut dyn Write,
+    ) -> WriteResult;
+}
+
+impl AttrValue for bool {
+    fn write_attr_with(
+        &self,
+        name: &str,
+        _escape: EscapeFn,
+        out: &mut dyn Write,
+    ) -> WriteResult {
+        if *self {
+            write_str(out, name)?;
+        }
+        Ok(())
+    }
+}
+
+impl<T: Display> AttrValue for Option<T> {
+    fn write_attr_with(
+        &self,
+        name: &str,
+        escape: EscapeFn,
+        out: &mut dyn Write,
+    ) -> WriteResult {
+        if let Some(value) = self {
+            write!(out, "{name}=\"")?;
+            escape(value, out)?;
+            write_str(out, "\"")?;
+        }
+        Ok(())
+    }
+}
+
+impl<T: AttrValue + ?Sized> AttrValue for &T {
+    fn write_attr_with(
+        &self,
+        name: &str,
+        escape: EscapeFn,
+        out: &mut dyn Write,
+    ) -> WriteResult {
+        (**self).write_attr_with(name, escape, out)
+    }
+}
+
+/// A list of class names, each with a condition for including it.
+///
+/// As an html value, the included names are written separated by
+/// spaces.
+/// As an attribute value, the attribute is omitted if no name is
+/// included.
+/// This is what `@?class=["a", "b" if x]` in a template gives.
+///
+/// # Examples
+/// ```ignore
+/// # use ructe::templates::{Attr, ClassList, ToHtml};
+/// # fn main() -> std::io::Result<()> {
+/// let classes = ClassList(&[(&"btn", true), (&"big", false), (&"<x>", true)]);
+/// assert_eq!(classes.to_buffer()?, "btn &lt;x&gt;");
+/// assert_eq!(Attr("class", &classes).to_buffer()?, "class=\"btn &lt;x&gt;\"");
+/// assert_eq!(Attr("class", ClassList(&[(&"big", false)])).to_buffer()?, "");
+/// # Ok(())
+/// # }
+/// ```
+#[allow(dead_code)]
+pub struct ClassList<'a>(pub &'a [(&'a dyn Display, bool)]);
+
+impl ToHtml for ClassList<'_> {
+    fn to_html(&self, out: &mut dyn Write) -> WriteResult {
+        let classes = self.0.iter().filter(|(_, included)| *included);
+        for (i, (class, _)) in classes.enumerate() {
+            if i > 0 {
+                write_str(out, " ")?;
+            }
+            class.to_html(out)?;
+        }
+        Ok(())
+    }
+}
+
+impl AttrValue for ClassList<'_> {
+    fn write_attr_with(
+        &self,
+        name: &str,
+        escape: EscapeFn,
+        out: &mut dyn Write,
+    ) -> WriteResult {
+        if self.0.iter().any(|(_, included)| *included) {
+            write!(out, "{name}=\"")?;
+            escape(self, out)?;
+            write_str(out, "\"")?;
+        }
+        Ok(())
+    }
+}
+
+/// Wrapper for a value that should be written escaped for a
+/// javascript string.
+///
+/// Any character that is not alphanumeric, a space, or one of `,-._`
+/// is written as a `\uXXXX` escape.
+/// No quotes are added, so this is only safe inside a quoted string.
+/// This is used for expressions in quoted strings in `script` elements
+/// and event handler attributes with contextual escaping.
+/// The wrapped value can be any [`ToHtml`] value, and its text (as
+/// given by [`ToHtml::to_text`]) is escaped; the other escaping
+/// wrappers below work the same way.
+#[allow(dead_code)]
+pub struct JsString<T>(pub T);
+
+impl<T: ToHtml + ?Sized> ToHtml for JsString<&T> {
+    fn to_html(&self, out: &mut dyn Write) -> WriteResult {
+        let mut buf = [0; 2];
+        for c in text_of(self.0)?.chars() {
+            if c.is_alphanumeric() || matches!(c, ' ' | ',' | '-' | '.' | '_')
+            {
+                write!(out, "{c}")?;
+            } else {
+                for unit in c.encode_utf16(&mut buf) {
+                    write!(out, "\\u{unit:04X}")?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Wrapper for a value that should be written escaped for css.
+///
+/// Any character that is not ascii alphanumeric is written as a css
+/// hex escape.
+/// This is used for expressions in `style` elements and attributes
+/// with contextual escaping.
+#[allow(dead_code)]
+pub struct CssEscaped<T>(pub T);
+
+impl<T: ToHtml + ?Sized> ToHtml for CssEscaped<&T> {
+    fn to_html(&self, out: &mut dyn Write) -> WriteResult {
+        for c in text_of(self.0)?.chars() {
+            if c.is_ascii_alphanumeric() {
+                write!(out, "{c}")?;
+            } else {
+                write!(out, "\\{:X} ", u32::from(c))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Wrapper for a value that should be written as an url.
+///
+/// An url with a scheme other than `http`, `https`, `mailto` or `tel`
+/// (such as `javascript:`) is replaced by `#`.
+/// This is used for expressions at the start of url attributes, such
+/// as `href`, with contextual escaping.
+#[allow(dead_code)]
+pub struct SafeUrl<T>(pub T);
+
+impl<T: ToHtml + ?Sized> ToHtml for SafeUrl<&T> {
+    fn to_html(&self, out: &mut dyn Write) -> WriteResult {
+        let url = text_of(self.0)?;
+        let scheme = url
+            .find([':', '/', '?', '#'])
+            .filter(|i| url[*i..].starts_with(':'))
+            .map(|i| url[..i].to_ascii_lowercase());
+        match scheme.as_deref() {
+            None | Some("http" | "https" | "mailto" | "tel") => {
+                url.to_html(out)
+            }
+            Some(_) => write_str(out, "#"),
+        }
+    }
+}
+
+/// Wrapper for a value that should be url encoded.
+///
+/// Any byte that is not an unreserved url character is percent
+/// encoded.
+/// This is used for expressions inside url attributes, such as in
+/// the query of an `href`, with contextual escaping.
+#[allow(dead_code)]
+pub struct UrlEncoded<T>(pub T);
+
+impl<T: ToHtml + ?Sized> ToHtml for UrlEncoded<&T> {
+    fn to_html(&self, out: &mut dyn Write) -> WriteResult {
+        for b in text_of(self.0)?.bytes() {
+            if b.is_ascii_alphanumeric()
+                || matches!(b, b'-' | b'.' | b'_' | b'~')
+            {
+                write!(out, "{}", char::from(b))?;
+            } else {
+                write!(out, "%{b:02X}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<T: Display + ?Sized> ToHtml for T {
+    #[inline]
+    fn to_html(&self, out: &mut dyn Write) -> WriteResult {
+        write!(ToHtmlEscapingWriter(out), "{self}")
+    }
+
+    #[inline]
+    fn to_text(&self, out: &mut dyn Write) -> WriteResult {
+        write!(out, "{self}")
+    }
+}
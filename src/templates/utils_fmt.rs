@@ -0,0 +1,96 @@
+use alloc::string::String;
+use core::fmt::{self, Display, Write};
+
+#[path = "utils_common.rs"]
+#[allow(clippy::duplicate_mod)] // also a submodule of utils.rs in tests
+mod common;
+pub use self::common::*;
+
+/// The result of writing to a [`Write`].
+type WriteResult = fmt::Result;
+
+/// This trait should be implemented for any value that can be the
+/// result of an expression in a template.
+///
+/// This trait decides how to format the given object as html.
+/// There exists a default implementation for any `T: Display` that
+/// formats the value using Display and then html-encodes the result.
+///
+/// This is the `core::fmt::Write` flavour of the trait, used by
+/// templates compiled with `Ructe::fmt_write`.
+pub trait ToHtml {
+    /// Write self to `out`, which is in html representation.
+    ///
+    /// # Errors
+    ///
+    /// This method returns an error if writing to `out` fails.
+    /// Implementors should not return an error for any other reason.
+    fn to_html(&self, out: &mut dyn Write) -> fmt::Result;
+
+    /// Write self to `out` as plain text, not escaped as html.
+    ///
+    /// This is what wrappers such as [`JsString`] escape for another
+    /// context than html.
+    /// The default is the html representation, so a value with
+    /// markup of its own gets escaped as that markup, while any
+    /// `T: Display` writes its `Display` text.
+    ///
+    /// # Errors
+    ///
+    /// This method returns an error if writing to `out` fails.
+    fn to_text(&self, out: &mut dyn Write) -> fmt::Result {
+        self.to_html(out)
+    }
+}
+
+/// Wrapper to format a [`ToHtml`] value with its html representation.
+///
+/// Every type that implements [`Display`] is also [`ToHtml`], escaped
+/// as html text, so a type with an html representation of its own
+/// (such as the struct generated for a template) can not implement
+/// [`Display`].
+/// This wrapper can be used with `format!` and friends instead.
+pub struct HtmlDisplay<'a, T: ?Sized>(pub &'a T);
+
+impl<T: ToHtml + ?Sized> Display for HtmlDisplay<'_, T> {
+    fn fmt(&self, out: &mut fmt::Formatter) -> fmt::Result {
+        self.0.to_html(out)
+    }
+}
+
+/// Write `s` to `out`.
+fn write_str(out: &mut dyn Write, s: &str) -> WriteResult {
+    out.write_str(s)
+}
+
+/// The plain text of `value`, see [`ToHtml::to_text`].
+fn text_of<T: ToHtml + ?Sized>(value: &T) -> Result<String, fmt::Error> {
+    let mut text = String::new();
+    value.to_text(&mut text)?;
+    Ok(text)
+}
+
+/// A writer that html-escapes everything written to it.
+///
+/// This is used by the code generated for `@(expr:spec)`, and not
+/// intended to be used directly.
+#[doc(hidden)]
+pub struct ToHtmlEscapingWriter<'a>(pub &'a mut dyn Write);
+
+impl Write for ToHtmlEscapingWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut rest = s;
+        while let Some(i) = rest.find(['"', '&', '\'', '<', '>']) {
+            self.0.write_str(&rest[..i])?;
+            self.0.write_str(match rest.as_bytes()[i] {
+                b'"' => "&quot;",
+                b'&' => "&amp;",
+                b'<' => "&lt;",
+                b'>' => "&gt;",
+                _ => "&#39;",
+            })?;
+            rest = &rest[i + 1..];
+        }
+        self.0.write_str(rest)
+    }
+}
@@ -45,6 +45,21 @@ use warp::{reject::Reject, reply::Response, Reply};
 ///
 /// Note that the `.html` method _finalizes_ the builder, that is, on
 /// success it returns a [`Response`] rather than a [`response::Builder`].
+///
+/// To allocate the buffer once, at a realistic size, use
+/// `.html_with_capacity` with the size hint of the template:
+///
+/// ```
+/// # use std::io::{self, Write};
+/// # use warp::http::Response;
+/// # use ructe::templates::RenderRucte;
+/// # const PAGE_HTML_SIZE_HINT: usize = 1000;
+/// # fn page_html(o: &mut Write, _: u8, _: u8) -> io::Result<()> { Ok(()) }
+/// # let (title, body) = (47, 11);
+/// Response::builder()
+///     .html_with_capacity(PAGE_HTML_SIZE_HINT, |o| page_html(o, title, body))
+/// # ;
+/// ```
 pub trait RenderRucte {
     /// Render a template on the response builder.
     ///
@@ -52,6 +67,16 @@ pub trait RenderRucte {
     fn html<F>(self, f: F) -> Result<Response, RenderError>
     where
         F: FnOnce(&mut Vec<u8>) -> io::Result<()>;
+
+    /// Render a template on the response builder, to a buffer with
+    /// the given initial capacity.
+    fn html_with_capacity<F>(
+        self,
+        capacity: usize,
+        f: F,
+    ) -> Result<Response, RenderError>
+    where
+        F: FnOnce(&mut Vec<u8>) -> io::Result<()>;
 }
 
 impl RenderRucte for response::Builder {
@@ -59,7 +84,18 @@ impl RenderRucte for response::Builder {
     where
         F: FnOnce(&mut Vec<u8>) -> io::Result<()>,
     {
-        let mut buf = Vec::new();
+        self.html_with_capacity(0, f)
+    }
+
+    fn html_with_capacity<F>(
+        self,
+        capacity: usize,
+        f: F,
+    ) -> Result<Response, RenderError>
+    where
+        F: FnOnce(&mut Vec<u8>) -> io::Result<()>,
+    {
+        let mut buf = Vec::with_capacity(capacity);
         f(&mut buf).map_err(RenderError::write)?;
         self.header(CONTENT_TYPE, TEXT_HTML_UTF_8.as_ref())
             .body(buf.into())